@@ -0,0 +1,127 @@
+#[macro_use]
+extern crate error_chain;
+
+// Only the pak handling is shared with the viewer
+#[macro_use]
+#[allow(dead_code)]
+#[path = "../parse/mod.rs"]
+mod parse;
+#[path = "../error.rs"]
+pub mod error;
+#[path = "../pak/mod.rs"]
+pub mod pak;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use crate::error::ResultExt;
+
+const USAGE: &str = "\
+Usage: rquake-pak <command> <pak> [args]
+
+Commands:
+    list <pak>                       Lists the offset, size and name of each file
+    extract <pak> <dir> [pattern..]  Extracts the files matching any of the
+                                     patterns, or every file, into dir
+    create <pak> <dir>               Creates a pak from the files in dir
+    verify <pak>                     Checks for files outside of the pak or
+                                     overlapping each other";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        ["list", pak] => list(pak),
+        ["create", pak, dir] => create(pak, dir),
+        ["verify", pak] => verify(pak),
+        _ if args.len() >= 3 && args[0] == "extract" => extract(args[1], args[2], &args[3..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        for cause in err.iter().skip(1) {
+            eprintln!("Caused by: {}", cause);
+        }
+        process::exit(1);
+    }
+}
+
+fn open(path: &str) -> error::Result<pak::PackFile> {
+    pak::PackFile::new(path)
+        .chain_err(|| format!("Failed to open {}", path))
+}
+
+fn list(path: &str) -> error::Result<()> {
+    let pak = open(path)?;
+    let mut total = 0u64;
+    let mut invalid = 0;
+    println!("{:>10} {:>10}  name", "offset", "size");
+    for (name, entry) in pak.entries() {
+        // Corrupt entries are shown but not counted, their
+        // sizes can be anything
+        if pak.in_range(&entry) {
+            println!("{:>10} {:>10}  {}", entry.offset, entry.size, name);
+            total += entry.size;
+        } else {
+            println!("{:>10} {:>10}  {} (invalid)", entry.offset as i64, entry.size as i64, name);
+            invalid += 1;
+        }
+    }
+    println!("{} files, {} bytes", pak.entries().count(), total);
+    if invalid > 0 {
+        println!("{} files are outside of the pak, run verify for details", invalid);
+    }
+    Ok(())
+}
+
+fn extract(path: &str, dir: &str, patterns: &[&str]) -> error::Result<()> {
+    let pak = open(path)?;
+    let dir = Path::new(dir);
+    let mut count = 0;
+    for (name, _) in pak.entries() {
+        if !patterns.is_empty() && !patterns.iter().any(|p| pak::glob_match(p, name)) {
+            continue;
+        }
+        // Names could otherwise write outside of the directory
+        pak::check_name(name)
+            .chain_err(|| format!("Not extracting {}", name))?;
+        let out = dir.join(name);
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = pak.file(name)
+            .chain_err(|| format!("Failed to read {}", name))?;
+        fs::write(&out, data)
+            .chain_err(|| format!("Failed to write {:?}", out))?;
+        println!("{}", name);
+        count += 1;
+    }
+    println!("Extracted {} files", count);
+    Ok(())
+}
+
+fn create(path: &str, dir: &str) -> error::Result<()> {
+    let mut writer = pak::PackWriter::new();
+    writer.add_dir(dir)?;
+    writer.save(path)?;
+    println!("Created {} with {} files", path, writer.len());
+    Ok(())
+}
+
+fn verify(path: &str) -> error::Result<()> {
+    let pak = open(path)?;
+    let problems = pak.verify();
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("{} problems found in {}", problems.len(), path);
+    }
+    println!("{} files, no problems found", pak.entries().count());
+    Ok(())
+}
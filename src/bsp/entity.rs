@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use cgmath::Vector3;
+
+use crate::error;
+
+#[derive(Debug, Clone, Default)]
+pub struct Entity {
+    pub properties: HashMap<String, String>,
+}
+
+impl Entity {
+    pub fn parse_entities(data: &str) -> error::Result<Vec<Entity>> {
+        let mut tokens = Tokenizer { data };
+        let mut entities = vec![];
+
+        while let Some(token) = tokens.next_token() {
+            if token != "{" {
+                bail!("Expected '{{' at the start of an entity, found {:?}", token);
+            }
+            let mut entity = Entity::default();
+            loop {
+                let key = match tokens.next_token() {
+                    Some("}") => break,
+                    Some(key) => key,
+                    None => bail!("Unexpected end of the entities lump"),
+                };
+                let value = match tokens.next_token() {
+                    Some("}") | None => bail!("Missing value for the entity key {:?}", key),
+                    Some(value) => value,
+                };
+                entity.properties.insert(key.to_owned(), value.to_owned());
+            }
+            entities.push(entity);
+        }
+
+        Ok(entities)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(|v| v.as_str())
+    }
+
+    pub fn classname(&self) -> Option<&str> {
+        self.get("classname")
+    }
+
+    pub fn origin(&self) -> Option<Vector3<f32>> {
+        self.get_vector("origin")
+    }
+
+    pub fn angle(&self) -> Option<f32> {
+        self.get("angle").and_then(|v| v.trim().parse().ok())
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.get("model")
+    }
+
+    // Brush entities reference their submodel as `*N`
+    pub fn model_index(&self) -> Option<usize> {
+        let model = self.model()?;
+        if model.starts_with('*') {
+            model[1..].parse().ok()
+        } else {
+            None
+        }
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        self.get("target")
+    }
+
+    pub fn targetname(&self) -> Option<&str> {
+        self.get("targetname")
+    }
+
+    pub fn get_vector(&self, key: &str) -> Option<Vector3<f32>> {
+        let mut parts = self.get(key)?
+            .split_whitespace()
+            .map(|v| v.parse::<f32>());
+        let x = parts.next()?.ok()?;
+        let y = parts.next()?.ok()?;
+        let z = parts.next()?.ok()?;
+        Some(Vector3::new(x, y, z))
+    }
+}
+
+struct Tokenizer<'a> {
+    data: &'a str,
+}
+
+impl <'a> Tokenizer<'a> {
+    fn next_token(&mut self) -> Option<&'a str> {
+        loop {
+            self.data = self.data.trim_start();
+            if self.data.starts_with("//") {
+                let end = self.data.find('\n').unwrap_or(self.data.len());
+                self.data = &self.data[end..];
+            } else {
+                break;
+            }
+        }
+        if self.data.is_empty() {
+            return None;
+        }
+
+        if self.data.starts_with('"') {
+            let rest = &self.data[1..];
+            let end = rest.find('"').unwrap_or(rest.len());
+            let token = &rest[..end];
+            self.data = &rest[(end + 1).min(rest.len())..];
+            return Some(token);
+        }
+
+        let end = self.data.find(char::is_whitespace).unwrap_or(self.data.len());
+        let token = &self.data[..end];
+        self.data = &self.data[end..];
+        Some(token)
+    }
+}
+
+#[test]
+fn test_parse_entities() {
+    let data = r#"
+{
+"classname" "worldspawn"
+"wad" "gfx/base.wad"
+}
+// A comment
+{
+"classname" "info_player_start"
+"origin" "480 -352 88"
+"angle" "90"
+}
+{
+"model" "*3"
+"classname" "func_door"
+"targetname" "t1"
+}
+"#;
+    let entities = Entity::parse_entities(data).unwrap();
+    assert_eq!(entities.len(), 3);
+    assert_eq!(entities[0].classname(), Some("worldspawn"));
+    assert_eq!(entities[0].get("wad"), Some("gfx/base.wad"));
+    assert_eq!(entities[1].origin(), Some(Vector3::new(480.0, -352.0, 88.0)));
+    assert_eq!(entities[1].angle(), Some(90.0));
+    assert_eq!(entities[2].model_index(), Some(3));
+    assert_eq!(entities[2].targetname(), Some("t1"));
+    assert_eq!(entities[2].target(), None);
+
+    assert!(Entity::parse_entities("{ \"classname\" }").is_err());
+}
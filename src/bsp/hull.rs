@@ -0,0 +1,212 @@
+use cgmath::Vector3;
+use cgmath::prelude::*;
+
+use super::*;
+
+// Hull 0 is the node tree used for rendering, the others are
+// clip node trees expanded by the size of the box being traced.
+pub const HULL_POINT: usize = 0;
+pub const HULL_PLAYER: usize = 1;
+pub const HULL_LARGE: usize = 2;
+
+// How far to stay away from a plane when clipping so the end
+// position never lands on the plane itself.
+const DIST_EPSILON: f32 = 0.03125;
+
+pub fn hull_bounds(hull: usize) -> (Vector3<f32>, Vector3<f32>) {
+    match hull {
+        HULL_PLAYER => (Vector3::new(-16.0, -16.0, -24.0), Vector3::new(16.0, 16.0, 32.0)),
+        HULL_LARGE => (Vector3::new(-32.0, -32.0, -24.0), Vector3::new(32.0, 32.0, 64.0)),
+        _ => (Vector3::zero(), Vector3::zero()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Trace {
+    // The whole move was inside solid space
+    pub all_solid: bool,
+    pub start_solid: bool,
+    pub in_open: bool,
+    pub in_water: bool,
+    // How far along the move the trace got before hitting something
+    pub fraction: f32,
+    pub end: Vector3<f32>,
+    pub plane_normal: Vector3<f32>,
+    pub plane_distance: f32,
+}
+
+impl BspFile {
+    fn hull_node(&self, hull: usize, node: usize) -> (&Plane, [ClipChild; 2]) {
+        if hull == HULL_POINT {
+            let n = &self.nodes[node];
+            let child = |c| match c {
+                NodeChild::Node(id) => ClipChild::Node(id),
+                NodeChild::Leaf(id) => ClipChild::Contents(self.leaves[id].contents),
+            };
+            (&self.planes[n.plane], [child(n.children[0]), child(n.children[1])])
+        } else {
+            let n = &self.clip_nodes[node];
+            (&self.planes[n.plane], n.children)
+        }
+    }
+
+    pub fn hull_point_contents(&self, hull: usize, model: usize, point: Vector3<f32>) -> Contents {
+        self.child_point_contents(hull, ClipChild::Node(self.models[model].head_nodes[hull]), point)
+    }
+
+    fn child_point_contents(&self, hull: usize, mut child: ClipChild, point: Vector3<f32>) -> Contents {
+        loop {
+            match child {
+                ClipChild::Contents(contents) => return contents,
+                ClipChild::Node(node) => {
+                    let (plane, children) = self.hull_node(hull, node);
+                    child = if point.dot(plane.normal) - plane.distance >= 0.0 {
+                        children[0]
+                    } else {
+                        children[1]
+                    };
+                },
+            }
+        }
+    }
+
+    // Traces a box, sized by the hull, from start to end against
+    // a model of the level.
+    pub fn trace(&self, hull: usize, model: usize, start: Vector3<f32>, end: Vector3<f32>) -> Trace {
+        let mut trace = Trace {
+            all_solid: true,
+            start_solid: false,
+            in_open: false,
+            in_water: false,
+            fraction: 1.0,
+            end: end,
+            plane_normal: Vector3::zero(),
+            plane_distance: 0.0,
+        };
+        let head = ClipChild::Node(self.models[model].head_nodes[hull]);
+        self.recursive_hull_check(hull, head, head, 0.0, 1.0, start, end, &mut trace);
+        if trace.all_solid {
+            trace.start_solid = true;
+        }
+        trace
+    }
+
+    // Returns false once the trace has hit something
+    fn recursive_hull_check(
+        &self,
+        hull: usize, head: ClipChild, child: ClipChild,
+        p1f: f32, p2f: f32,
+        p1: Vector3<f32>, p2: Vector3<f32>,
+        trace: &mut Trace,
+    ) -> bool {
+        let node = match child {
+            ClipChild::Contents(Contents::Solid) => {
+                trace.start_solid = true;
+                return true;
+            },
+            ClipChild::Contents(contents) => {
+                trace.all_solid = false;
+                if contents == Contents::Empty {
+                    trace.in_open = true;
+                } else {
+                    trace.in_water = true;
+                }
+                return true;
+            },
+            ClipChild::Node(node) => node,
+        };
+
+        let (plane, children) = self.hull_node(hull, node);
+        let t1 = p1.dot(plane.normal) - plane.distance;
+        let t2 = p2.dot(plane.normal) - plane.distance;
+
+        if t1 >= 0.0 && t2 >= 0.0 {
+            return self.recursive_hull_check(hull, head, children[0], p1f, p2f, p1, p2, trace);
+        }
+        if t1 < 0.0 && t2 < 0.0 {
+            return self.recursive_hull_check(hull, head, children[1], p1f, p2f, p1, p2, trace);
+        }
+
+        // Put the cross point on the near side of the plane
+        let mut frac = if t1 < 0.0 {
+            (t1 + DIST_EPSILON) / (t1 - t2)
+        } else {
+            (t1 - DIST_EPSILON) / (t1 - t2)
+        };
+        frac = frac.max(0.0).min(1.0);
+
+        let mut midf = p1f + (p2f - p1f) * frac;
+        let mut mid = p1 + (p2 - p1) * frac;
+        let side = if t1 < 0.0 { 1 } else { 0 };
+
+        // Move up to the node
+        if !self.recursive_hull_check(hull, head, children[side], p1f, midf, p1, mid, trace) {
+            return false;
+        }
+
+        if self.child_point_contents(hull, children[side ^ 1], mid) != Contents::Solid {
+            // Go past the node
+            return self.recursive_hull_check(hull, head, children[side ^ 1], midf, p2f, mid, p2, trace);
+        }
+
+        if trace.all_solid {
+            // Never got out of the solid area
+            return false;
+        }
+
+        // The other side of the node is solid, this is the impact point
+        if side == 0 {
+            trace.plane_normal = plane.normal;
+            trace.plane_distance = plane.distance;
+        } else {
+            trace.plane_normal = -plane.normal;
+            trace.plane_distance = -plane.distance;
+        }
+
+        // Floating point error can leave the mid point inside
+        // the solid so back it up until it isn't
+        while self.child_point_contents(hull, head, mid) == Contents::Solid {
+            frac -= 0.1;
+            if frac < 0.0 {
+                trace.fraction = midf;
+                trace.end = mid;
+                return false;
+            }
+            midf = p1f + (p2f - p1f) * frac;
+            mid = p1 + (p2 - p1) * frac;
+        }
+
+        trace.fraction = midf;
+        trace.end = mid;
+        false
+    }
+}
+
+#[test]
+fn test_trace_floor() {
+    // A single floor at z = 0 with solid space below it
+    let level = BspFile::clip_level(
+        vec![Plane {
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            distance: 0.0,
+            kind: 2,
+        }],
+        vec![ClipNode {
+            plane: 0,
+            children: [ClipChild::Contents(Contents::Empty), ClipChild::Contents(Contents::Solid)],
+        }],
+    );
+
+    assert_eq!(level.hull_point_contents(HULL_PLAYER, 0, Vector3::new(0.0, 0.0, 5.0)), Contents::Empty);
+    assert_eq!(level.hull_point_contents(HULL_PLAYER, 0, Vector3::new(0.0, 0.0, -5.0)), Contents::Solid);
+
+    let trace = level.trace(HULL_PLAYER, 0, Vector3::new(0.0, 0.0, 10.0), Vector3::new(5.0, 0.0, -10.0));
+    assert!(!trace.all_solid && !trace.start_solid);
+    assert!(trace.fraction < 0.5);
+    assert!(trace.end.z > 0.0 && trace.end.z < 0.1);
+    assert_eq!(trace.plane_normal, Vector3::new(0.0, 0.0, 1.0));
+
+    let trace = level.trace(HULL_PLAYER, 0, Vector3::new(0.0, 0.0, 10.0), Vector3::new(0.0, 50.0, 10.0));
+    assert_eq!(trace.fraction, 1.0);
+    assert_eq!(trace.end, Vector3::new(0.0, 50.0, 10.0));
+}
@@ -1,20 +1,39 @@
 
-use std::io::{Read, Seek, SeekFrom};
+use std::io::Read;
 use std::ops::Range;
 use cgmath::Vector3;
+use cgmath::prelude::*;
 
 use crate::error;
 use crate::parse::*;
+use crate::bitset::BitSet;
+use crate::pak::SharedData;
 
+mod entity;
+pub use self::entity::Entity;
+mod hull;
+pub use self::hull::{Trace, hull_bounds, HULL_POINT, HULL_PLAYER, HULL_LARGE};
+
+const SIZE_HEADER: usize = 4 + 15 * (4 + 4);
 const SIZE_TEXTURE_INFO: usize = 4*6 + 4*2 + 4*2;
 const SIZE_VERTEX: usize = 4 * 3;
 const SIZE_EDGE: usize = 2 + 2;
 const SIZE_PLANE: usize = 4*3 + 4 + 4;
 const SIZE_FACE: usize = 2 + 2 + 4 + 2 + 2 + 4 + 4;
 const SIZE_MODEL: usize = (4*3)*3 + 4*4 + 4 + 4 + 4;
+const SIZE_NODE: usize = 4 + 2*2 + 2*3*2 + 2 + 2;
+const SIZE_CLIP_NODE: usize = 4 + 2*2;
+const SIZE_LEAF: usize = 4 + 4 + 2*3*2 + 2 + 2 + 4;
+const SIZE_MIP_TEXTURE: usize = 16 + 4*2 + 4*4;
+
+// Largest size of a face in texels along either texture axis,
+// Quake refuses to load levels with larger faces
+const MAX_SURFACE_EXTENTS: f32 = 256.0;
 
 pub struct BspFile {
-    pub light_maps: Vec<u8>,
+    pub entities: Vec<Entity>,
+    pub light_maps: SharedData,
+    pub visibility: SharedData,
     pub textures: Vec<Texture>,
     pub texture_info: Vec<TextureInfo>,
     pub edges: Vec<Edge>,
@@ -22,12 +41,28 @@ pub struct BspFile {
     pub planes: Vec<Plane>,
     pub faces: Vec<Face>,
     pub models: Vec<Model>,
+    pub nodes: Vec<Node>,
+    pub clip_nodes: Vec<ClipNode>,
+    pub leaves: Vec<Leaf>,
+    pub mark_surfaces: Vec<usize>,
 }
 
 impl BspFile {
-    pub fn parse<R>(r: &mut R) -> error::Result<BspFile>
-        where R: Read + Seek,
-    {
+    // Parses a level from the whole file. Every lump is checked to
+    // be within the file and every index between lumps is checked
+    // so that a corrupt level is an error instead of a panic later.
+    pub fn parse(data: &[u8]) -> error::Result<BspFile> {
+        BspFile::parse_shared(&data.to_vec().into())
+    }
+
+    // Like `parse` but the lighting, visibility and texture data
+    // are shared with the file rather than copied.
+    pub fn parse_shared(file: &SharedData) -> error::Result<BspFile> {
+        let data = &file[..];
+        if data.len() < SIZE_HEADER {
+            bail!("BSP file is too small to hold its header ({} bytes)", data.len());
+        }
+        let mut r = data;
 
         let version = r.read_long()?;
 
@@ -35,36 +70,40 @@ impl BspFile {
             bail!("Unsupported BSP version");
         }
 
-        let _e_entities = Entry::read(r)?;
-        let e_planes = Entry::read(r)?;
-        let e_wall_textures = Entry::read(r)?;
-        let e_vertices = Entry::read(r)?;
-        let _e_visibility_list = Entry::read(r)?;
-        let _e_nodes = Entry::read(r)?;
-        let e_texture_info = Entry::read(r)?;
-        let e_faces = Entry::read(r)?;
-        let e_light_maps = Entry::read(r)?;
-        let _e_clip_nodes = Entry::read(r)?;
-        let _e_leaves = Entry::read(r)?;
-        let _e_face_list = Entry::read(r)?;
-        let e_edges = Entry::read(r)?;
-        let e_ledges = Entry::read(r)?;
-        let e_models = Entry::read(r)?;
-
-        let mut light_maps = vec![0; e_light_maps.size as usize];
-        r.seek(SeekFrom::Start(e_light_maps.offset as u64))?;
-        r.read_exact(&mut light_maps)?;
-
-        r.seek(SeekFrom::Start(e_wall_textures.offset as u64))?;
-        let textures = Texture::parse_textures(r)?;
-
-        r.seek(SeekFrom::Start(e_texture_info.offset as u64))?;
-        let texture_info = TextureInfo::parse(e_texture_info.size as usize / SIZE_TEXTURE_INFO, r)?;
-
-        r.seek(SeekFrom::Start(e_vertices.offset as u64))?;
-        let vlen = e_vertices.size as usize / SIZE_VERTEX;
-        let mut vertices = Vec::with_capacity(vlen);
-        for _ in 0 .. vlen {
+        let e_entities = Entry::read(&mut r)?;
+        let e_planes = Entry::read(&mut r)?;
+        let e_wall_textures = Entry::read(&mut r)?;
+        let e_vertices = Entry::read(&mut r)?;
+        let e_visibility_list = Entry::read(&mut r)?;
+        let e_nodes = Entry::read(&mut r)?;
+        let e_texture_info = Entry::read(&mut r)?;
+        let e_faces = Entry::read(&mut r)?;
+        let e_light_maps = Entry::read(&mut r)?;
+        let e_clip_nodes = Entry::read(&mut r)?;
+        let e_leaves = Entry::read(&mut r)?;
+        let e_face_list = Entry::read(&mut r)?;
+        let e_edges = Entry::read(&mut r)?;
+        let e_ledges = Entry::read(&mut r)?;
+        let e_models = Entry::read(&mut r)?;
+
+        let entity_data = e_entities.data(data, "entities", 1)?;
+        // Quake's character set uses the high bit so the text isn't
+        // UTF-8, each byte is decoded as its own character instead
+        let entity_text = entity_data.iter()
+            .take_while(|&&v| v != 0)
+            .map(|&v| v as char)
+            .collect::<String>();
+        let entities = Entity::parse_entities(&entity_text)?;
+
+        let light_maps = file.slice(e_light_maps.range(data, "lighting", 1)?);
+        let visibility = file.slice(e_visibility_list.range(data, "visibility", 1)?);
+
+        let textures = Texture::parse_textures(&file.slice(e_wall_textures.range(data, "textures", 1)?))?;
+        let texture_info = TextureInfo::parse(e_texture_info.data(data, "texture info", SIZE_TEXTURE_INFO)?)?;
+
+        let vertex_data = e_vertices.data(data, "vertices", SIZE_VERTEX)?;
+        let mut vertices = Vec::with_capacity(vertex_data.len() / SIZE_VERTEX);
+        for mut r in vertex_data.chunks_exact(SIZE_VERTEX) {
             vertices.push(Vector3::new(
                 r.read_float()?,
                 r.read_float()?,
@@ -72,27 +111,31 @@ impl BspFile {
             ));
         }
 
-        r.seek(SeekFrom::Start(e_edges.offset as u64))?;
-        let edges = Edge::parse(e_edges.size as usize / SIZE_EDGE, vertices, r)?;
+        let edges = Edge::parse(e_edges.data(data, "edges", SIZE_EDGE)?, &vertices)?;
 
-        let lelen = e_ledges.size as usize / 4;
-        let mut ledges = Vec::with_capacity(lelen);
-        r.seek(SeekFrom::Start(e_ledges.offset as u64))?;
-        for _ in 0 .. lelen {
+        let ledge_data = e_ledges.data(data, "ledges", 4)?;
+        let mut ledges = Vec::with_capacity(ledge_data.len() / 4);
+        for mut r in ledge_data.chunks_exact(4) {
             ledges.push(r.read_long()?);
         }
 
-        r.seek(SeekFrom::Start(e_planes.offset as u64))?;
-        let planes = Plane::parse(e_planes.size as usize / SIZE_PLANE, r)?;
-
-        r.seek(SeekFrom::Start(e_faces.offset as u64))?;
-        let faces = Face::parse(e_faces.size as usize / SIZE_FACE, r)?;
+        let planes = Plane::parse(e_planes.data(data, "planes", SIZE_PLANE)?)?;
+        let faces = Face::parse(e_faces.data(data, "faces", SIZE_FACE)?)?;
+        let models = Model::parse(e_models.data(data, "models", SIZE_MODEL)?)?;
+        let nodes = Node::parse(e_nodes.data(data, "nodes", SIZE_NODE)?)?;
+        let clip_nodes = ClipNode::parse(e_clip_nodes.data(data, "clip nodes", SIZE_CLIP_NODE)?)?;
+        let leaves = Leaf::parse(e_leaves.data(data, "leaves", SIZE_LEAF)?)?;
 
-        r.seek(SeekFrom::Start(e_models.offset as u64))?;
-        let models = Model::parse(e_models.size as usize / SIZE_MODEL, r)?;
+        let mark_data = e_face_list.data(data, "mark surfaces", 2)?;
+        let mut mark_surfaces = Vec::with_capacity(mark_data.len() / 2);
+        for mut r in mark_data.chunks_exact(2) {
+            mark_surfaces.push(r.read_ushort()? as usize);
+        }
 
-        Ok(BspFile {
+        let bsp = BspFile {
+            entities: entities,
             light_maps: light_maps,
+            visibility: visibility,
             textures: textures,
             texture_info: texture_info,
             edges: edges,
@@ -100,22 +143,332 @@ impl BspFile {
             planes: planes,
             faces: faces,
             models: models,
-        })
+            nodes: nodes,
+            clip_nodes: clip_nodes,
+            leaves: leaves,
+            mark_surfaces: mark_surfaces,
+        };
+        bsp.check_indices()?;
+        Ok(bsp)
+    }
+
+    // Checks the indices from one lump into another are in range
+    fn check_indices(&self) -> error::Result<()> {
+        for (idx, info) in self.texture_info.iter().enumerate() {
+            check_index("texture info", idx, "texture", info.texture, self.textures.len())?;
+        }
+        for (idx, ledge) in self.ledges.iter().enumerate() {
+            check_index("ledge", idx, "edge", (*ledge as i64).abs() as usize, self.edges.len())?;
+        }
+        for (idx, face) in self.faces.iter().enumerate() {
+            check_index("face", idx, "plane", face.plane, self.planes.len())?;
+            check_range("face", idx, "ledges", &face.ledges, self.ledges.len())?;
+            check_index("face", idx, "texture info", face.texture_info, self.texture_info.len())?;
+            if face.ledges.len() < 3 {
+                bail!("face {} has {} edges but needs at least 3", idx, face.ledges.len());
+            }
+            // Like Quake only sky and liquid faces may be larger as
+            // they aren't lit, unless they have a light map anyway
+            if !self.texture_info[face.texture_info].animated || face.light_map != -1 {
+                let (_, size) = self.surface_extents(face);
+                let extents = [(size[0] - 1.0) * 16.0, (size[1] - 1.0) * 16.0];
+                if !(extents[0] <= MAX_SURFACE_EXTENTS && extents[1] <= MAX_SURFACE_EXTENTS) {
+                    bail!("face {} has bad surface extents {}x{}", idx, extents[0], extents[1]);
+                }
+            }
+            if face.light_map == -1 || face.light_map_count() == 0 {
+                continue;
+            }
+            let (_, size) = self.light_map_extents(face);
+            let end = size[0].checked_mul(size[1])
+                .and_then(|v| v.checked_mul(face.light_map_count()))
+                .and_then(|v| v.checked_add(face.light_map as usize));
+            if face.light_map < 0 || end.map_or(true, |end| end > self.light_maps.len()) {
+                bail!(
+                    "face {} has a {}x{} light map at {} outside of the {} bytes of lighting",
+                    idx, size[0], size[1], face.light_map, self.light_maps.len(),
+                );
+            }
+        }
+        for (idx, node) in self.nodes.iter().enumerate() {
+            check_index("node", idx, "plane", node.plane, self.planes.len())?;
+            for child in &node.children {
+                match *child {
+                    NodeChild::Node(id) => {
+                        check_index("node", idx, "node", id, self.nodes.len())?;
+                        check_child_order("node", idx, id)?;
+                    },
+                    NodeChild::Leaf(id) => check_index("node", idx, "leaf", id, self.leaves.len())?,
+                }
+            }
+            check_range("node", idx, "faces", &node.faces, self.faces.len())?;
+        }
+        for (idx, node) in self.clip_nodes.iter().enumerate() {
+            check_index("clip node", idx, "plane", node.plane, self.planes.len())?;
+            for child in &node.children {
+                if let ClipChild::Node(id) = *child {
+                    check_index("clip node", idx, "clip node", id, self.clip_nodes.len())?;
+                    check_child_order("clip node", idx, id)?;
+                }
+            }
+        }
+        for (idx, leaf) in self.leaves.iter().enumerate() {
+            check_range("leaf", idx, "mark surfaces", &leaf.mark_surfaces, self.mark_surfaces.len())?;
+        }
+        for (idx, face) in self.mark_surfaces.iter().enumerate() {
+            check_index("mark surface", idx, "face", *face, self.faces.len())?;
+        }
+        if self.models.is_empty() {
+            bail!("BSP file has no models");
+        }
+        for (idx, model) in self.models.iter().enumerate() {
+            check_range("model", idx, "faces", &model.faces, self.faces.len())?;
+            check_index("model", idx, "node", model.head_nodes[HULL_POINT], self.nodes.len())?;
+            // The third clip hull is never used but its head node
+            // is still checked like the others
+            for head in &model.head_nodes[HULL_PLAYER ..] {
+                check_index("model", idx, "clip node", *head, self.clip_nodes.len())?;
+            }
+            if model.vis_leafs >= self.leaves.len() {
+                bail!("model {} has {} visible leaves but there are only {} leaves", idx, model.vis_leafs, self.leaves.len());
+            }
+        }
+        Ok(())
+    }
+
+    // The first entity holds the settings of the level
+    pub fn worldspawn(&self) -> Option<&Entity> {
+        self.entities.first()
+            .filter(|e| e.classname() == Some("worldspawn"))
     }
+
+    // Walks the world's node tree to find the leaf
+    // containing the point
+    pub fn find_leaf(&self, point: Vector3<f32>) -> usize {
+        let mut node = self.models[0].head_nodes[0];
+        loop {
+            let n = &self.nodes[node];
+            let plane = &self.planes[n.plane];
+            let child = if point.dot(plane.normal) - plane.distance >= 0.0 {
+                n.children[0]
+            } else {
+                n.children[1]
+            };
+            match child {
+                NodeChild::Node(id) => node = id,
+                NodeChild::Leaf(id) => return id,
+            }
+        }
+    }
+
+    // Returns the set of leaves potentially visible from the
+    // given leaf, indexed by leaf id.
+    pub fn leaf_visibility(&self, leaf: usize) -> BitSet {
+        let num_leafs = self.models[0].vis_leafs;
+        let vis_offset = self.leaves.get(leaf).map_or(-1, |v| v.vis_offset);
+        if leaf == 0 || vis_offset < 0 || vis_offset as usize >= self.visibility.len() {
+            // No visibility information, everything is visible
+            let mut vis = BitSet::new(num_leafs + 1);
+            for i in 1 ..= num_leafs {
+                vis.set(i, true);
+            }
+            return vis;
+        }
+        decompress_vis(&self.visibility[vis_offset as usize..], num_leafs)
+    }
+
+    // The brightness of the floor below the point, as Quake lights
+    // its models. `styles` is the value of each light style with 1.0
+    // being normal brightness.
+    pub fn light_point(&self, point: Vector3<f32>, styles: &[f32]) -> f32 {
+        let end = point - Vector3::new(0.0, 0.0, 2048.0);
+        let head = NodeChild::Node(self.models[0].head_nodes[0]);
+        self.light_point_node(head, point, end, styles)
+            .unwrap_or(0.0)
+    }
+
+    fn light_point_node(&self, node: NodeChild, start: Vector3<f32>, end: Vector3<f32>, styles: &[f32]) -> Option<f32> {
+        let n = match node {
+            NodeChild::Node(id) => &self.nodes[id],
+            NodeChild::Leaf(_) => return None,
+        };
+        let plane = &self.planes[n.plane];
+        let front = start.dot(plane.normal) - plane.distance;
+        let back = end.dot(plane.normal) - plane.distance;
+        let side = (front < 0.0) as usize;
+        if (back < 0.0) as usize == side {
+            return self.light_point_node(n.children[side], start, end, styles);
+        }
+
+        let mid = start + (end - start) * (front / (front - back));
+        if let Some(light) = self.light_point_node(n.children[side], start, mid, styles) {
+            return Some(light);
+        }
+
+        // The line crosses the node's plane, check the faces on
+        // it for the point that was hit
+        for face in &self.faces[n.faces.clone()] {
+            let tex_info = &self.texture_info[face.texture_info];
+            let tex = &self.textures[tex_info.texture];
+            if tex.name.starts_with('*') || tex.name.starts_with("sky") {
+                continue;
+            }
+            let (mins, size) = self.light_map_extents(face);
+            let s = mid.dot(tex_info.vector_s) + tex_info.dist_s - mins[0];
+            let t = mid.dot(tex_info.vector_t) + tex_info.dist_t - mins[1];
+            if s < 0.0 || t < 0.0 || s > (size[0] - 1) as f32 * 16.0 || t > (size[1] - 1) as f32 * 16.0 {
+                continue;
+            }
+            if face.light_map < 0 {
+                return Some(0.0);
+            }
+
+            let offset = face.light_map as usize
+                + (t / 16.0) as usize * size[0]
+                + (s / 16.0) as usize;
+            let mut light = 0.0;
+            for (layer, style) in face.styles.iter().take(face.light_map_count()).enumerate() {
+                let value = self.light_maps.get(offset + layer * size[0] * size[1])
+                    .map_or(0.0, |v| *v as f32 / 255.0);
+                light += value * styles.get(*style as usize).cloned().unwrap_or(1.0);
+            }
+            return Some(light);
+        }
+
+        self.light_point_node(n.children[side ^ 1], mid, end, styles)
+    }
+
+    // The texture space position of the first sample of the face's
+    // light map and its width and height in samples. Light maps
+    // have a sample every 16 texels.
+    pub fn light_map_extents(&self, face: &Face) -> ([f32; 2], [usize; 2]) {
+        let (start, size) = self.surface_extents(face);
+        (
+            [start[0] * 16.0, start[1] * 16.0],
+            [size[0] as usize, size[1] as usize],
+        )
+    }
+
+    // The first light map sample and the number of samples along
+    // each axis. These are kept as floats as they can be anything
+    // until the face has been checked when parsing.
+    fn surface_extents(&self, face: &Face) -> ([f32; 2], [f32; 2]) {
+        use std::f32;
+        let tex_info = &self.texture_info[face.texture_info];
+        let mut min = [f32::INFINITY; 2];
+        let mut max = [f32::NEG_INFINITY; 2];
+        for ledge in &self.ledges[face.ledges.clone()] {
+            let vert = if *ledge < 0 {
+                self.edges[(-*ledge) as usize].1
+            } else {
+                self.edges[*ledge as usize].0
+            };
+            let st = [
+                vert.dot(tex_info.vector_s) + tex_info.dist_s,
+                vert.dot(tex_info.vector_t) + tex_info.dist_t,
+            ];
+            for i in 0 .. 2 {
+                min[i] = min[i].min(st[i]);
+                max[i] = max[i].max(st[i]);
+            }
+        }
+        let start = [(min[0] / 16.0).floor(), (min[1] / 16.0).floor()];
+        (
+            start,
+            [
+                (max[0] / 16.0).ceil() - start[0] + 1.0,
+                (max[1] / 16.0).ceil() - start[1] + 1.0,
+            ],
+        )
+    }
+}
+
+// A level for the collision tests made of only the clip hull,
+// every hull uses the given clip nodes and the point hull is a
+// single empty leaf
+#[cfg(test)]
+impl BspFile {
+    pub fn clip_level(planes: Vec<Plane>, clip_nodes: Vec<ClipNode>) -> BspFile {
+        BspFile {
+            entities: vec![],
+            light_maps: SharedData::default(),
+            visibility: SharedData::default(),
+            textures: vec![],
+            texture_info: vec![],
+            edges: vec![],
+            ledges: vec![],
+            planes: planes,
+            faces: vec![],
+            models: vec![Model {
+                bound: (Vector3::zero(), Vector3::zero()),
+                origin: Vector3::zero(),
+                head_nodes: [0, 0, 0, 0],
+                vis_leafs: 0,
+                faces: 0 .. 0,
+            }],
+            nodes: vec![Node {
+                plane: 0,
+                children: [NodeChild::Leaf(0), NodeChild::Leaf(0)],
+                bound: (Vector3::zero(), Vector3::zero()),
+                faces: 0 .. 0,
+            }],
+            clip_nodes: clip_nodes,
+            leaves: vec![Leaf {
+                contents: Contents::Empty,
+                vis_offset: -1,
+                bound: (Vector3::zero(), Vector3::zero()),
+                mark_surfaces: 0 .. 0,
+                ambient_level: [0; 4],
+            }],
+            mark_surfaces: vec![],
+        }
+    }
+}
+
+// Expands a run length encoded visibility row. Runs of zero
+// bytes are stored as a zero followed by the run length.
+//
+// The first bit of the row is leaf 1 as the solid leaf 0 is never
+// stored so the returned set is shifted to line up with leaf ids.
+pub fn decompress_vis(data: &[u8], num_leafs: usize) -> BitSet {
+    let mut vis = BitSet::new(num_leafs + 1);
+    let row = (num_leafs + 7) >> 3;
+    let mut data = data.iter();
+    let mut out = 0;
+    while out < row {
+        let b = match data.next() {
+            Some(b) => *b,
+            None => break,
+        };
+        if b == 0 {
+            out += data.next().map_or(row, |v| *v as usize);
+            continue;
+        }
+        for bit in 0 .. 8 {
+            let leaf = out * 8 + bit;
+            if b & (1 << bit) != 0 && leaf < num_leafs {
+                vis.set(leaf + 1, true);
+            }
+        }
+        out += 1;
+    }
+    vis
 }
+
 pub struct Model {
     pub bound: (Vector3<f32>, Vector3<f32>),
     pub origin: Vector3<f32>,
+    // Hull 0 starts at a node, hulls 1-3 start at a clip node
+    pub head_nodes: [usize; 4],
+    pub vis_leafs: usize,
     pub faces: Range<usize>,
 }
 
 impl Model {
-    pub fn parse<R>(count: usize, r: &mut R) -> error::Result<Vec<Model>>
-        where R: Read + Seek,
-    {
-        let mut models = Vec::with_capacity(count);
+    pub fn parse(data: &[u8]) -> error::Result<Vec<Model>> {
+        let mut models = Vec::with_capacity(data.len() / SIZE_MODEL);
 
-        for _ in 0 .. count {
+        for (idx, mut r) in data.chunks_exact(SIZE_MODEL).enumerate() {
             let bound_min = Vector3::new(
                 r.read_float()?,
                 r.read_float()?,
@@ -131,19 +484,24 @@ impl Model {
                 r.read_float()?,
                 r.read_float()?,
             );
-            let _node_id = [
-                r.read_long()?,
-                r.read_long()?,
-                r.read_long()?,
-                r.read_long()?,
+            let head_nodes = [
+                r.read_long()? as usize,
+                r.read_long()? as usize,
+                r.read_long()? as usize,
+                r.read_long()? as usize,
             ];
-            let _leafs = r.read_long()?;
+            let vis_leafs = r.read_long()?;
             let face_start = r.read_long()?;
             let face_number = r.read_long()?;
+            if vis_leafs < 0 || face_start < 0 || face_number < 0 {
+                bail!("model {} has a negative leaf or face count", idx);
+            }
 
             models.push(Model {
                 bound: (bound_min, bound_max),
                 origin: origin,
+                head_nodes: head_nodes,
+                vis_leafs: vis_leafs as usize,
                 faces: face_start as usize .. (face_start as usize + face_number as usize),
             });
         }
@@ -152,35 +510,201 @@ impl Model {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Contents {
+    Empty,
+    Solid,
+    Water,
+    Slime,
+    Lava,
+    Sky,
+    Other(i32),
+}
+
+impl Contents {
+    pub fn from_raw(v: i32) -> Contents {
+        match v {
+            -1 => Contents::Empty,
+            -2 => Contents::Solid,
+            -3 => Contents::Water,
+            -4 => Contents::Slime,
+            -5 => Contents::Lava,
+            -6 => Contents::Sky,
+            v => Contents::Other(v),
+        }
+    }
+
+    pub fn is_liquid(self) -> bool {
+        match self {
+            Contents::Water | Contents::Slime | Contents::Lava => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeChild {
+    Node(usize),
+    Leaf(usize),
+}
+
+impl NodeChild {
+    fn from_raw(v: i16) -> NodeChild {
+        if v < 0 {
+            NodeChild::Leaf((-1 - v as i32) as usize)
+        } else {
+            NodeChild::Node(v as usize)
+        }
+    }
+}
+
+pub struct Node {
+    pub plane: usize,
+    pub children: [NodeChild; 2],
+    pub bound: (Vector3<f32>, Vector3<f32>),
+    pub faces: Range<usize>,
+}
+
+impl Node {
+    pub fn parse(data: &[u8]) -> error::Result<Vec<Node>> {
+        let mut nodes = Vec::with_capacity(data.len() / SIZE_NODE);
+
+        for mut r in data.chunks_exact(SIZE_NODE) {
+            nodes.push(Node {
+                plane: r.read_ulong()? as usize,
+                children: [
+                    NodeChild::from_raw(r.read_short()?),
+                    NodeChild::from_raw(r.read_short()?),
+                ],
+                bound: read_short_bound(&mut r)?,
+                faces: {
+                    let start = r.read_ushort()? as usize;
+                    start .. (start + r.read_ushort()? as usize)
+                },
+            });
+        }
+
+        Ok(nodes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipChild {
+    Node(usize),
+    Contents(Contents),
+}
+
+impl ClipChild {
+    fn from_raw(v: i16) -> ClipChild {
+        if v < 0 {
+            ClipChild::Contents(Contents::from_raw(v as i32))
+        } else {
+            ClipChild::Node(v as usize)
+        }
+    }
+}
+
+pub struct ClipNode {
+    pub plane: usize,
+    pub children: [ClipChild; 2],
+}
+
+impl ClipNode {
+    pub fn parse(data: &[u8]) -> error::Result<Vec<ClipNode>> {
+        let mut nodes = Vec::with_capacity(data.len() / SIZE_CLIP_NODE);
+
+        for mut r in data.chunks_exact(SIZE_CLIP_NODE) {
+            nodes.push(ClipNode {
+                plane: r.read_ulong()? as usize,
+                children: [
+                    ClipChild::from_raw(r.read_short()?),
+                    ClipChild::from_raw(r.read_short()?),
+                ],
+            });
+        }
+
+        Ok(nodes)
+    }
+}
+
+pub struct Leaf {
+    pub contents: Contents,
+    // Offset into the visibility lump, -1 if the leaf has no visibility data
+    pub vis_offset: i32,
+    pub bound: (Vector3<f32>, Vector3<f32>),
+    pub mark_surfaces: Range<usize>,
+    pub ambient_level: [u8; 4],
+}
+
+impl Leaf {
+    pub fn parse(data: &[u8]) -> error::Result<Vec<Leaf>> {
+        let mut leaves = Vec::with_capacity(data.len() / SIZE_LEAF);
+
+        for mut r in data.chunks_exact(SIZE_LEAF) {
+            leaves.push(Leaf {
+                contents: Contents::from_raw(r.read_long()?),
+                vis_offset: r.read_long()?,
+                bound: read_short_bound(&mut r)?,
+                mark_surfaces: {
+                    let start = r.read_ushort()? as usize;
+                    start .. (start + r.read_ushort()? as usize)
+                },
+                ambient_level: [
+                    r.read_uchar()?,
+                    r.read_uchar()?,
+                    r.read_uchar()?,
+                    r.read_uchar()?,
+                ],
+            });
+        }
+
+        Ok(leaves)
+    }
+}
+
+fn read_short_bound<R>(r: &mut R) -> error::Result<(Vector3<f32>, Vector3<f32>)>
+    where R: Read,
+{
+    let min = Vector3::new(
+        r.read_short()? as f32,
+        r.read_short()? as f32,
+        r.read_short()? as f32,
+    );
+    let max = Vector3::new(
+        r.read_short()? as f32,
+        r.read_short()? as f32,
+        r.read_short()? as f32,
+    );
+    Ok((min, max))
+}
+
 pub struct Face {
     pub plane: usize,
     pub front: bool,
     pub ledges: Range<usize>,
     pub texture_info: usize,
-    pub type_light: u8,
-    pub base_light: u8,
-    pub light: [u8; 2],
+    // Up to four light styles, each with its own light map stacked
+    // one after another from light_map. Unused styles are 0xFF.
+    pub styles: [u8; 4],
     pub light_map: i32,
 }
 
 impl Face {
-    pub fn parse<R>(count: usize, r: &mut R) -> error::Result<Vec<Face>>
-        where R: Read + Seek,
-    {
-        let mut faces = Vec::with_capacity(count);
+    pub fn parse(data: &[u8]) -> error::Result<Vec<Face>> {
+        let mut faces = Vec::with_capacity(data.len() / SIZE_FACE);
 
-        for _ in 0 .. count {
+        for mut r in data.chunks_exact(SIZE_FACE) {
             faces.push(Face {
                 plane: r.read_ushort()? as usize,
                 front: r.read_ushort()? == 0,
                 ledges: {
-                    let start = r.read_long()? as usize;
+                    let start = r.read_ulong()? as usize;
                     start .. (start + r.read_ushort()? as usize)
                 },
                 texture_info: r.read_ushort()? as usize,
-                type_light: r.read_uchar()?,
-                base_light: r.read_uchar()?,
-                light: [
+                styles: [
+                    r.read_uchar()?,
+                    r.read_uchar()?,
                     r.read_uchar()?,
                     r.read_uchar()?,
                 ],
@@ -190,6 +714,12 @@ impl Face {
 
         Ok(faces)
     }
+
+    pub fn light_map_count(&self) -> usize {
+        self.styles.iter()
+            .take_while(|&&v| v != 0xFF)
+            .count()
+    }
 }
 
 pub struct Plane {
@@ -199,12 +729,10 @@ pub struct Plane {
 }
 
 impl Plane {
-    pub fn parse<R>(count: usize, r: &mut R) -> error::Result<Vec<Plane>>
-        where R: Read + Seek,
-    {
-        let mut planes = Vec::with_capacity(count);
+    pub fn parse(data: &[u8]) -> error::Result<Vec<Plane>> {
+        let mut planes = Vec::with_capacity(data.len() / SIZE_PLANE);
 
-        for _ in 0 .. count {
+        for mut r in data.chunks_exact(SIZE_PLANE) {
             planes.push(Plane {
                 normal: Vector3::new(
                     r.read_float()?,
@@ -223,15 +751,13 @@ impl Plane {
 pub struct Edge(pub Vector3<f32>, pub Vector3<f32>);
 
 impl Edge {
-    pub fn parse<R>(count: usize, vertices: Vec<Vector3<f32>>, r: &mut R) -> error::Result<Vec<Edge>>
-        where R: Read + Seek,
-    {
-        let mut edges = Vec::with_capacity(count);
-        for _ in 0 .. count {
-            edges.push(Edge(
-                vertices[r.read_ushort()? as usize],
-                vertices[r.read_ushort()? as usize],
-            ));
+    pub fn parse(data: &[u8], vertices: &[Vector3<f32>]) -> error::Result<Vec<Edge>> {
+        let mut edges = Vec::with_capacity(data.len() / SIZE_EDGE);
+        for (idx, mut r) in data.chunks_exact(SIZE_EDGE).enumerate() {
+            let a = r.read_ushort()? as usize;
+            let b = r.read_ushort()? as usize;
+            check_index("edge", idx, "vertex", a.max(b), vertices.len())?;
+            edges.push(Edge(vertices[a], vertices[b]));
         }
         Ok(edges)
     }
@@ -247,12 +773,10 @@ pub struct TextureInfo {
 }
 
 impl TextureInfo {
-    pub fn parse<R>(count: usize, r: &mut R) -> error::Result<Vec<TextureInfo>>
-        where R: Read + Seek,
-    {
-        let mut info = Vec::with_capacity(count);
+    pub fn parse(data: &[u8]) -> error::Result<Vec<TextureInfo>> {
+        let mut info = Vec::with_capacity(data.len() / SIZE_TEXTURE_INFO);
 
-        for _ in 0 .. count {
+        for mut r in data.chunks_exact(SIZE_TEXTURE_INFO) {
             let vector_s = Vector3::new(
                 r.read_float()?,
                 r.read_float()?,
@@ -292,11 +816,16 @@ pub struct Texture {
 }
 
 impl Texture {
-    pub fn parse_textures<R>(r: &mut R) -> error::Result<Vec<Texture>>
-        where R: Read + Seek,
-    {
-        let base_offset = r.seek(SeekFrom::Current(0))?;
+    pub fn parse_textures(data: &SharedData) -> error::Result<Vec<Texture>> {
+        // Levels without textures may leave out the lump entirely
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut r = &data[..];
         let count = r.read_long()?;
+        if count < 0 || count as usize > r.len() / 4 {
+            bail!("The textures lump is too small for its {} textures", count);
+        }
 
         let mut textures = Vec::with_capacity(count as usize);
         for id in 0 .. count {
@@ -309,18 +838,24 @@ impl Texture {
                 continue;
             }
 
-            let coffset = r.seek(SeekFrom::Current(0))?;
-
-            r.seek(SeekFrom::Start(base_offset + offset as u64))?;
-            let name = read_string!(r, 16);
+            let header = if offset >= 0 {
+                lump_range(data, offset as usize, SIZE_MIP_TEXTURE)
+            } else {
+                None
+            };
+            let mut t = match header {
+                Some(v) => &data[v],
+                None => bail!("texture {} at {} is outside of the textures lump", id, offset),
+            };
+            let name = read_string!(t, 16);
             let name = from_cstring(&name)?;
-            let width = r.read_ulong()?;
-            let height = r.read_ulong()?;
+            let width = t.read_ulong()?;
+            let height = t.read_ulong()?;
             let offsets = [
-                r.read_ulong()?,
-                r.read_ulong()?,
-                r.read_ulong()?,
-                r.read_ulong()?,
+                t.read_ulong()?,
+                t.read_ulong()?,
+                t.read_ulong()?,
+                t.read_ulong()?,
             ];
 
             let mut tex = Texture {
@@ -336,32 +871,51 @@ impl Texture {
                 ],
             };
 
-            for (i, o) in offsets.into_iter().enumerate() {
-                r.seek(SeekFrom::Start(base_offset + offset as u64 + *o as u64))?;
+            for (i, o) in offsets.iter().enumerate() {
                 let w = width >> i;
                 let h = height >> i;
-                let mut data = vec![0; (w * h) as usize];
-                r.read_exact(&mut data)?;
+                let picture = (w as usize).checked_mul(h as usize)
+                    .and_then(|size| lump_range(data, offset as usize + *o as usize, size));
+                let picture = match picture {
+                    Some(v) => v,
+                    None => bail!("texture {} ({}) mip level {} is outside of the textures lump", id, tex.name, i),
+                };
                 tex.pictures[i] = Picture {
                     width: w,
                     height: h,
-                    data: data,
+                    data: data.slice(picture),
                 };
             }
 
             textures.push(tex);
-            r.seek(SeekFrom::Start(coffset))?;
         }
 
         Ok(textures)
     }
+
+    // Animated textures are named `+0name` to `+9name` with the
+    // alternate frames named `+aname` to `+jname`. Returns the
+    // shared name, whether this is an alternate frame and the
+    // frame number.
+    pub fn animation_frame(&self) -> Option<(&str, bool, usize)> {
+        let mut chars = self.name.chars();
+        if chars.next() != Some('+') {
+            return None;
+        }
+        let (alternate, frame) = match chars.next()?.to_ascii_lowercase() {
+            c @ '0' ..= '9' => (false, c as usize - '0' as usize),
+            c @ 'a' ..= 'j' => (true, c as usize - 'a' as usize),
+            _ => return None,
+        };
+        Some((chars.as_str(), alternate, frame))
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Picture {
     pub width: u32,
     pub height: u32,
-    pub data: Vec<u8>,
+    pub data: SharedData,
 }
 
 struct Entry {
@@ -378,4 +932,210 @@ impl Entry {
             size: r.read_long()?,
         })
     }
-}
\ No newline at end of file
+
+    fn data<'a>(&self, data: &'a [u8], name: &str, record_size: usize) -> error::Result<&'a [u8]> {
+        Ok(&data[self.range(data, name, record_size)?])
+    }
+
+    // Where the lump's data is in the file, checked to be within
+    // the file and to hold a whole number of records
+    fn range(&self, data: &[u8], name: &str, record_size: usize) -> error::Result<Range<usize>> {
+        let lump = if self.offset >= 0 && self.size >= 0 {
+            lump_range(data, self.offset as usize, self.size as usize)
+        } else {
+            None
+        };
+        let lump = match lump {
+            Some(v) => v,
+            None => bail!(
+                "The {} lump at {} with size {} is outside of the file ({} bytes)",
+                name, self.offset, self.size, data.len(),
+            ),
+        };
+        if lump.len() % record_size != 0 {
+            bail!("The {} lump's size {} isn't a multiple of {}", name, lump.len(), record_size);
+        }
+        Ok(lump)
+    }
+}
+
+fn lump_range(data: &[u8], offset: usize, size: usize) -> Option<Range<usize>> {
+    let end = offset.checked_add(size)?;
+    if end <= data.len() {
+        Some(offset .. end)
+    } else {
+        None
+    }
+}
+
+fn check_index(kind: &str, idx: usize, target: &str, value: usize, count: usize) -> error::Result<()> {
+    if value >= count {
+        bail!("{} {} references {} {} but there are only {}", kind, idx, target, value, count);
+    }
+    Ok(())
+}
+
+// qbsp writes nodes before their children, so requiring that
+// stops a corrupt tree from looping back on itself
+fn check_child_order(kind: &str, idx: usize, child: usize) -> error::Result<()> {
+    if child <= idx {
+        bail!("{} {} has child {} {} which doesn't come after it", kind, idx, kind, child);
+    }
+    Ok(())
+}
+
+fn check_range(kind: &str, idx: usize, target: &str, range: &Range<usize>, count: usize) -> error::Result<()> {
+    if range.end > count {
+        bail!("{} {} references {} {}..{} but there are only {}", kind, idx, target, range.start, range.end, count);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_animation_frame() {
+    let tex = |name: &str| Texture {
+        name: name.into(),
+        .. Texture::default()
+    };
+    assert_eq!(tex("+0button").animation_frame(), Some(("button", false, 0)));
+    assert_eq!(tex("+9slip").animation_frame(), Some(("slip", false, 9)));
+    assert_eq!(tex("+Cbutton").animation_frame(), Some(("button", true, 2)));
+    assert_eq!(tex("+kbutton").animation_frame(), None);
+    assert_eq!(tex("*water0").animation_frame(), None);
+    assert_eq!(tex("+").animation_frame(), None);
+}
+
+#[test]
+fn test_decompress_vis() {
+    // Leaves 1, 2, skip 24 leaves, then leaf 33 and 40
+    let vis = decompress_vis(&[0b0000_0011, 0, 3, 0b1000_0001], 40);
+    for leaf in 0 ..= 40 {
+        assert_eq!(vis.get(leaf), leaf == 1 || leaf == 2 || leaf == 33 || leaf == 40, "leaf {}", leaf);
+    }
+
+    // Truncated data shouldn't panic
+    let vis = decompress_vis(&[0xFF, 0], 64);
+    assert!(vis.get(8));
+    assert!(!vis.get(9));
+}
+
+#[test]
+fn test_parse_checks_lumps() {
+    // A single lit triangle on the floor of an open leaf
+    let mut lumps = vec![vec![]; 15];
+    lumps[0] = b"{\n\"classname\" \"worldspawn\"\n}\n\0".to_vec();
+    lumps[1].write_floats(&[0.0, 0.0, 1.0, 0.0]);
+    lumps[1].write_long(2);
+    lumps[2].write_longs(&[1, 8]);
+    lumps[2].extend_from_slice(b"floor\0\0\0\0\0\0\0\0\0\0\0");
+    lumps[2].write_longs(&[2, 2, 40, 44, 45, 45]);
+    lumps[2].extend_from_slice(&[1, 2, 3, 4, 5]);
+    lumps[3].write_floats(&[0.0, 0.0, 0.0, 64.0, 0.0, 0.0, 0.0, 64.0, 0.0]);
+    lumps[5].write_long(0);
+    lumps[5].write_shorts(&[-1, -2, 0, 0, 0, 64, 64, 0, 0, 1]);
+    lumps[6].write_floats(&[1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+    lumps[6].write_longs(&[0, 0]);
+    lumps[7].write_shorts(&[0, 0]);
+    lumps[7].write_long(0);
+    lumps[7].write_shorts(&[3, 0]);
+    lumps[7].extend_from_slice(&[0, 255, 255, 255]);
+    lumps[7].write_long(0);
+    lumps[8] = vec![128; 25];
+    lumps[9].write_long(0);
+    lumps[9].write_shorts(&[-1, -2]);
+    for &(contents, surfaces) in &[(-2, 0), (-1, 1)] {
+        lumps[10].write_longs(&[contents, -1]);
+        lumps[10].write_shorts(&[0, 0, 0, 64, 64, 0, 0, surfaces]);
+        lumps[10].write_long(0);
+    }
+    lumps[11].write_short(0);
+    lumps[12].write_shorts(&[0, 0, 0, 1, 1, 2, 2, 0]);
+    lumps[13].write_longs(&[1, 2, 3]);
+    lumps[14].write_floats(&[0.0; 9]);
+    lumps[14].write_longs(&[0, 0, 0, 0, 1, 0, 1]);
+
+    let build = |lumps: &[Vec<u8>]| {
+        let mut data = vec![];
+        data.write_long(29);
+        let mut offset = SIZE_HEADER;
+        for lump in lumps {
+            data.write_longs(&[offset as i32, lump.len() as i32]);
+            offset += lump.len();
+        }
+        for lump in lumps {
+            data.extend_from_slice(lump);
+        }
+        data
+    };
+    let error = |data: &[u8]| BspFile::parse(data).err().map(|e| e.to_string());
+
+    let file = SharedData::from(build(&lumps));
+    let bsp = BspFile::parse_shared(&file).unwrap();
+    assert_eq!(bsp.worldspawn().and_then(|e| e.classname()), Some("worldspawn"));
+    assert_eq!(&bsp.textures[0].pictures[0].data[..], [1, 2, 3, 4]);
+    // The lighting and textures are shared with the file
+    let lighting = SIZE_HEADER + lumps[.. 8].iter().map(|v| v.len()).sum::<usize>();
+    assert_eq!(bsp.light_maps.as_ptr(), file[lighting ..].as_ptr());
+    let texture = SIZE_HEADER + lumps[.. 2].iter().map(|v| v.len()).sum::<usize>() + 48;
+    assert_eq!(bsp.textures[0].pictures[0].data.as_ptr(), file[texture ..].as_ptr());
+    assert_eq!(bsp.light_map_extents(&bsp.faces[0]), ([0.0, 0.0], [5, 5]));
+    assert_eq!(bsp.find_leaf(Vector3::new(16.0, 16.0, 16.0)), 0);
+
+    let mut message = lumps.clone();
+    message[0] = b"{\n\"classname\" \"worldspawn\"\n\"message\" \"\x90Hi\x91\"\n}\n\0".to_vec();
+    let bsp = BspFile::parse(&build(&message)).unwrap();
+    assert_eq!(bsp.worldspawn().and_then(|e| e.get("message")), Some("\u{90}Hi\u{91}"));
+
+    let mut bad = lumps.clone();
+    bad[7][10] = 1;
+    assert_eq!(error(&build(&bad)).unwrap(), "face 0 references texture info 1 but there are only 1");
+
+    let mut bad = lumps.clone();
+    bad[12][10] = 3;
+    assert_eq!(error(&build(&bad)).unwrap(), "edge 2 references vertex 3 but there are only 3");
+
+    let mut bad = lumps.clone();
+    bad[8].pop();
+    assert_eq!(error(&build(&bad)).unwrap(), "face 0 has a 5x5 light map at 0 outside of the 24 bytes of lighting");
+
+    let mut bad = lumps.clone();
+    bad[2][28] = 200;
+    assert_eq!(error(&build(&bad)).unwrap(), "texture 0 (floor) mip level 0 is outside of the textures lump");
+
+    let mut bad = lumps.clone();
+    bad[13][4] = 0xFF;
+    assert_eq!(error(&build(&bad)).unwrap(), "ledge 1 references edge 255 but there are only 4");
+
+    let mut bad = lumps.clone();
+    bad[5][6 .. 8].copy_from_slice(&0i16.to_le_bytes());
+    assert_eq!(error(&build(&bad)).unwrap(), "node 0 has child node 0 which doesn't come after it");
+
+    let mut bad = lumps.clone();
+    bad[9][6 .. 8].copy_from_slice(&0i16.to_le_bytes());
+    assert_eq!(error(&build(&bad)).unwrap(), "clip node 0 has child clip node 0 which doesn't come after it");
+
+    let mut bad = lumps.clone();
+    bad[3][4 .. 8].copy_from_slice(&1e30f32.to_le_bytes());
+    assert!(error(&build(&bad)).unwrap().starts_with("face 0 has bad surface extents 64x"));
+
+    let mut bad = lumps.clone();
+    bad[7][8] = 2;
+    assert_eq!(error(&build(&bad)).unwrap(), "face 0 has 2 edges but needs at least 3");
+
+    let mut bad = lumps.clone();
+    bad[14][48] = 1;
+    assert_eq!(error(&build(&bad)).unwrap(), "model 0 references clip node 1 but there are only 1");
+
+    let mut bad = lumps.clone();
+    bad[5].push(0);
+    assert_eq!(error(&build(&bad)).unwrap(), "The nodes lump's size 25 isn't a multiple of 24");
+
+    let data = build(&lumps);
+    let len = data.len() as i32;
+    let data = with_longs(&data, 12, &[len]);
+    assert_eq!(
+        error(&data).unwrap(),
+        format!("The planes lump at {} with size 20 is outside of the file ({} bytes)", len, len),
+    );
+    assert!(error(&data[.. 100]).is_some());
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use log::*;
+
+use crate::input::parse_commands;
+
+// Lines of output kept for scrolling back through
+const MAX_LINES: usize = 256;
+const MAX_HISTORY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Float(f32),
+}
+
+impl Value {
+    // Parses text as a value of the same type
+    fn parse_as(self, text: &str) -> Option<Value> {
+        Some(match self {
+            Value::Bool(_) => Value::Bool(match text {
+                "1" | "true" => true,
+                "0" | "false" => false,
+                _ => return None,
+            }),
+            Value::Float(_) => Value::Float(text.parse().ok()?),
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(v) => f.write_str(if *v { "1" } else { "0" }),
+            Value::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+struct Cvar {
+    value: Value,
+    default: Value,
+    help: &'static str,
+}
+
+// Commands typed into the console or bound to keys. Cvars are
+// handled here, commands are checked against the registered
+// ones and handed back to the game to run.
+pub struct Console {
+    cvars: BTreeMap<String, Cvar>,
+    commands: BTreeMap<String, &'static str>,
+
+    lines: Vec<String>,
+    history: Vec<String>,
+    // Position in the history while stepping through it
+    history_pos: Option<usize>,
+    pub input: String,
+    pub open: bool,
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
+impl Console {
+    pub fn new() -> Console {
+        let mut console = Console {
+            cvars: BTreeMap::new(),
+            commands: BTreeMap::new(),
+            lines: vec![],
+            history: vec![],
+            history_pos: None,
+            input: String::new(),
+            open: false,
+        };
+        console.register_command("cvarlist", "Lists the cvars and their values");
+        console.register_command("cmdlist", "Lists the commands");
+        console.register_command("clear", "Clears the console");
+        console.register_command("toggleconsole", "Opens or closes the console");
+        console
+    }
+
+    pub fn register_command(&mut self, name: &str, help: &'static str) {
+        self.commands.insert(name.to_owned(), help);
+    }
+
+    pub fn register_cvar(&mut self, name: &str, value: Value, help: &'static str) {
+        self.cvars.insert(name.to_owned(), Cvar {
+            value: value,
+            default: value,
+            help: help,
+        });
+    }
+
+    pub fn cvar(&self, name: &str) -> Option<Value> {
+        self.cvars.get(name).map(|v| v.value)
+    }
+
+    // The value of a float cvar, 0 if it doesn't exist
+    pub fn float(&self, name: &str) -> f32 {
+        match self.cvar(name) {
+            Some(Value::Float(v)) => v,
+            _ => 0.0,
+        }
+    }
+
+    pub fn bool(&self, name: &str) -> bool {
+        match self.cvar(name) {
+            Some(Value::Bool(v)) => v,
+            _ => false,
+        }
+    }
+
+    pub fn print(&mut self, line: impl Into<String>) {
+        self.lines.push(line.into());
+        if self.lines.len() > MAX_LINES {
+            let extra = self.lines.len() - MAX_LINES;
+            self.lines.drain(.. extra);
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    // Runs the commands in the text, returning the ones the
+    // game has to handle
+    pub fn execute(&mut self, text: &str) -> Vec<Vec<String>> {
+        self.run(text, false)
+    }
+
+    // Runs a config file like `execute`. Unknown commands are only
+    // logged as config.cfg contains many settings that don't apply
+    // here.
+    pub fn execute_config(&mut self, text: &str) -> Vec<Vec<String>> {
+        self.run(text, true)
+    }
+
+    fn run(&mut self, text: &str, quiet: bool) -> Vec<Vec<String>> {
+        let mut commands = vec![];
+        for args in parse_commands(text) {
+            let name = args[0].as_str();
+            if let Some(cvar) = self.cvars.get_mut(name) {
+                match args.get(1) {
+                    Some(text) => match cvar.value.parse_as(text) {
+                        Some(v) => cvar.value = v,
+                        None => {
+                            let line = format!("Invalid value for {}: {}", name, text);
+                            self.print(line);
+                        },
+                    },
+                    None => {
+                        let line = format!(
+                            "\"{}\" is \"{}\", default \"{}\"",
+                            name, cvar.value, cvar.default,
+                        );
+                        self.print(line);
+                    },
+                }
+                continue;
+            }
+            match name {
+                "cvarlist" => {
+                    let lines = self.cvars.iter()
+                        .map(|(name, cvar)| format!("{} \"{}\" - {}", name, cvar.value, cvar.help))
+                        .collect::<Vec<_>>();
+                    for line in lines {
+                        self.print(line);
+                    }
+                },
+                "cmdlist" => {
+                    let lines = self.commands.iter()
+                        .map(|(name, help)| format!("{} - {}", name, help))
+                        .collect::<Vec<_>>();
+                    for line in lines {
+                        self.print(line);
+                    }
+                },
+                "clear" => self.lines.clear(),
+                "toggleconsole" => self.open = !self.open,
+                _ if self.commands.contains_key(name) => commands.push(args),
+                _ if quiet => debug!("Ignoring config command: {:?}", args),
+                _ => self.print(format!("Unknown command \"{}\"", name)),
+            }
+        }
+        commands
+    }
+
+    // Runs the typed line, adding it to the history
+    pub fn submit(&mut self) -> Vec<Vec<String>> {
+        let line = std::mem::replace(&mut self.input, String::new());
+        self.history_pos = None;
+        self.print(format!("]{}", line));
+        if line.trim().is_empty() {
+            return vec![];
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+            if self.history.len() > MAX_HISTORY {
+                self.history.remove(0);
+            }
+        }
+        self.execute(&line)
+    }
+
+    // Steps back through the history when `back` is set or
+    // forward towards an empty line otherwise
+    pub fn history_step(&mut self, back: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match (self.history_pos, back) {
+            (None, true) => Some(self.history.len() - 1),
+            (None, false) => None,
+            (Some(pos), true) => Some(pos.saturating_sub(1)),
+            (Some(pos), false) if pos + 1 < self.history.len() => Some(pos + 1),
+            (Some(_), false) => None,
+        };
+        self.history_pos = pos;
+        self.input = match pos {
+            Some(pos) => self.history[pos].clone(),
+            None => String::new(),
+        };
+    }
+
+    // Completes the command or cvar name being typed. If more than
+    // one matches the shared part is completed and the matches
+    // are printed.
+    pub fn complete(&mut self) {
+        let prefix = self.input.trim_start();
+        if prefix.is_empty() || prefix.contains(' ') {
+            return;
+        }
+        let matches = self.commands.keys()
+            .chain(self.cvars.keys())
+            .filter(|v| v.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        match matches.len() {
+            0 => {},
+            1 => self.input = format!("{} ", matches[0]),
+            _ => {
+                let mut common = matches[0].clone();
+                for name in &matches[1..] {
+                    let len = common.bytes()
+                        .zip(name.bytes())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    common.truncate(len);
+                }
+                self.input = common;
+                for name in matches {
+                    self.print(format!("  {}", name));
+                }
+            },
+        }
+    }
+}
+
+#[test]
+fn test_console() {
+    let mut console = Console::new();
+    console.register_cvar("fov", Value::Float(90.0), "Field of view");
+    console.register_cvar("r_drawworld", Value::Bool(true), "Draw the level");
+    console.register_command("map", "Loads a map");
+    console.register_command("maps", "Lists the maps");
+
+    let commands = console.execute("fov 110; r_drawworld 0; map e1m1; bogus");
+    assert_eq!(commands, vec![vec!["map".to_owned(), "e1m1".to_owned()]]);
+    assert_eq!(console.float("fov"), 110.0);
+    assert!(!console.bool("r_drawworld"));
+    assert_eq!(console.lines().last().map(|v| v.as_str()), Some("Unknown command \"bogus\""));
+
+    console.execute("fov wide");
+    assert_eq!(console.float("fov"), 110.0);
+
+    // Settings in a config that don't apply here are skipped quietly
+    let lines = console.lines().len();
+    let commands = console.execute_config("vid_mode 0\nfov 100\nmap e1m2");
+    assert_eq!(commands, vec![vec!["map".to_owned(), "e1m2".to_owned()]]);
+    assert_eq!(console.float("fov"), 100.0);
+    assert_eq!(console.lines().len(), lines);
+
+    console.input = "ma".to_owned();
+    console.complete();
+    assert_eq!(console.input, "map");
+    console.input = "r_d".to_owned();
+    console.complete();
+    assert_eq!(console.input, "r_drawworld ");
+
+    console.input = "fov 90".to_owned();
+    console.submit();
+    console.input = "maps".to_owned();
+    console.submit();
+    console.history_step(true);
+    assert_eq!(console.input, "maps");
+    console.history_step(true);
+    assert_eq!(console.input, "fov 90");
+    console.history_step(false);
+    console.history_step(false);
+    assert_eq!(console.input, "");
+}
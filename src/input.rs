@@ -0,0 +1,417 @@
+use std::collections::{HashMap, HashSet};
+use cgmath::{Deg, Rad, Vector3};
+use winit::{VirtualKeyCode, MouseButton};
+use log::*;
+
+use crate::player::MoveCommand;
+
+const FORWARD_SPEED: f32 = 200.0;
+const BACK_SPEED: f32 = 200.0;
+const SIDE_SPEED: f32 = 350.0;
+const UP_SPEED: f32 = 200.0;
+const SPEED_KEY_SCALE: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Keyboard(VirtualKeyCode),
+    Mouse(MouseButton),
+}
+
+// Actions that stay active while their key is held,
+// bound using `+name` commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    Forward,
+    Back,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    Speed,
+    Jump,
+}
+
+impl Button {
+    fn from_name(name: &str) -> Option<Button> {
+        Some(match name {
+            "forward" => Button::Forward,
+            "back" => Button::Back,
+            "moveleft" => Button::MoveLeft,
+            "moveright" => Button::MoveRight,
+            "moveup" => Button::MoveUp,
+            "movedown" => Button::MoveDown,
+            "speed" => Button::Speed,
+            "jump" => Button::Jump,
+            _ => return None,
+        })
+    }
+}
+
+pub struct Input {
+    bindings: HashMap<Key, String>,
+    held: HashSet<Button>,
+
+    // Set from the console's cvars of the same names
+    pub sensitivity: f32,
+    // Degrees turned per unit of mouse movement before sensitivity,
+    // a negative pitch inverts the y axis.
+    pub m_yaw: f32,
+    pub m_pitch: f32,
+}
+
+impl Default for Input {
+    fn default() -> Input {
+        Input::new()
+    }
+}
+
+impl Input {
+    pub fn new() -> Input {
+        let mut input = Input {
+            bindings: HashMap::new(),
+            held: HashSet::new(),
+            sensitivity: 3.0,
+            m_yaw: 0.022,
+            m_pitch: 0.022,
+        };
+        input.exec_config(DEFAULT_CONFIG);
+        input.exec_config(VIEWER_CONFIG);
+        input
+    }
+
+    pub fn invert_y(&self) -> bool {
+        self.m_pitch < 0.0
+    }
+
+    pub fn bind(&mut self, key: Key, command: &str) {
+        self.bindings.insert(key, command.to_owned());
+    }
+
+    pub fn unbind(&mut self, key: Key) {
+        self.bindings.remove(&key);
+    }
+
+    pub fn binding(&self, key: Key) -> Option<&str> {
+        self.bindings.get(&key).map(|v| v.as_str())
+    }
+
+    // Quake's config.cfg starts with unbindall, which would also
+    // remove the viewer's own keys. A leading unbindall is taken out
+    // of the commands and run straight away, binding the viewer's
+    // keys again so the rest of the config can still change them.
+    pub fn take_unbindall(&mut self, commands: &mut Vec<Vec<String>>) {
+        if commands.first().map_or(false, |v| v.len() == 1 && v[0] == "unbindall") {
+            commands.remove(0);
+            self.bindings.clear();
+            self.exec_config(VIEWER_CONFIG);
+        }
+    }
+
+    // Runs the lines of a Quake style config file. Unknown commands
+    // are skipped as config.cfg contains many settings that don't
+    // apply here.
+    pub fn exec_config(&mut self, data: &str) {
+        for args in parse_commands(data) {
+            if !self.execute(&args) {
+                debug!("Ignoring config command: {:?}", args);
+            }
+        }
+    }
+
+    // Returns whether the command was handled
+    pub fn execute(&mut self, args: &[String]) -> bool {
+        let args: Vec<&str> = args.iter().map(|v| v.as_str()).collect();
+        match args.as_slice() {
+            ["bind", key, command] => match key_from_name(key) {
+                Some(key) => self.bind(key, command),
+                None => warn!("Unknown key {:?}", key),
+            },
+            ["unbind", key] => match key_from_name(key) {
+                Some(key) => self.unbind(key),
+                None => warn!("Unknown key {:?}", key),
+            },
+            ["unbindall"] => self.bindings.clear(),
+            _ => return false,
+        }
+        true
+    }
+
+    // Updates the state of held buttons, returning the bound command
+    // for other bindings when the key is pressed.
+    pub fn key_event(&mut self, key: Key, pressed: bool) -> Option<String> {
+        // Like Quake the keys on either side share a binding
+        let key = match key {
+            Key::Keyboard(VirtualKeyCode::RShift) => Key::Keyboard(VirtualKeyCode::LShift),
+            Key::Keyboard(VirtualKeyCode::RControl) => Key::Keyboard(VirtualKeyCode::LControl),
+            Key::Keyboard(VirtualKeyCode::RAlt) => Key::Keyboard(VirtualKeyCode::LAlt),
+            key => key,
+        };
+        let command = self.bindings.get(&key)?;
+        if command.starts_with('+') {
+            if let Some(button) = Button::from_name(&command[1..]) {
+                if pressed {
+                    self.held.insert(button);
+                } else {
+                    self.held.remove(&button);
+                }
+            }
+            None
+        } else if pressed {
+            Some(command.clone())
+        } else {
+            None
+        }
+    }
+
+    // Releases all buttons, used when focus is lost as the release
+    // events would be missed.
+    pub fn release_all(&mut self) {
+        self.held.clear();
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held.contains(&button)
+    }
+
+    // Converts a mouse movement in pixels into a change
+    // of yaw and pitch
+    pub fn mouse_look(&self, dx: f32, dy: f32) -> (Rad<f32>, Rad<f32>) {
+        (
+            Deg(dx * self.sensitivity * self.m_yaw).into(),
+            Deg(dy * self.sensitivity * self.m_pitch).into(),
+        )
+    }
+
+    pub fn move_command(&self, forward: Vector3<f32>, right: Vector3<f32>) -> MoveCommand {
+        let axis = |pos, neg, pos_speed, neg_speed| {
+            let mut v = 0.0;
+            if self.is_held(pos) {
+                v += pos_speed;
+            }
+            if self.is_held(neg) {
+                v -= neg_speed;
+            }
+            v
+        };
+        let scale = if self.is_held(Button::Speed) { SPEED_KEY_SCALE } else { 1.0 };
+        MoveCommand {
+            forward,
+            right,
+            forward_move: axis(Button::Forward, Button::Back, FORWARD_SPEED, BACK_SPEED) * scale,
+            side_move: axis(Button::MoveRight, Button::MoveLeft, SIDE_SPEED, SIDE_SPEED) * scale,
+            up_move: axis(Button::MoveUp, Button::MoveDown, UP_SPEED, UP_SPEED) * scale,
+            jump: self.is_held(Button::Jump),
+        }
+    }
+}
+
+const DEFAULT_CONFIG: &str = r#"
+bind w +forward
+bind s +back
+bind a +moveleft
+bind d +moveright
+bind uparrow +forward
+bind downarrow +back
+bind space +jump
+bind e +moveup
+bind c +movedown
+bind shift +speed
+"#;
+
+// Keys for the viewer's own commands, kept by `take_unbindall`
+const VIEWER_CONFIG: &str = r#"
+bind v noclip
+bind n nextspawn
+bind p nextmap
+bind escape togglemouse
+"#;
+
+// Splits config text into commands. Commands end at a new line
+// or a `;` outside of quotes and `//` starts a comment.
+pub fn parse_commands(data: &str) -> Vec<Vec<String>> {
+    let mut commands = vec![];
+    let mut args = vec![];
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | ';' => {
+                if !args.is_empty() {
+                    commands.push(args);
+                    args = vec![];
+                }
+            },
+            '/' if chars.peek() == Some(&'/') => {
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            },
+            '"' => {
+                let mut arg = String::new();
+                while let Some(c) = chars.next() {
+                    if c == '"' || c == '\n' {
+                        break;
+                    }
+                    arg.push(c);
+                }
+                args.push(arg);
+            },
+            c if c.is_whitespace() => {},
+            c => {
+                let mut arg = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' || c == '"' {
+                        break;
+                    }
+                    arg.push(c);
+                    chars.next();
+                }
+                args.push(arg);
+            },
+        }
+    }
+    if !args.is_empty() {
+        commands.push(args);
+    }
+    commands
+}
+
+pub fn key_from_name(name: &str) -> Option<Key> {
+    let name = name.to_lowercase();
+    KEY_NAMES.iter()
+        .find(|v| v.0 == name)
+        .map(|v| v.1)
+}
+
+pub fn key_name(key: Key) -> Option<&'static str> {
+    KEY_NAMES.iter()
+        .find(|v| v.1 == key)
+        .map(|v| v.0)
+}
+
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("a", Key::Keyboard(VirtualKeyCode::A)),
+    ("b", Key::Keyboard(VirtualKeyCode::B)),
+    ("c", Key::Keyboard(VirtualKeyCode::C)),
+    ("d", Key::Keyboard(VirtualKeyCode::D)),
+    ("e", Key::Keyboard(VirtualKeyCode::E)),
+    ("f", Key::Keyboard(VirtualKeyCode::F)),
+    ("g", Key::Keyboard(VirtualKeyCode::G)),
+    ("h", Key::Keyboard(VirtualKeyCode::H)),
+    ("i", Key::Keyboard(VirtualKeyCode::I)),
+    ("j", Key::Keyboard(VirtualKeyCode::J)),
+    ("k", Key::Keyboard(VirtualKeyCode::K)),
+    ("l", Key::Keyboard(VirtualKeyCode::L)),
+    ("m", Key::Keyboard(VirtualKeyCode::M)),
+    ("n", Key::Keyboard(VirtualKeyCode::N)),
+    ("o", Key::Keyboard(VirtualKeyCode::O)),
+    ("p", Key::Keyboard(VirtualKeyCode::P)),
+    ("q", Key::Keyboard(VirtualKeyCode::Q)),
+    ("r", Key::Keyboard(VirtualKeyCode::R)),
+    ("s", Key::Keyboard(VirtualKeyCode::S)),
+    ("t", Key::Keyboard(VirtualKeyCode::T)),
+    ("u", Key::Keyboard(VirtualKeyCode::U)),
+    ("v", Key::Keyboard(VirtualKeyCode::V)),
+    ("w", Key::Keyboard(VirtualKeyCode::W)),
+    ("x", Key::Keyboard(VirtualKeyCode::X)),
+    ("y", Key::Keyboard(VirtualKeyCode::Y)),
+    ("z", Key::Keyboard(VirtualKeyCode::Z)),
+    ("0", Key::Keyboard(VirtualKeyCode::Key0)),
+    ("1", Key::Keyboard(VirtualKeyCode::Key1)),
+    ("2", Key::Keyboard(VirtualKeyCode::Key2)),
+    ("3", Key::Keyboard(VirtualKeyCode::Key3)),
+    ("4", Key::Keyboard(VirtualKeyCode::Key4)),
+    ("5", Key::Keyboard(VirtualKeyCode::Key5)),
+    ("6", Key::Keyboard(VirtualKeyCode::Key6)),
+    ("7", Key::Keyboard(VirtualKeyCode::Key7)),
+    ("8", Key::Keyboard(VirtualKeyCode::Key8)),
+    ("9", Key::Keyboard(VirtualKeyCode::Key9)),
+    ("f1", Key::Keyboard(VirtualKeyCode::F1)),
+    ("f2", Key::Keyboard(VirtualKeyCode::F2)),
+    ("f3", Key::Keyboard(VirtualKeyCode::F3)),
+    ("f4", Key::Keyboard(VirtualKeyCode::F4)),
+    ("f5", Key::Keyboard(VirtualKeyCode::F5)),
+    ("f6", Key::Keyboard(VirtualKeyCode::F6)),
+    ("f7", Key::Keyboard(VirtualKeyCode::F7)),
+    ("f8", Key::Keyboard(VirtualKeyCode::F8)),
+    ("f9", Key::Keyboard(VirtualKeyCode::F9)),
+    ("f10", Key::Keyboard(VirtualKeyCode::F10)),
+    ("f11", Key::Keyboard(VirtualKeyCode::F11)),
+    ("f12", Key::Keyboard(VirtualKeyCode::F12)),
+    ("tab", Key::Keyboard(VirtualKeyCode::Tab)),
+    ("enter", Key::Keyboard(VirtualKeyCode::Return)),
+    ("escape", Key::Keyboard(VirtualKeyCode::Escape)),
+    ("space", Key::Keyboard(VirtualKeyCode::Space)),
+    ("backspace", Key::Keyboard(VirtualKeyCode::Back)),
+    ("uparrow", Key::Keyboard(VirtualKeyCode::Up)),
+    ("downarrow", Key::Keyboard(VirtualKeyCode::Down)),
+    ("leftarrow", Key::Keyboard(VirtualKeyCode::Left)),
+    ("rightarrow", Key::Keyboard(VirtualKeyCode::Right)),
+    ("alt", Key::Keyboard(VirtualKeyCode::LAlt)),
+    ("ctrl", Key::Keyboard(VirtualKeyCode::LControl)),
+    ("shift", Key::Keyboard(VirtualKeyCode::LShift)),
+    ("ins", Key::Keyboard(VirtualKeyCode::Insert)),
+    ("del", Key::Keyboard(VirtualKeyCode::Delete)),
+    ("pgdn", Key::Keyboard(VirtualKeyCode::PageDown)),
+    ("pgup", Key::Keyboard(VirtualKeyCode::PageUp)),
+    ("home", Key::Keyboard(VirtualKeyCode::Home)),
+    ("end", Key::Keyboard(VirtualKeyCode::End)),
+    ("pause", Key::Keyboard(VirtualKeyCode::Pause)),
+    ("semicolon", Key::Keyboard(VirtualKeyCode::Semicolon)),
+    ("`", Key::Keyboard(VirtualKeyCode::Grave)),
+    ("-", Key::Keyboard(VirtualKeyCode::Minus)),
+    ("=", Key::Keyboard(VirtualKeyCode::Equals)),
+    ("[", Key::Keyboard(VirtualKeyCode::LBracket)),
+    ("]", Key::Keyboard(VirtualKeyCode::RBracket)),
+    ("\\", Key::Keyboard(VirtualKeyCode::Backslash)),
+    ("'", Key::Keyboard(VirtualKeyCode::Apostrophe)),
+    (",", Key::Keyboard(VirtualKeyCode::Comma)),
+    (".", Key::Keyboard(VirtualKeyCode::Period)),
+    ("/", Key::Keyboard(VirtualKeyCode::Slash)),
+    ("mouse1", Key::Mouse(MouseButton::Left)),
+    ("mouse2", Key::Mouse(MouseButton::Right)),
+    ("mouse3", Key::Mouse(MouseButton::Middle)),
+];
+
+#[test]
+fn test_config() {
+    let mut input = Input::new();
+    input.exec_config(r#"
+unbindall
+bind "w" "+forward"
+bind UPARROW "+forward"; bind "mouse2" "+jump" // Comment
+bind "n" "nextspawn"
+bind shift "+speed"
+_windowed_mouse "1"
+"#);
+
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::W)), Some("+forward"));
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::Up)), Some("+forward"));
+    assert_eq!(input.binding(Key::Mouse(MouseButton::Right)), Some("+jump"));
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::S)), None);
+
+    assert_eq!(input.key_event(Key::Keyboard(VirtualKeyCode::W), true), None);
+    assert!(input.is_held(Button::Forward));
+    assert_eq!(input.key_event(Key::Keyboard(VirtualKeyCode::N), true), Some("nextspawn".to_owned()));
+    assert_eq!(input.key_event(Key::Keyboard(VirtualKeyCode::W), false), None);
+    assert!(!input.is_held(Button::Forward));
+    assert_eq!(input.key_event(Key::Keyboard(VirtualKeyCode::RShift), true), None);
+    assert!(input.is_held(Button::Speed));
+
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::Escape)), None);
+
+    // A leading unbindall keeps the viewer's keys but the
+    // config can still rebind them
+    let mut input = Input::new();
+    let mut commands = parse_commands("unbindall\nbind p \"+jump\"\nunbindall\n");
+    input.take_unbindall(&mut commands);
+    assert_eq!(commands.len(), 2);
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::W)), None);
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::Escape)), Some("togglemouse"));
+    assert!(input.execute(&commands[0]));
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::P)), Some("+jump"));
+    assert_eq!(input.binding(Key::Keyboard(VirtualKeyCode::N)), Some("nextspawn"));
+}
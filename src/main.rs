@@ -25,10 +25,21 @@ pub mod error;
 pub mod render;
 pub mod bsp;
 pub mod bitset;
+pub mod player;
+pub mod input;
+pub mod tga;
+pub mod mdl;
+pub mod spr;
+pub mod wad;
+pub mod console;
+pub mod vfs;
 
-use std::time::{Instant, Duration};
+use std::time::Instant;
 use std::rc::Rc;
-use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::process;
+use log::*;
 
 const WIDTH: u32 = 640;
 const HEIGHT: u32 = 480;
@@ -41,7 +52,28 @@ use hal::format::AsFormat as _;
 
 fn main() {
     env_logger::init_from_env("QUAKE_LOG");
-    let pak = Rc::new(pak::PackFile::new("id1/PAK0.PAK").unwrap());
+    let game_dir = game_dir();
+    let mut files = vfs::FileSystem::new();
+    files.add_game_dir(BASE_DIR).unwrap();
+    if game_dir != Path::new(BASE_DIR) {
+        files.add_game_dir(&game_dir).unwrap();
+    }
+    let files = Rc::new(files);
+
+    let maps = find_maps(&files);
+    let (mut level_idx, level) = match load_first_map(&files, &maps) {
+        Some(v) => v,
+        None => {
+            if maps.is_empty() {
+                eprintln!("No maps were found in {}", game_dir.display());
+            } else {
+                eprintln!("None of the {} maps found could be loaded", maps.len());
+            }
+            process::exit(1);
+        },
+    };
+    let first_map = &maps[level_idx];
+    let mut level = Rc::new(level);
 
     let wb = winit::WindowBuilder::new()
         .with_dimensions(winit::dpi::LogicalSize::new(
@@ -78,25 +110,47 @@ fn main() {
 
     let adapter = adapters.remove(0);
 
-    let start = bsp::BspFile::parse(
-        &mut Cursor::new(pak.file("maps/start.bsp").unwrap())
-    ).unwrap();
+    let mut spawns = spawn_points(&level);
+    let mut spawn_idx = 0;
 
     let mut renderer = render::Renderer::new(
-        pak.clone(), start,
+        files.clone(), level.clone(),
         adapter, surface,
         size,
     ).unwrap();
+    renderer.hud.map_name = first_map.clone();
+    let mut player = player::Player::new(cgmath::Vector3::new(0.0, 0.0, 0.0));
+    spawn_player(spawns.first(), &mut renderer.camera, &mut player);
+
+    let mut input = input::Input::new();
+    let mut console = console::Console::new();
+    for &(name, help) in COMMANDS {
+        console.register_command(name, help);
+    }
+    console.register_cvar("fov", console::Value::Float(renderer.fov), "Horizontal field of view in degrees");
+    console.register_cvar("sensitivity", console::Value::Float(input.sensitivity), "Mouse sensitivity");
+    console.register_cvar("m_yaw", console::Value::Float(input.m_yaw), "Mouse yaw speed");
+    console.register_cvar("m_pitch", console::Value::Float(input.m_pitch), "Mouse pitch speed, negative inverts");
+    console.register_cvar("r_drawworld", console::Value::Bool(renderer.draw_world), "Draw the level");
+    console.register_cvar("r_wateralpha", console::Value::Float(renderer.water_alpha), "Opacity of liquids");
+
+    // Commands are queued with the number of execs they came
+    // through so a config that execs itself can be stopped
+    let mut commands = VecDeque::new();
+    match read_config(&files, "config.cfg") {
+        Ok(config) => {
+            let mut config = console.execute_config(&config);
+            input.take_unbindall(&mut config);
+            commands.extend(config.into_iter().map(|v| (1, v)));
+        },
+        Err(err) => info!("Not loading config.cfg: {}", err),
+    }
 
     let mut running = true;
-    let mut moving_forward = false;
     let mut lock_mouse = false;
-    let mut level_idx = 0;
     let mut last_frame = Instant::now();
     let mut display_size: (u32, u32) = (WIDTH, HEIGHT);
 
-    let mut frames = 0;
-    let mut last_fps = Instant::now();
     while running {
         let start = Instant::now();
         let diff = last_frame.elapsed();
@@ -105,7 +159,7 @@ fn main() {
             (diff.as_secs() * 1_000_000_000 + diff.subsec_nanos() as u64) as f32 / (1_000_000_000.0 / 60.0);
 
         events_loop.poll_events(|event| {
-            use winit::{Event, WindowEvent, VirtualKeyCode, ElementState, MouseButton};
+            use winit::{Event, WindowEvent, ElementState, MouseButton, VirtualKeyCode};
 
             #[cfg(feature = "gl")]
             let window = renderer.surface.window().window();
@@ -116,29 +170,55 @@ fn main() {
 
             match event {
                 Event::WindowEvent{event: WindowEvent::KeyboardInput{input:key, ..}, ..} => {
-                    if key.virtual_keycode == Some(VirtualKeyCode::Escape) && key.state == ElementState::Released {
-                        lock_mouse = !lock_mouse;
-                        if lock_mouse {
-                            window.hide_cursor(true);
-                        } else {
-                            window.hide_cursor(false);
+                    let code = match key.virtual_keycode {
+                        Some(v) => v,
+                        None => return,
+                    };
+                    let pressed = key.state == ElementState::Pressed;
+                    if pressed && code == VirtualKeyCode::Grave {
+                        console.open = !console.open;
+                        input.release_all();
+                        return;
+                    }
+                    if !console.open {
+                        if let Some(command) = input.key_event(input::Key::Keyboard(code), pressed) {
+                            commands.extend(console.execute(&command).into_iter().map(|v| (0, v)));
                         }
+                        return;
                     }
-                    if key.virtual_keycode == Some(VirtualKeyCode::W) {
-                        moving_forward = key.state == ElementState::Pressed;
-
-                    } else if key.virtual_keycode == Some(VirtualKeyCode::P) && key.state == ElementState::Released {
-                        level_idx = (level_idx + 1) % LEVELS.len();
-                        let level = bsp::BspFile::parse(
-                            &mut Cursor::new(pak.file(&format!("maps/{}.bsp", LEVELS[level_idx])).unwrap())
-                        ).unwrap();
-                        renderer.change_level(level).unwrap();
+                    // Text is typed with the character events, these
+                    // are the keys used to edit it
+                    if !pressed {
+                        return;
+                    }
+                    match code {
+                        VirtualKeyCode::Return => commands.extend(console.submit().into_iter().map(|v| (0, v))),
+                        VirtualKeyCode::Back => { console.input.pop(); },
+                        VirtualKeyCode::Tab => console.complete(),
+                        VirtualKeyCode::Up => console.history_step(true),
+                        VirtualKeyCode::Down => console.history_step(false),
+                        VirtualKeyCode::Escape => console.open = false,
+                        _ => {},
                     }
                 },
-                Event::WindowEvent{event: WindowEvent::MouseInput{state: ElementState::Pressed, button: MouseButton::Left, ..}, ..} => {
+                Event::WindowEvent{event: WindowEvent::ReceivedCharacter(c), ..} => {
+                    // The console key itself isn't typed
+                    if console.open && c.is_ascii() && !c.is_ascii_control() && c != '`' && c != '~' {
+                        console.input.push(c);
+                    }
+                },
+                Event::WindowEvent{event: WindowEvent::MouseInput{state: ElementState::Pressed, button: MouseButton::Left, ..}, ..} if !lock_mouse => {
                     window.hide_cursor(true);
                     lock_mouse = true;
                 },
+                Event::WindowEvent{event: WindowEvent::MouseInput{state, button, ..}, ..} => {
+                    if let Some(command) = input.key_event(input::Key::Mouse(button), state == ElementState::Pressed) {
+                        commands.extend(console.execute(&command).into_iter().map(|v| (0, v)));
+                    }
+                },
+                Event::WindowEvent{event: WindowEvent::Focused(false), ..} => {
+                    input.release_all();
+                },
                 Event::WindowEvent{event: WindowEvent::CloseRequested, ..} => {
                     running = false;
                 },
@@ -155,38 +235,230 @@ fn main() {
 
                     window.set_cursor_position((width / 2.0, height / 2.0).into()).unwrap();
 
-                    renderer.camera.rot_x -= cgmath::Rad(dy as f32 / 2000.0);
-                    renderer.camera.rot_y -= cgmath::Rad(dx as f32 / 2000.0);
+                    let (yaw, pitch) = input.mouse_look(dx as f32, dy as f32);
+                    renderer.camera.rot_x -= pitch;
+                    renderer.camera.rot_y -= yaw;
                 },
                 _ => {},
             }
         });
 
-        if moving_forward {
-            renderer.camera.x += 5.0 * renderer.camera.rot_y.0.sin() * delta;
-            renderer.camera.y += 5.0 * renderer.camera.rot_y.0.cos() * delta;
-            renderer.camera.z -= 5.0 * (-renderer.camera.rot_x.0).sin() * delta;
+        while let Some((depth, command)) = commands.pop_front() {
+            let args: Vec<&str> = command.iter().map(|v| v.as_str()).collect();
+            match args.as_slice() {
+                // Quake's config binds escape to its menu, the viewer
+                // has none so the mouse is toggled instead
+                ["togglemouse"] | ["togglemenu"] => {
+                    #[cfg(feature = "gl")]
+                    let window = renderer.surface.window().window();
+                    #[cfg(not(feature = "gl"))]
+                    let window = &window;
+
+                    lock_mouse = !lock_mouse;
+                    window.hide_cursor(lock_mouse);
+                },
+                ["noclip"] => player.toggle_noclip(),
+                ["nextmap"] => {
+                    level_idx = (level_idx + 1) % maps.len();
+                    match change_level(&files, &maps[level_idx], &mut renderer, &mut player) {
+                        Ok(v) => {
+                            level = v.0;
+                            spawns = v.1;
+                            spawn_idx = 0;
+                        },
+                        Err(err) => console.print(format!("Couldn't load {}: {}", maps[level_idx], err)),
+                    }
+                },
+                ["map", name] => match change_level(&files, name, &mut renderer, &mut player) {
+                    Ok(v) => {
+                        level = v.0;
+                        spawns = v.1;
+                        spawn_idx = 0;
+                        level_idx = maps.iter().position(|v| v == name).unwrap_or(level_idx);
+                    },
+                    Err(err) => console.print(format!("Couldn't load {}: {}", name, err)),
+                },
+                ["maps"] => for name in &maps {
+                    console.print(name.as_str());
+                },
+                ["exec", name] if depth >= MAX_EXEC_DEPTH => {
+                    console.print(format!("Couldn't exec {}: configs are nested too deeply", name));
+                },
+                ["exec", name] => match read_config(&files, name) {
+                    Ok(text) => commands.extend(console.execute_config(&text).into_iter().map(|v| (depth + 1, v))),
+                    Err(err) => console.print(format!("Couldn't exec {}: {}", name, err)),
+                },
+                ["bind", key] => match input::key_from_name(key) {
+                    Some(key) => match input.binding(key) {
+                        Some(binding) => console.print(format!("\"{}\" = \"{}\"", args[1], binding)),
+                        None => console.print(format!("\"{}\" is not bound", args[1])),
+                    },
+                    None => console.print(format!("Unknown key \"{}\"", key)),
+                },
+                ["screenshot"] => match screenshot_path(&game_dir) {
+                    Some(path) => {
+                        let name = path.display().to_string();
+                        match renderer.screenshot(path) {
+                            Ok(()) => console.print(format!("Saving {}...", name)),
+                            Err(err) => console.print(format!("Couldn't save {}: {}", name, err)),
+                        }
+                    },
+                    None => console.print("Too many screenshots"),
+                },
+                ["nextspawn"] => {
+                    if !spawns.is_empty() {
+                        spawn_idx = (spawn_idx + 1) % spawns.len();
+                        spawn_player(spawns.get(spawn_idx), &mut renderer.camera, &mut player);
+                    }
+                },
+                // bind, unbind and unbindall
+                _ if input.execute(&command) => {},
+                _ => console.print(format!("Invalid use of {}", args[0])),
+            }
+        }
+
+        input.sensitivity = console.float("sensitivity");
+        input.m_yaw = console.float("m_yaw");
+        input.m_pitch = console.float("m_pitch");
+        renderer.fov = console.float("fov");
+        renderer.draw_world = console.bool("r_drawworld");
+        renderer.water_alpha = console.float("r_wateralpha");
+
+        let cmd = input.move_command(renderer.camera.forward(), renderer.camera.right());
+        player.update(&level, &cmd, delta / 60.0);
+        renderer.camera.set_position(player.eye_position());
+
+        match renderer.draw(delta, display_size, &console) {
+            Some((path, Ok(()))) => console.print(format!("Wrote {}", path.display())),
+            Some((path, Err(err))) => console.print(format!("Couldn't save {}: {}", path.display(), err)),
+            None => {},
         }
+    }
+}
 
-        renderer.draw(delta, display_size);
+// Loads a level and moves the player to its first spawn point
+fn change_level<B: hal::Backend>(
+    files: &vfs::FileSystem,
+    name: &str,
+    renderer: &mut render::Renderer<B>,
+    player: &mut player::Player,
+) -> error::Result<(Rc<bsp::BspFile>, Vec<bsp::Entity>)> {
+    let level = Rc::new(load_level(files, name)?);
+    let spawns = spawn_points(&level);
+    renderer.change_level(level.clone())?;
+    renderer.hud.map_name = name.to_owned();
+    spawn_player(spawns.first(), &mut renderer.camera, player);
+    Ok((level, spawns))
+}
 
-        frames += 1;
-        if last_fps.elapsed() > Duration::from_secs(1) {
-            println!("FPS: {}", frames);
-            frames = 0;
-            last_fps = Instant::now();
+// Moves the camera and the player to a spawn point. Intermission
+// points are raised views over the level so the player flies from
+// them instead of falling.
+fn spawn_player(spawn: Option<&bsp::Entity>, camera: &mut render::Camera, player: &mut player::Player) {
+    if let Some(spawn) = spawn {
+        camera.spawn_at(spawn);
+        if spawn.classname() == Some("info_intermission") {
+            player.mode = player::MoveMode::Noclip;
         }
     }
+    player.teleport(camera.position() - cgmath::Vector3::new(0.0, 0.0, player::VIEW_HEIGHT));
+}
+
+fn read_config(files: &vfs::FileSystem, name: &str) -> error::Result<String> {
+    let data = files.file(name)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+// The directory given with `-game <dir>` like Quake, otherwise
+// the base directory
+fn game_dir() -> PathBuf {
+    let args = std::env::args().collect::<Vec<_>>();
+    args.iter()
+        .position(|v| v == "-game")
+        .and_then(|idx| args.get(idx + 1))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(BASE_DIR))
 }
 
-const LEVELS: &'static [&'static str] = &[
-    "start",
-    "e1m1",
-    "e1m2",
-    "e1m3",
-    "e1m4",
-    "e1m5",
-    "e1m6",
-    "e1m7",
-    "e1m8",
-];
\ No newline at end of file
+// The first unused screenshot name in the game directory
+fn screenshot_path(game_dir: &Path) -> Option<PathBuf> {
+    (0 .. 100)
+        .map(|i| game_dir.join(format!("rquake{:02}.tga", i)))
+        .find(|path| !path.exists())
+}
+
+// Every level in the game, the b_*.bsp files in maps are the
+// models of items instead of levels
+fn find_maps(files: &vfs::FileSystem) -> Vec<String> {
+    files.glob("maps/*.bsp")
+        .into_iter()
+        .map(|name| name["maps/".len() .. name.len() - ".bsp".len()].to_owned())
+        .filter(|name| !name.starts_with("b_"))
+        .collect()
+}
+
+// Tries the start map then the others in order, skipping any
+// that fail to load
+fn load_first_map(files: &vfs::FileSystem, maps: &[String]) -> Option<(usize, bsp::BspFile)> {
+    let start = maps.iter()
+        .position(|v| v == START_MAP)
+        .unwrap_or(0);
+    (start .. maps.len()).chain(0 .. start)
+        .find_map(|idx| match load_level(files, &maps[idx]) {
+            Ok(level) => Some((idx, level)),
+            Err(err) => {
+                warn!("Couldn't load {}: {}", maps[idx], err);
+                None
+            },
+        })
+}
+
+fn load_level(files: &vfs::FileSystem, name: &str) -> error::Result<bsp::BspFile> {
+    bsp::BspFile::parse_shared(
+        &files.file(&format!("maps/{}.bsp", name))?
+    )
+}
+
+// Player starts first so that a level begins where the player would,
+// followed by the other points of interest in the level.
+fn spawn_points(level: &bsp::BspFile) -> Vec<bsp::Entity> {
+    SPAWN_CLASSES.iter()
+        .flat_map(|class| level.entities.iter()
+            .filter(move |e| e.classname() == Some(*class))
+        )
+        .cloned()
+        .collect()
+}
+
+// The level loaded first if the game has it
+const START_MAP: &str = "start";
+
+// Always searched, `-game` directories are layered on top
+const BASE_DIR: &str = "id1";
+
+// How many configs can exec each other before giving up,
+// stops a config that execs itself from running forever
+const MAX_EXEC_DEPTH: usize = 16;
+
+const SPAWN_CLASSES: &'static [&'static str] = &[
+    "info_player_start",
+    "info_player_deathmatch",
+    "info_player_coop",
+    "info_intermission",
+];
+
+// Commands run by the game, the console handles its own
+// commands and the cvars
+const COMMANDS: &[(&str, &str)] = &[
+    ("togglemouse", "Locks or frees the mouse"),
+    ("noclip", "Toggles flying through walls"),
+    ("nextmap", "Loads the next map"),
+    ("nextspawn", "Moves to the next spawn point"),
+    ("map", "Loads the named map"),
+    ("maps", "Lists the maps"),
+    ("exec", "Runs a config file"),
+    ("bind", "Binds a key to a command or shows its binding"),
+    ("unbind", "Removes a key's binding"),
+    ("unbindall", "Removes every binding"),
+    ("screenshot", "Saves the next frame as a tga"),
+];
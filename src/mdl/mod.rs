@@ -0,0 +1,385 @@
+use std::io::Read;
+use std::ops::Range;
+use cgmath::Vector3;
+
+use crate::error;
+use crate::parse::*;
+
+pub struct MdlFile {
+    pub scale: Vector3<f32>,
+    pub translate: Vector3<f32>,
+    pub bounding_radius: f32,
+    pub eye_position: Vector3<f32>,
+    pub skin_width: u32,
+    pub skin_height: u32,
+    pub skins: Vec<Skin>,
+    pub st_verts: Vec<StVert>,
+    pub triangles: Vec<Triangle>,
+    pub frames: Vec<Frame>,
+    pub sync_type: i32,
+    pub flags: i32,
+    pub size: f32,
+}
+
+impl MdlFile {
+    pub fn parse<R>(r: &mut R) -> error::Result<MdlFile>
+        where R: Read,
+    {
+        let magic = read_string!(r, 4);
+        if &magic != b"IDPO" {
+            bail!("Invalid mdl magic");
+        }
+        let version = r.read_long()?;
+        if version != 6 {
+            bail!("Unsupported mdl version {}", version);
+        }
+
+        let scale = read_vector(r)?;
+        let translate = read_vector(r)?;
+        let bounding_radius = r.read_float()?;
+        let eye_position = read_vector(r)?;
+
+        let num_skins = read_count(r, "skins")?;
+        let skin_width = read_count(r, "skin width")? as u32;
+        let skin_height = read_count(r, "skin height")? as u32;
+        let num_verts = read_count(r, "vertices")?;
+        let num_triangles = read_count(r, "triangles")?;
+        let num_frames = read_count(r, "frames")?;
+        let sync_type = r.read_long()?;
+        let flags = r.read_long()?;
+        let size = r.read_float()?;
+
+        let skin_size = match skin_width.checked_mul(skin_height) {
+            Some(v) => v as usize,
+            None => bail!("Invalid skin size {}x{}", skin_width, skin_height),
+        };
+        // The counts aren't trusted for allocating up front, the
+        // file running out stops a corrupt count instead
+        let mut skins = vec![];
+        for _ in 0 .. num_skins {
+            skins.push(Skin::parse(skin_size, r)?);
+        }
+
+        let mut st_verts = vec![];
+        for _ in 0 .. num_verts {
+            st_verts.push(StVert {
+                on_seam: r.read_long()? != 0,
+                s: r.read_long()?,
+                t: r.read_long()?,
+            });
+        }
+
+        let mut triangles = vec![];
+        for _ in 0 .. num_triangles {
+            let faces_front = r.read_long()? != 0;
+            let mut vertices = [0; 3];
+            for v in &mut vertices {
+                let idx = r.read_long()?;
+                if idx < 0 || idx as usize >= num_verts {
+                    bail!("Triangle vertex {} out of range", idx);
+                }
+                *v = idx as usize;
+            }
+            triangles.push(Triangle {
+                faces_front: faces_front,
+                vertices: vertices,
+            });
+        }
+
+        let mut frames = vec![];
+        for _ in 0 .. num_frames {
+            frames.push(Frame::parse(num_verts, r)?);
+        }
+
+        Ok(MdlFile {
+            scale: scale,
+            translate: translate,
+            bounding_radius: bounding_radius,
+            eye_position: eye_position,
+            skin_width: skin_width,
+            skin_height: skin_height,
+            skins: skins,
+            st_verts: st_verts,
+            triangles: triangles,
+            frames: frames,
+            sync_type: sync_type,
+            flags: flags,
+            size: size,
+        })
+    }
+
+    // Frame vertices are packed into a byte per axis and scaled
+    // back up to the model's size
+    pub fn vertex_position(&self, v: &FrameVertex) -> Vector3<f32> {
+        Vector3::new(
+            v.position[0] as f32 * self.scale.x + self.translate.x,
+            v.position[1] as f32 * self.scale.y + self.translate.y,
+            v.position[2] as f32 * self.scale.z + self.translate.z,
+        )
+    }
+
+    // The run of frames starting at `start` that share its name
+    // without the trailing number, such as stand1 to stand9
+    pub fn frame_sequence(&self, start: usize) -> Range<usize> {
+        let stem = |frame: &Frame| {
+            let name = &frame.at_time(0.0).name;
+            name.trim_end_matches(|c: char| c.is_ascii_digit()).to_owned()
+        };
+        let first = match self.frames.get(start) {
+            Some(frame) => stem(frame),
+            None => return start .. start,
+        };
+        let len = self.frames[start..].iter()
+            .take_while(|v| stem(v) == first)
+            .count();
+        start .. start + len
+    }
+}
+
+// Skins are indices into the palette
+pub enum Skin {
+    Single(Vec<u8>),
+    // Skins that animate, each with the time it ends at
+    Group {
+        times: Vec<f32>,
+        skins: Vec<Vec<u8>>,
+    },
+}
+
+impl Skin {
+    fn parse<R>(size: usize, r: &mut R) -> error::Result<Skin>
+        where R: Read,
+    {
+        if r.read_long()? == 0 {
+            return Ok(Skin::Single(read_bytes(size, r)?));
+        }
+        let count = read_group_count(r, "group skins")?;
+        let mut times = vec![];
+        for _ in 0 .. count {
+            times.push(r.read_float()?);
+        }
+        let mut skins = vec![];
+        for _ in 0 .. count {
+            skins.push(read_bytes(size, r)?);
+        }
+        Ok(Skin::Group {
+            times: times,
+            skins: skins,
+        })
+    }
+
+    // Index of the skin to show at the given time in seconds
+    pub fn index_at_time(&self, time: f32) -> usize {
+        match self {
+            Skin::Single(_) => 0,
+            Skin::Group { times, .. } => interval_index(times, time),
+        }
+    }
+}
+
+pub struct StVert {
+    // Vertices on the seam between the front and back half of the
+    // skin are moved across by half the skin width on back faces
+    pub on_seam: bool,
+    pub s: i32,
+    pub t: i32,
+}
+
+pub struct Triangle {
+    pub faces_front: bool,
+    pub vertices: [usize; 3],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameVertex {
+    pub position: [u8; 3],
+    // Index into Quake's table of precalculated normals
+    pub normal: u8,
+}
+
+impl FrameVertex {
+    fn parse<R>(r: &mut R) -> error::Result<FrameVertex>
+        where R: Read,
+    {
+        Ok(FrameVertex {
+            position: [r.read_uchar()?, r.read_uchar()?, r.read_uchar()?],
+            normal: r.read_uchar()?,
+        })
+    }
+}
+
+pub struct SimpleFrame {
+    pub bound_min: FrameVertex,
+    pub bound_max: FrameVertex,
+    pub name: String,
+    pub vertices: Vec<FrameVertex>,
+}
+
+impl SimpleFrame {
+    fn parse<R>(num_verts: usize, r: &mut R) -> error::Result<SimpleFrame>
+        where R: Read,
+    {
+        let bound_min = FrameVertex::parse(r)?;
+        let bound_max = FrameVertex::parse(r)?;
+        let name = read_string!(r, 16);
+        let mut vertices = vec![];
+        for _ in 0 .. num_verts {
+            vertices.push(FrameVertex::parse(r)?);
+        }
+        Ok(SimpleFrame {
+            bound_min: bound_min,
+            bound_max: bound_max,
+            name: from_cstring(&name)?,
+            vertices: vertices,
+        })
+    }
+}
+
+pub enum Frame {
+    Single(SimpleFrame),
+    // Frames that animate on their own, each with the time it
+    // ends at
+    Group {
+        bound_min: FrameVertex,
+        bound_max: FrameVertex,
+        times: Vec<f32>,
+        frames: Vec<SimpleFrame>,
+    },
+}
+
+impl Frame {
+    fn parse<R>(num_verts: usize, r: &mut R) -> error::Result<Frame>
+        where R: Read,
+    {
+        if r.read_long()? == 0 {
+            return Ok(Frame::Single(SimpleFrame::parse(num_verts, r)?));
+        }
+        let count = read_group_count(r, "group frames")?;
+        let bound_min = FrameVertex::parse(r)?;
+        let bound_max = FrameVertex::parse(r)?;
+        let mut times = vec![];
+        for _ in 0 .. count {
+            times.push(r.read_float()?);
+        }
+        let mut frames = vec![];
+        for _ in 0 .. count {
+            frames.push(SimpleFrame::parse(num_verts, r)?);
+        }
+        Ok(Frame::Group {
+            bound_min: bound_min,
+            bound_max: bound_max,
+            times: times,
+            frames: frames,
+        })
+    }
+
+    // Index of the pose to show at the given time in seconds,
+    // single frames only have the one.
+    pub fn index_at_time(&self, time: f32) -> usize {
+        match self {
+            Frame::Single(_) => 0,
+            Frame::Group { times, .. } => interval_index(times, time),
+        }
+    }
+
+    pub fn at_time(&self, time: f32) -> &SimpleFrame {
+        match self {
+            Frame::Single(frame) => frame,
+            Frame::Group { frames, .. } => &frames[self.index_at_time(time)],
+        }
+    }
+
+    pub fn poses(&self) -> &[SimpleFrame] {
+        match self {
+            Frame::Single(frame) => std::slice::from_ref(frame),
+            Frame::Group { frames, .. } => frames,
+        }
+    }
+}
+
+// Groups loop through their entries, each shown until the
+// time listed for it
+fn interval_index(times: &[f32], time: f32) -> usize {
+    let total = times.last().cloned().unwrap_or(0.0);
+    let time = if total > 0.0 { time % total } else { 0.0 };
+    times.iter()
+        .position(|&v| time < v)
+        .unwrap_or(0)
+}
+
+fn read_vector<R>(r: &mut R) -> error::Result<Vector3<f32>>
+    where R: Read,
+{
+    Ok(Vector3::new(
+        r.read_float()?,
+        r.read_float()?,
+        r.read_float()?,
+    ))
+}
+
+#[test]
+fn test_parse_mdl() {
+    use std::io::Cursor;
+
+    let mut data = b"IDPO".to_vec();
+    data.write_long(6);
+    data.write_floats(&[2.0, 2.0, 2.0, -10.0, -10.0, 0.0, 20.0, 0.0, 0.0, 24.0]);
+    // Skins, skin size, vertices, triangles, frames, sync type, flags
+    data.write_longs(&[2, 2, 2, 3, 1, 2, 0, 0]);
+    data.write_float(1.0);
+
+    // A single skin then a group of two
+    data.write_long(0);
+    data.extend_from_slice(&[1, 2, 3, 4]);
+    data.write_longs(&[1, 2]);
+    data.write_floats(&[0.1, 0.2]);
+    data.extend_from_slice(&[5, 6, 7, 8, 9, 10, 11, 12]);
+
+    data.write_longs(&[0, 0, 0, 1, 1, 0, 0, 0, 1]);
+    data.write_longs(&[1, 0, 1, 2]);
+
+    let frame = |data: &mut Vec<u8>, name: &[u8; 16]| {
+        data.extend_from_slice(&[0, 0, 0, 0, 5, 5, 5, 0]);
+        data.extend_from_slice(name);
+        data.extend_from_slice(&[0, 0, 0, 1, 5, 0, 0, 2, 0, 5, 5, 3]);
+    };
+    data.write_long(0);
+    frame(&mut data, b"stand1\0\0\0\0\0\0\0\0\0\0");
+    data.write_longs(&[1, 2]);
+    data.extend_from_slice(&[0, 0, 0, 0, 5, 5, 5, 0]);
+    data.write_floats(&[0.5, 1.0]);
+    frame(&mut data, b"run1\0\0\0\0\0\0\0\0\0\0\0\0");
+    frame(&mut data, b"run2\0\0\0\0\0\0\0\0\0\0\0\0");
+
+    let mdl = MdlFile::parse(&mut Cursor::new(data.clone())).unwrap();
+    assert_eq!((mdl.skin_width, mdl.skin_height), (2, 2));
+    assert_eq!(mdl.skins.len(), 2);
+    match &mdl.skins[1] {
+        Skin::Group { times, skins } => {
+            assert_eq!(times, &vec![0.1, 0.2]);
+            assert_eq!(skins[1], vec![9, 10, 11, 12]);
+        },
+        _ => panic!("Expected a skin group"),
+    }
+    assert!(mdl.st_verts[1].on_seam);
+    assert_eq!(mdl.triangles[0].vertices, [0, 1, 2]);
+    assert!(mdl.triangles[0].faces_front);
+
+    match &mdl.frames[0] {
+        Frame::Single(frame) => {
+            assert_eq!(frame.name, "stand1");
+            assert_eq!(mdl.vertex_position(&frame.vertices[1]), Vector3::new(0.0, -10.0, 0.0));
+        },
+        _ => panic!("Expected a single frame"),
+    }
+    assert_eq!(mdl.frames[1].at_time(0.25).name, "run1");
+    assert_eq!(mdl.frames[1].at_time(1.75).name, "run2");
+    assert_eq!(mdl.frame_sequence(0), 0 .. 1);
+    assert_eq!(mdl.frame_sequence(1), 1 .. 2);
+
+    // An empty skin group, then skins too large for the file
+    assert!(MdlFile::parse(&mut Cursor::new(with_longs(&data, 96, &[0]))).is_err());
+    for size in &[[0x10000, 0x8000], [0x10000, 0x10000]] {
+        assert!(MdlFile::parse(&mut Cursor::new(with_longs(&data, 52, size))).is_err());
+    }
+}
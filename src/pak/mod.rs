@@ -1,21 +1,106 @@
 
 use std::path::Path;
-use std::io::{self, Read, Seek, SeekFrom};
-use std::fs::File;
-use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::collections::BTreeMap;
 use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter};
+use std::ops::{Bound, Deref, Range};
+use std::sync::Arc;
 
 use crate::parse::*;
 use crate::error;
 
-pub struct PackFile {
-    file: RefCell<File>,
-    entries: HashMap<String, Entry>,
+mod writer;
+pub use self::writer::{PackWriter, check_name};
+
+// The magic followed by the directory's offset and size
+const HEADER_SIZE: usize = 12;
+// Names are nul terminated within this size
+const NAME_SIZE: usize = 0x38;
+const ENTRY_SIZE: usize = 0x40;
+
+// A pak file whose files are read from `S`, by default the file
+// on disk which is read from as needed
+pub struct PackFile<S = RefCell<File>> {
+    storage: S,
+    len: u64,
+    directory: Directory,
+}
+
+// A pak file held in memory. Files are borrowed from it instead
+// of being copied and it can be shared between threads.
+pub type SharedPackFile = PackFile<Arc<[u8]>>;
+
+struct Directory {
+    range: Range<u64>,
+    // Sorted by name so files in a directory are next to
+    // each other
+    entries: BTreeMap<String, Entry>,
+    // Names found more than once in the directory, the last
+    // entry is the one used
+    duplicates: Vec<String>,
+}
+
+// Part of some shared data such as a file borrowed from a
+// SharedPackFile, cloning it doesn't copy the data
+#[derive(Clone)]
+pub struct SharedData {
+    data: Arc<[u8]>,
+    range: Range<usize>,
 }
 
-struct Entry {
-    offset: u64,
-    size: u64,
+impl SharedData {
+    // Part of this data, the range is relative to its start
+    pub fn slice(&self, range: Range<usize>) -> SharedData {
+        assert!(range.start <= range.end && range.end <= self.len());
+        SharedData {
+            data: self.data.clone(),
+            range: self.range.start + range.start .. self.range.start + range.end,
+        }
+    }
+}
+
+impl From<Vec<u8>> for SharedData {
+    fn from(v: Vec<u8>) -> SharedData {
+        let data: Arc<[u8]> = v.into();
+        SharedData {
+            range: 0 .. data.len(),
+            data,
+        }
+    }
+}
+
+impl Default for SharedData {
+    fn default() -> SharedData {
+        Vec::new().into()
+    }
+}
+
+impl Deref for SharedData {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for SharedData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Debug for SharedData {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+// Where a file is stored in the pak
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+    pub offset: u64,
+    pub size: u64,
 }
 
 impl PackFile {
@@ -24,46 +109,289 @@ impl PackFile {
         where P: AsRef<Path>
     {
         let mut f = File::open(name)?;
+        let directory = read_directory(&mut f)?;
+        let len = f.seek(SeekFrom::End(0))?;
+        Ok(PackFile {
+            storage: RefCell::new(f),
+            len,
+            directory,
+        })
+    }
+
+    pub fn file(&self, name: &str) -> io::Result<Vec<u8>> {
+        let e = self.entry(name)?;
+        let mut file = self.storage.borrow_mut();
+        file.seek(SeekFrom::Start(e.offset))?;
+        let mut data = vec![0; e.size as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl SharedPackFile {
+    // Reads the whole pak into memory
+    pub fn load<P>(name: P) -> error::Result<SharedPackFile>
+        where P: AsRef<Path>
+    {
+        SharedPackFile::from_data(fs::read(name)?.into())
+    }
+
+    pub fn from_data(data: Arc<[u8]>) -> error::Result<SharedPackFile> {
+        let directory = read_directory(&mut Cursor::new(&data[..]))?;
+        Ok(PackFile {
+            len: data.len() as u64,
+            storage: data,
+            directory,
+        })
+    }
+
+    pub fn file(&self, name: &str) -> io::Result<SharedData> {
+        let e = self.entry(name)?;
+        Ok(SharedData {
+            data: self.storage.clone(),
+            range: e.offset as usize .. (e.offset + e.size) as usize,
+        })
+    }
 
-        let magic = read_string!(f, 4);
+    // The whole pak file
+    pub fn data(&self) -> &Arc<[u8]> {
+        &self.storage
+    }
+}
 
-        if &magic != b"PACK" {
-            bail!("Invalid pak magic");
+impl <S> PackFile<S> {
+    // The entry for a file that is within the pak
+    fn entry(&self, name: &str) -> io::Result<Entry> {
+        match self.directory.entries.get(name) {
+            Some(e) if self.in_range(e) => Ok(*e),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "File is outside of the pak")),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No such file in the pak")),
         }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.directory.entries.contains_key(name)
+    }
+
+    pub fn size(&self, name: &str) -> Option<u64> {
+        self.directory.entries.get(name).map(|e| e.size)
+    }
 
-        let offset = f.read_long()?;
-        let size = f.read_long()? / 0x40;
-        f.seek(SeekFrom::Start(offset as u64))?;
+    // Where every file is stored in name order
+    pub fn entries(&self) -> impl Iterator<Item=(&str, Entry)> {
+        self.directory.entries.iter()
+            .map(|(name, e)| (name.as_str(), *e))
+    }
 
-        let mut entries = HashMap::default();
+    // Whether the entry's data is all within the pak
+    pub fn in_range(&self, e: &Entry) -> bool {
+        e.offset.checked_add(e.size).map_or(false, |end| end <= self.len)
+    }
 
-        for _ in 0 .. size {
-            let name = read_string!(f, 0x38);
-            let entry_offset = f.read_long()?;
-            let entry_size = f.read_long()?;
+    // Problems with the layout of the pak, files outside of it
+    // or overlapping the header, directory or each other
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = self.directory.duplicates.iter()
+            .map(|name| format!("{} is in the directory more than once", name))
+            .collect::<Vec<_>>();
 
-            let name = from_cstring(&name)?;
-            entries.insert(name, Entry {
-                offset: entry_offset as u64,
-                size: entry_size as u64,
-            });
+        let mut ranges = vec![
+            ("the header", 0 .. HEADER_SIZE as u64),
+            ("the directory", self.directory.range.clone()),
+        ];
+        for (name, e) in &self.directory.entries {
+            if !self.in_range(e) {
+                problems.push(format!(
+                    "{} at {} with size {} is outside of the pak ({} bytes)",
+                    name, e.offset as i64, e.size as i64, self.len,
+                ));
+            } else if e.size > 0 {
+                ranges.push((name, e.offset .. e.offset + e.size));
+            }
         }
 
-        Ok(PackFile {
-            file: RefCell::new(f),
-            entries,
-        })
+        // Each range is compared with the one reaching the
+        // furthest of the ranges that start before it
+        ranges.sort_by_key(|(_, r)| r.start);
+        let mut furthest: Option<(&str, Range<u64>)> = None;
+        for (name, range) in ranges {
+            match furthest {
+                Some((other, ref other_range)) if range.start < other_range.end => {
+                    problems.push(format!("{} overlaps {}", name, other));
+                    if range.end <= other_range.end {
+                        continue;
+                    }
+                },
+                _ => {},
+            }
+            furthest = Some((name, range));
+        }
+        problems
     }
 
-    pub fn file(&self, name: &str) -> io::Result<Vec<u8>> {
-        let mut file = self.file.borrow_mut();
-        if let Some(e) = self.entries.get(name) {
-            file.seek(SeekFrom::Start(e.offset))?;
-            let mut data = vec![0; e.size as usize];
-            file.read_exact(&mut data)?;
-            Ok(data)
-        } else {
-            Err(io::Error::new(io::ErrorKind::NotFound, "No such file in the pak"))
+    // Files whose names start with the prefix, such as
+    // every file in a directory
+    pub fn files_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item=(&'a str, u64)> + 'a {
+        self.directory.entries.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(name, _)| name.starts_with(prefix))
+            .map(|(name, e)| (name.as_str(), e.size))
+    }
+
+    // Names of the files matching a pattern like `maps/*.bsp`
+    pub fn glob<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        // Only the files starting with the part before the
+        // first wildcard can match
+        let prefix = &pattern[.. pattern.find(|c| c == '*' || c == '?').unwrap_or(pattern.len())];
+        self.files_with_prefix(prefix)
+            .map(|(name, _)| name)
+            .filter(move |name| glob_match(pattern, name))
+    }
+}
+
+fn read_directory<R: Read + Seek>(r: &mut R) -> error::Result<Directory> {
+    let magic = read_string!(r, 4);
+
+    if &magic != b"PACK" {
+        bail!("Invalid pak magic");
+    }
+
+    let offset = r.read_long()?;
+    let size = r.read_long()? / ENTRY_SIZE as i32;
+    if offset < 0 || size < 0 {
+        bail!("Invalid pak directory");
+    }
+    r.seek(SeekFrom::Start(offset as u64))?;
+
+    let mut entries = BTreeMap::default();
+    let mut duplicates = vec![];
+
+    for _ in 0 .. size {
+        let name = read_string!(r, NAME_SIZE);
+        let entry_offset = r.read_long()?;
+        let entry_size = r.read_long()?;
+
+        let name = from_cstring(&name)?;
+        let entry = Entry {
+            offset: entry_offset as u64,
+            size: entry_size as u64,
+        };
+        if entries.insert(name.clone(), entry).is_some() {
+            duplicates.push(name);
         }
     }
-}
\ No newline at end of file
+
+    Ok(Directory {
+        range: offset as u64 .. offset as u64 + (size as usize * ENTRY_SIZE) as u64,
+        entries,
+        duplicates,
+    })
+}
+
+// Matches a name against a pattern where `*` matches any run of
+// characters and `?` matches any single character, neither
+// match a `/`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    // Where to retry from if the last `*` needs to match
+    // more characters
+    let mut retry = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                retry = Some((p, n));
+                p += 1;
+                continue;
+            },
+            Some(b'?') if name[n] != b'/' => {
+                p += 1;
+                n += 1;
+                continue;
+            },
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            },
+            _ => {},
+        }
+        match retry {
+            Some((rp, rn)) if name[rn] != b'/' => {
+                retry = Some((rp, rn + 1));
+                p = rp + 1;
+                n = rn + 1;
+            },
+            _ => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("maps/*.bsp", "maps/e1m1.bsp"));
+    assert!(glob_match("maps/*.bsp", "maps/.bsp"));
+    assert!(!glob_match("maps/*.bsp", "maps/e1m1.bsp.bak"));
+    assert!(!glob_match("maps/*.bsp", "maps/sub/e1m1.bsp"));
+    assert!(glob_match("maps/e?m*.bsp", "maps/e2m10.bsp"));
+    assert!(!glob_match("maps/e?m*.bsp", "maps/dm1.bsp"));
+    assert!(glob_match("*", "pak0"));
+    assert!(glob_match("progs/*.m*l", "progs/player.mdl"));
+    assert!(!glob_match("progs/*", "sound/misc/r_tele1.wav"));
+}
+
+#[test]
+fn test_verify() {
+    let mut writer = PackWriter::new();
+    writer.add_file("a", vec![1; 4]).unwrap();
+    writer.add_file("b", vec![2; 4]).unwrap();
+    writer.add_file("c", vec![3; 4]).unwrap();
+    let mut data = vec![];
+    writer.write(&mut data).unwrap();
+
+    assert!(SharedPackFile::from_data(data.clone().into()).unwrap().verify().is_empty());
+
+    // Move b over the end of a and make c run past the end
+    let dir = HEADER_SIZE + 12;
+    let offset = dir + ENTRY_SIZE + NAME_SIZE;
+    data[offset .. offset + 4].copy_from_slice(&14i32.to_le_bytes());
+    let size = dir + ENTRY_SIZE * 2 + NAME_SIZE + 4;
+    data[size .. size + 4].copy_from_slice(&1000i32.to_le_bytes());
+    let pak = SharedPackFile::from_data(data.into()).unwrap();
+
+    assert_eq!(pak.entries().nth(1), Some(("b", Entry { offset: 14, size: 4 })));
+    assert_eq!(pak.verify(), vec![
+        "c at 20 with size 1000 is outside of the pak (216 bytes)".to_owned(),
+        "b overlaps a".to_owned(),
+    ]);
+    assert!(pak.file("c").is_err());
+}
+
+#[test]
+fn test_shared_pack_file() {
+    let mut writer = PackWriter::new();
+    writer.add_file("maps/e1m1.bsp", b"level".to_vec()).unwrap();
+    writer.add_file("progs/player.mdl", vec![1, 2, 3]).unwrap();
+    let mut data = vec![];
+    writer.write(&mut data).unwrap();
+
+    let pak = Arc::new(SharedPackFile::from_data(data.into()).unwrap());
+    let file = pak.file("maps/e1m1.bsp").unwrap();
+    assert_eq!(&file[..], b"level");
+    // Borrowed from the pak's data rather than copied
+    assert_eq!(file.as_ptr(), pak.data()[HEADER_SIZE ..].as_ptr());
+    assert!(pak.file("maps/e1m2.bsp").is_err());
+    assert_eq!(&file.slice(1 .. 3)[..], b"ev");
+    assert_eq!(file.slice(1 .. 3).as_ptr(), file[1 ..].as_ptr());
+
+    let threads = (0 .. 2)
+        .map(|_| {
+            let pak = pak.clone();
+            std::thread::spawn(move || pak.file("progs/player.mdl").unwrap().to_vec())
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::Path;
+
+use byteorder::{WriteBytesExt, LittleEndian};
+
+use crate::error;
+use super::{HEADER_SIZE, NAME_SIZE, ENTRY_SIZE};
+
+// Builds a pak file from named files. The files are written
+// after the header followed by the directory like id's tools.
+#[derive(Default)]
+pub struct PackWriter {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl PackWriter {
+    pub fn new() -> PackWriter {
+        PackWriter {
+            files: BTreeMap::new(),
+        }
+    }
+
+    // Adds a file with a name like `maps/e1m1.bsp`, the name has
+    // to fit in the directory with its terminating nul
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> error::Result<()> {
+        check_name(name)?;
+        if self.files.contains_key(name) {
+            bail!("{} is already in the pak", name);
+        }
+        // A name can't be both a file and a directory
+        let parent = name.match_indices('/')
+            .map(|(idx, _)| &name[.. idx])
+            .find(|v| self.files.contains_key(*v));
+        if let Some(parent) = parent {
+            bail!("{} is a file in the pak, it can't contain {}", parent, name);
+        }
+        let dir = format!("{}/", name);
+        let child = self.files.range::<str, _>((Bound::Included(dir.as_str()), Bound::Unbounded))
+            .next()
+            .filter(|(v, _)| v.starts_with(&dir));
+        if let Some((child, _)) = child {
+            bail!("{} is a directory in the pak containing {}", name, child);
+        }
+        self.files.insert(name.to_owned(), data);
+        Ok(())
+    }
+
+    // Adds every file under the directory named by its path
+    // relative to the directory
+    pub fn add_dir<P: AsRef<Path>>(&mut self, dir: P) -> error::Result<()> {
+        self.add_dir_files(dir.as_ref(), "")
+    }
+
+    fn add_dir_files(&mut self, dir: &Path, prefix: &str) -> error::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|v| v.file_name());
+        for entry in entries {
+            let name = match entry.file_name().into_string() {
+                Ok(v) => format!("{}{}", prefix, v),
+                Err(v) => bail!("{:?} isn't a valid file name", v),
+            };
+            if entry.file_type()?.is_dir() {
+                self.add_dir_files(&entry.path(), &format!("{}/", name))?;
+            } else {
+                let data = fs::read(entry.path())?;
+                self.add_file(&name, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> error::Result<()> {
+        let data_size = self.files.values().map(|v| v.len() as u64).sum::<u64>();
+        let dir_offset = HEADER_SIZE as u64 + data_size;
+        let dir_size = (self.files.len() * ENTRY_SIZE) as u64;
+        // Offsets and sizes are stored as signed 32 bit values
+        if dir_offset + dir_size > i32::max_value() as u64 {
+            bail!("Pak file would be {} bytes, too large for the format", dir_offset + dir_size);
+        }
+
+        w.write_all(b"PACK")?;
+        w.write_i32::<LittleEndian>(dir_offset as i32)?;
+        w.write_i32::<LittleEndian>(dir_size as i32)?;
+        for data in self.files.values() {
+            w.write_all(data)?;
+        }
+
+        let mut offset = HEADER_SIZE as i32;
+        for (name, data) in &self.files {
+            let mut entry_name = [0u8; NAME_SIZE];
+            entry_name[.. name.len()].copy_from_slice(name.as_bytes());
+            w.write_all(&entry_name)?;
+            w.write_i32::<LittleEndian>(offset)?;
+            w.write_i32::<LittleEndian>(data.len() as i32)?;
+            offset += data.len() as i32;
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
+        let mut f = io::BufWriter::new(File::create(path)?);
+        self.write(&mut f)?;
+        f.flush()?;
+        Ok(())
+    }
+}
+
+// Names are relative paths separated by `/` with nothing
+// that would leave the directory they're extracted to
+pub fn check_name(name: &str) -> error::Result<()> {
+    if name.len() >= NAME_SIZE {
+        bail!("{} is longer than {} characters", name, NAME_SIZE - 1);
+    }
+    if !name.bytes().all(|c| c.is_ascii_graphic() || c == b' ') || name.contains('\\') {
+        bail!("{:?} contains invalid characters", name);
+    }
+    if name.split('/').any(|v| v.is_empty() || v == "." || v == "..") {
+        bail!("{:?} isn't a valid relative path", name);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_pack_writer() {
+    use super::PackFile;
+
+    let mut writer = PackWriter::new();
+    writer.add_file("maps/e1m1.bsp", b"level".to_vec()).unwrap();
+    writer.add_file("gfx.wad", vec![]).unwrap();
+    writer.add_file("progs/player.mdl", vec![1, 2, 3]).unwrap();
+    assert!(writer.add_file("maps/e1m1.bsp", vec![]).is_err());
+    assert!(writer.add_file("maps", vec![]).is_err());
+    assert!(writer.add_file("gfx.wad/a", vec![]).is_err());
+    assert!(writer.add_file("../e1m1.bsp", vec![]).is_err());
+    assert!(writer.add_file("/maps/e1m2.bsp", vec![]).is_err());
+    assert!(writer.add_file("maps\\e1m2.bsp", vec![]).is_err());
+    assert!(writer.add_file(&"a".repeat(NAME_SIZE), vec![]).is_err());
+    writer.add_file(&"a".repeat(NAME_SIZE - 1), vec![4]).unwrap();
+    assert_eq!(writer.len(), 4);
+
+    let path = std::env::temp_dir().join(format!("rquake-pak-{}.pak", std::process::id()));
+    writer.save(&path).unwrap();
+    let pak = PackFile::new(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(pak.entries().map(|(name, e)| (name, e.size)).collect::<Vec<_>>(), vec![
+        ("a".repeat(NAME_SIZE - 1).as_str(), 1),
+        ("gfx.wad", 0),
+        ("maps/e1m1.bsp", 5),
+        ("progs/player.mdl", 3),
+    ]);
+    assert_eq!(pak.file("maps/e1m1.bsp").unwrap(), b"level");
+    assert_eq!(pak.file("progs/player.mdl").unwrap(), vec![1, 2, 3]);
+    assert_eq!(pak.file(&"a".repeat(NAME_SIZE - 1)).unwrap(), vec![4]);
+}
@@ -3,6 +3,8 @@ use std::io::{self, Read};
 use std::str;
 use byteorder::{ReadBytesExt, LittleEndian};
 
+use crate::error;
+
 macro_rules! read_string {
     ($r:ident, $len:expr) => ({
         let mut data = [0u8; $len];
@@ -51,4 +53,87 @@ pub fn from_cstring(data: &[u8]) -> Result<String, str::Utf8Error>  {
         .unwrap_or(data.len());
     let data = str::from_utf8(&data[..end])?;
     Ok(data.to_owned())
-}
\ No newline at end of file
+}
+
+pub fn read_count<R>(r: &mut R, name: &str) -> error::Result<usize>
+    where R: Read,
+{
+    let count = r.read_long()?;
+    if count < 0 {
+        bail!("Invalid number of {}: {}", name, count);
+    }
+    Ok(count as usize)
+}
+
+// Groups have at least one entry to show
+pub fn read_group_count<R>(r: &mut R, name: &str) -> error::Result<usize>
+    where R: Read,
+{
+    let count = read_count(r, name)?;
+    if count == 0 {
+        bail!("Invalid number of {}: 0", name);
+    }
+    Ok(count)
+}
+
+// Reads through `take` so a corrupt size runs out of data
+// instead of allocating it all up front
+pub fn read_bytes<R>(size: usize, r: &mut R) -> error::Result<Vec<u8>>
+    where R: Read,
+{
+    let mut data = vec![];
+    r.by_ref().take(size as u64).read_to_end(&mut data)?;
+    if data.len() != size {
+        bail!("Unexpected end of file reading {} bytes", size);
+    }
+    Ok(data)
+}
+
+// Little endian writes for building files in tests
+#[cfg(test)]
+pub trait CWrite {
+    fn write_short(&mut self, v: i16);
+    fn write_long(&mut self, v: i32);
+    fn write_float(&mut self, v: f32);
+
+    fn write_shorts(&mut self, v: &[i16]) {
+        for v in v {
+            self.write_short(*v);
+        }
+    }
+    fn write_longs(&mut self, v: &[i32]) {
+        for v in v {
+            self.write_long(*v);
+        }
+    }
+    fn write_floats(&mut self, v: &[f32]) {
+        for v in v {
+            self.write_float(*v);
+        }
+    }
+}
+
+#[cfg(test)]
+impl CWrite for Vec<u8> {
+    fn write_short(&mut self, v: i16) {
+        self.extend_from_slice(&v.to_le_bytes());
+    }
+    fn write_long(&mut self, v: i32) {
+        self.extend_from_slice(&v.to_le_bytes());
+    }
+    fn write_float(&mut self, v: f32) {
+        self.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+// A copy of the data with the longs at `offset` replaced,
+// used to corrupt a field of a test file
+#[cfg(test)]
+pub fn with_longs(data: &[u8], offset: usize, v: &[i32]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    for (idx, v) in v.iter().enumerate() {
+        let offset = offset + idx * 4;
+        data[offset .. offset + 4].copy_from_slice(&v.to_le_bytes());
+    }
+    data
+}
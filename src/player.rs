@@ -0,0 +1,457 @@
+use cgmath::Vector3;
+use cgmath::prelude::*;
+
+use crate::bsp::{self, BspFile, Contents};
+
+// Height of the player's eyes above their origin
+pub const VIEW_HEIGHT: f32 = 22.0;
+
+const STOP_SPEED: f32 = 100.0;
+const MAX_SPEED: f32 = 320.0;
+const NOCLIP_SPEED: f32 = 300.0;
+const ACCELERATE: f32 = 10.0;
+const AIR_SPEED: f32 = 30.0;
+const WATER_ACCELERATE: f32 = 10.0;
+const FRICTION: f32 = 4.0;
+const WATER_FRICTION: f32 = 4.0;
+const GRAVITY: f32 = 800.0;
+const JUMP_VELOCITY: f32 = 270.0;
+const STEP_SIZE: f32 = 18.0;
+const MAX_CLIP_PLANES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveMode {
+    Walk,
+    Noclip,
+}
+
+// The movement requested by the player for a frame. Moves
+// are in units per second along the view direction.
+#[derive(Debug, Clone, Copy)]
+pub struct MoveCommand {
+    pub forward: Vector3<f32>,
+    pub right: Vector3<f32>,
+    pub forward_move: f32,
+    pub side_move: f32,
+    pub up_move: f32,
+    pub jump: bool,
+}
+
+pub struct Player {
+    pub origin: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    pub mode: MoveMode,
+    pub on_ground: bool,
+    // 0 = not in liquid, 1 = feet, 2 = waist, 3 = eyes
+    pub water_level: u8,
+    pub water_type: Contents,
+    jump_held: bool,
+}
+
+impl Player {
+    pub fn new(origin: Vector3<f32>) -> Player {
+        Player {
+            origin,
+            velocity: Vector3::zero(),
+            mode: MoveMode::Walk,
+            on_ground: false,
+            water_level: 0,
+            water_type: Contents::Empty,
+            jump_held: false,
+        }
+    }
+
+    pub fn eye_position(&self) -> Vector3<f32> {
+        self.origin + Vector3::new(0.0, 0.0, VIEW_HEIGHT)
+    }
+
+    pub fn teleport(&mut self, origin: Vector3<f32>) {
+        self.origin = origin;
+        self.velocity = Vector3::zero();
+        self.on_ground = false;
+    }
+
+    pub fn toggle_noclip(&mut self) {
+        self.mode = match self.mode {
+            MoveMode::Walk => MoveMode::Noclip,
+            MoveMode::Noclip => MoveMode::Walk,
+        };
+        self.velocity = Vector3::zero();
+    }
+
+    pub fn update(&mut self, level: &BspFile, cmd: &MoveCommand, dt: f32) {
+        if dt <= 0.0 {
+            return;
+        }
+        if self.mode == MoveMode::Noclip {
+            let wish = cmd.forward * cmd.forward_move
+                + cmd.right * cmd.side_move
+                + Vector3::new(0.0, 0.0, cmd.up_move);
+            self.velocity = if wish.magnitude2() > 0.0 {
+                wish.normalize() * NOCLIP_SPEED.min(wish.magnitude())
+            } else {
+                Vector3::zero()
+            };
+            self.origin += self.velocity * dt;
+            return;
+        }
+
+        self.categorize_position(level);
+
+        if cmd.jump {
+            self.jump();
+        } else {
+            self.jump_held = false;
+        }
+
+        self.friction(dt);
+
+        if self.water_level >= 2 {
+            self.water_move(level, cmd, dt);
+        } else {
+            self.air_move(level, cmd, dt);
+        }
+
+        self.categorize_position(level);
+    }
+
+    fn trace(&self, level: &BspFile, start: Vector3<f32>, end: Vector3<f32>) -> bsp::Trace {
+        level.trace(bsp::HULL_PLAYER, 0, start, end)
+    }
+
+    fn categorize_position(&mut self, level: &BspFile) {
+        let point = self.origin - Vector3::new(0.0, 0.0, 1.0);
+        if self.velocity.z > 180.0 {
+            self.on_ground = false;
+        } else {
+            let trace = self.trace(level, self.origin, point);
+            self.on_ground = trace.fraction < 1.0 && trace.plane_normal.z >= 0.7;
+            if self.on_ground && !trace.start_solid && !trace.all_solid {
+                self.origin = trace.end;
+            }
+        }
+
+        let (mins, maxs) = bsp::hull_bounds(bsp::HULL_PLAYER);
+        self.water_level = 0;
+        self.water_type = Contents::Empty;
+
+        let feet = self.origin + Vector3::new(0.0, 0.0, mins.z + 1.0);
+        let contents = level.hull_point_contents(bsp::HULL_POINT, 0, feet);
+        if contents.is_liquid() {
+            self.water_type = contents;
+            self.water_level = 1;
+            let waist = self.origin + Vector3::new(0.0, 0.0, (mins.z + maxs.z) * 0.5);
+            if level.hull_point_contents(bsp::HULL_POINT, 0, waist).is_liquid() {
+                self.water_level = 2;
+                if level.hull_point_contents(bsp::HULL_POINT, 0, self.eye_position()).is_liquid() {
+                    self.water_level = 3;
+                }
+            }
+        }
+    }
+
+    fn jump(&mut self) {
+        if self.water_level >= 2 {
+            // Swimming upwards
+            self.velocity.z = match self.water_type {
+                Contents::Water => 100.0,
+                Contents::Slime => 80.0,
+                _ => 50.0,
+            };
+            return;
+        }
+        if !self.on_ground || self.jump_held {
+            return;
+        }
+        self.on_ground = false;
+        self.velocity.z += JUMP_VELOCITY;
+        self.jump_held = true;
+    }
+
+    fn friction(&mut self, dt: f32) {
+        let speed = self.velocity.magnitude();
+        if speed < 1.0 {
+            self.velocity.x = 0.0;
+            self.velocity.y = 0.0;
+            return;
+        }
+
+        let mut drop = 0.0;
+        if self.on_ground {
+            drop += speed.max(STOP_SPEED) * FRICTION * dt;
+        }
+        if self.water_level >= 2 {
+            drop += speed * WATER_FRICTION * self.water_level as f32 * dt;
+        }
+
+        let new_speed = (speed - drop).max(0.0);
+        self.velocity *= new_speed / speed;
+    }
+
+    fn accelerate(&mut self, wish_dir: Vector3<f32>, wish_speed: f32, accel: f32, dt: f32) {
+        let current = self.velocity.dot(wish_dir);
+        let add = wish_speed - current;
+        if add <= 0.0 {
+            return;
+        }
+        let speed = (accel * dt * wish_speed).min(add);
+        self.velocity += wish_dir * speed;
+    }
+
+    fn air_accelerate(&mut self, wish_dir: Vector3<f32>, wish_speed: f32, dt: f32) {
+        let current = self.velocity.dot(wish_dir);
+        let add = wish_speed.min(AIR_SPEED) - current;
+        if add <= 0.0 {
+            return;
+        }
+        let speed = (ACCELERATE * wish_speed * dt).min(add);
+        self.velocity += wish_dir * speed;
+    }
+
+    fn air_move(&mut self, level: &BspFile, cmd: &MoveCommand, dt: f32) {
+        let mut forward = cmd.forward;
+        let mut right = cmd.right;
+        forward.z = 0.0;
+        right.z = 0.0;
+        let forward = normalize_or_zero(forward);
+        let right = normalize_or_zero(right);
+
+        let wish = forward * cmd.forward_move + right * cmd.side_move;
+        let wish_speed = wish.magnitude().min(MAX_SPEED);
+        let wish_dir = normalize_or_zero(wish);
+
+        if self.on_ground {
+            self.velocity.z = 0.0;
+            self.accelerate(wish_dir, wish_speed, ACCELERATE, dt);
+            self.ground_move(level, dt);
+        } else {
+            self.air_accelerate(wish_dir, wish_speed, dt);
+            self.velocity.z -= GRAVITY * dt;
+            self.fly_move(level, dt);
+        }
+    }
+
+    fn water_move(&mut self, level: &BspFile, cmd: &MoveCommand, dt: f32) {
+        let mut wish = cmd.forward * cmd.forward_move + cmd.right * cmd.side_move;
+        if cmd.forward_move == 0.0 && cmd.side_move == 0.0 && cmd.up_move == 0.0 {
+            // Sink slowly when not swimming
+            wish.z -= 60.0;
+        } else {
+            wish.z += cmd.up_move;
+        }
+        let wish_speed = wish.magnitude().min(MAX_SPEED) * 0.7;
+        let wish_dir = normalize_or_zero(wish);
+
+        self.accelerate(wish_dir, wish_speed, WATER_ACCELERATE, dt);
+        self.fly_move(level, dt);
+    }
+
+    // Walking along the ground, trying to step up onto stairs
+    // when blocked.
+    fn ground_move(&mut self, level: &BspFile, dt: f32) {
+        self.velocity.z = 0.0;
+        if self.velocity.x == 0.0 && self.velocity.y == 0.0 {
+            return;
+        }
+
+        let mut dest = self.origin + self.velocity * dt;
+        dest.z = self.origin.z;
+        let trace = self.trace(level, self.origin, dest);
+        if trace.fraction == 1.0 {
+            self.origin = trace.end;
+            return;
+        }
+
+        let original = self.origin;
+        let original_velocity = self.velocity;
+
+        // Slide along the ground
+        self.fly_move(level, dt);
+        let down = self.origin;
+        let down_velocity = self.velocity;
+
+        // Retry the move stepped up
+        self.origin = original;
+        self.velocity = original_velocity;
+
+        let dest = self.origin + Vector3::new(0.0, 0.0, STEP_SIZE);
+        let trace = self.trace(level, self.origin, dest);
+        if !trace.start_solid && !trace.all_solid {
+            self.origin = trace.end;
+        }
+        self.fly_move(level, dt);
+
+        // Press back down onto the step
+        let dest = self.origin - Vector3::new(0.0, 0.0, STEP_SIZE);
+        let trace = self.trace(level, self.origin, dest);
+        if trace.fraction < 1.0 && trace.plane_normal.z < 0.7 {
+            // Stepped onto something too steep to stand on
+            self.origin = down;
+            self.velocity = down_velocity;
+            return;
+        }
+        if !trace.start_solid && !trace.all_solid {
+            self.origin = trace.end;
+        }
+        let up = self.origin;
+
+        let down_dist = (down.x - original.x).powi(2) + (down.y - original.y).powi(2);
+        let up_dist = (up.x - original.x).powi(2) + (up.y - original.y).powi(2);
+        if down_dist > up_dist {
+            self.origin = down;
+            self.velocity = down_velocity;
+        } else {
+            self.velocity.z = down_velocity.z;
+        }
+    }
+
+    // Moves along the velocity, sliding along anything in
+    // the way.
+    fn fly_move(&mut self, level: &BspFile, dt: f32) {
+        let primal_velocity = self.velocity;
+        let mut original_velocity = self.velocity;
+        let mut planes: [Vector3<f32>; MAX_CLIP_PLANES] = [Vector3::zero(); MAX_CLIP_PLANES];
+        let mut num_planes = 0;
+        let mut time_left = dt;
+
+        for _ in 0 .. 4 {
+            let end = self.origin + self.velocity * time_left;
+            let trace = self.trace(level, self.origin, end);
+
+            if trace.start_solid || trace.all_solid {
+                // Stuck inside something
+                self.velocity = Vector3::zero();
+                return;
+            }
+
+            if trace.fraction > 0.0 {
+                // Later planes clip the velocity left after
+                // this move, not the one from before it
+                self.origin = trace.end;
+                original_velocity = self.velocity;
+                num_planes = 0;
+            }
+            if trace.fraction == 1.0 {
+                break;
+            }
+
+            time_left -= time_left * trace.fraction;
+
+            if num_planes >= MAX_CLIP_PLANES {
+                self.velocity = Vector3::zero();
+                break;
+            }
+            planes[num_planes] = trace.plane_normal;
+            num_planes += 1;
+
+            // Find a velocity that is parallel to all the planes
+            let mut found = false;
+            for i in 0 .. num_planes {
+                self.velocity = clip_velocity(original_velocity, planes[i]);
+                if (0 .. num_planes).all(|j| j == i || self.velocity.dot(planes[j]) >= 0.0) {
+                    found = true;
+                    break;
+                }
+            }
+
+            if !found {
+                // Go along the crease between two planes
+                if num_planes != 2 {
+                    self.velocity = Vector3::zero();
+                    break;
+                }
+                let dir = planes[0].cross(planes[1]);
+                self.velocity = dir * dir.dot(self.velocity);
+            }
+
+            // Stop dead instead of bouncing back into a corner
+            if self.velocity.dot(primal_velocity) <= 0.0 {
+                self.velocity = Vector3::zero();
+                break;
+            }
+        }
+    }
+}
+
+fn clip_velocity(velocity: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    let backoff = velocity.dot(normal);
+    let mut out = velocity - normal * backoff;
+    for i in 0 .. 3 {
+        if out[i].abs() < 0.1 {
+            out[i] = 0.0;
+        }
+    }
+    out
+}
+
+fn normalize_or_zero(v: Vector3<f32>) -> Vector3<f32> {
+    if v.magnitude2() > 0.0 {
+        v.normalize()
+    } else {
+        v
+    }
+}
+
+#[test]
+fn test_walk_move() {
+    use bsp::{ClipChild, ClipNode, Plane};
+
+    // A floor at z = 0 with an 18 unit ledge from x = 64 onwards
+    let plane = |normal, distance, kind| Plane { normal, distance, kind };
+    let level = BspFile::clip_level(
+        vec![
+            plane(Vector3::new(0.0, 0.0, 1.0), 0.0, 2),
+            plane(Vector3::new(1.0, 0.0, 0.0), 64.0, 0),
+            plane(Vector3::new(0.0, 0.0, 1.0), STEP_SIZE, 2),
+        ],
+        vec![
+            ClipNode {
+                plane: 1,
+                children: [ClipChild::Node(2), ClipChild::Node(1)],
+            },
+            ClipNode {
+                plane: 0,
+                children: [ClipChild::Contents(Contents::Empty), ClipChild::Contents(Contents::Solid)],
+            },
+            ClipNode {
+                plane: 2,
+                children: [ClipChild::Contents(Contents::Empty), ClipChild::Contents(Contents::Solid)],
+            },
+        ],
+    );
+    let mut cmd = MoveCommand {
+        forward: Vector3::new(1.0, 0.0, 0.0),
+        right: Vector3::new(0.0, -1.0, 0.0),
+        forward_move: 0.0,
+        side_move: 0.0,
+        up_move: 0.0,
+        jump: false,
+    };
+    let dt = 0.05;
+
+    // Falls and lands on the floor
+    let mut player = Player::new(Vector3::new(0.0, 0.0, 40.0));
+    for _ in 0 .. 20 {
+        player.update(&level, &cmd, dt);
+    }
+    assert!(player.on_ground);
+    assert!(player.origin.z >= 0.0 && player.origin.z < 0.1);
+    assert_eq!(player.velocity.z, 0.0);
+
+    // Friction stops a slide along the floor
+    player.velocity = Vector3::new(200.0, 0.0, 0.0);
+    for _ in 0 .. 10 {
+        player.update(&level, &cmd, dt);
+    }
+    assert_eq!(player.velocity, Vector3::zero());
+    assert!(player.origin.x > 0.0 && player.origin.x < 40.0);
+
+    // Walking into the ledge steps up onto it
+    cmd.forward_move = MAX_SPEED;
+    for _ in 0 .. 20 {
+        player.update(&level, &cmd, dt);
+    }
+    assert!(player.on_ground);
+    assert!(player.origin.x > 64.0);
+    assert!(player.origin.z >= STEP_SIZE && player.origin.z < STEP_SIZE + 0.1);
+}
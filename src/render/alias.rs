@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::mem::size_of;
+use std::ops::Range;
+use cgmath::prelude::*;
+use cgmath::{Vector3, Matrix4};
+use log::*;
+
+use hal::{
+    Backend,
+    CommandPool,
+    pso,
+    command,
+    queue,
+};
+
+use crate::error;
+use crate::bsp;
+use crate::mdl;
+use crate::vfs::FileSystem;
+use super::atlas;
+use super::alloc;
+use super::qmap::entity_transform;
+use super::{BufferBundle, ImageBundle, upload_vertex_buffer, upload_atlas};
+
+// The models of entities that have one. Quake's game code picks
+// these so they are listed here instead, with the skin to use.
+const ENTITY_MODELS: &[(&str, &str, usize)] = &[
+    ("monster_army", "progs/soldier.mdl", 0),
+    ("monster_dog", "progs/dog.mdl", 0),
+    ("monster_ogre", "progs/ogre.mdl", 0),
+    ("monster_ogre_marksman", "progs/ogre.mdl", 0),
+    ("monster_knight", "progs/knight.mdl", 0),
+    ("monster_hell_knight", "progs/hknight.mdl", 0),
+    ("monster_demon1", "progs/demon.mdl", 0),
+    ("monster_wizard", "progs/wizard.mdl", 0),
+    ("monster_zombie", "progs/zombie.mdl", 0),
+    ("monster_shambler", "progs/shambler.mdl", 0),
+    ("monster_enforcer", "progs/enforcer.mdl", 0),
+    ("monster_fish", "progs/fish.mdl", 0),
+    ("monster_tarbaby", "progs/tarbaby.mdl", 0),
+    ("monster_shalrath", "progs/shalrath.mdl", 0),
+    ("monster_boss", "progs/boss.mdl", 0),
+    ("monster_oldone", "progs/oldone.mdl", 0),
+    ("item_armor1", "progs/armor.mdl", 0),
+    ("item_armor2", "progs/armor.mdl", 1),
+    ("item_armorInv", "progs/armor.mdl", 2),
+    ("weapon_supershotgun", "progs/g_shot.mdl", 0),
+    ("weapon_nailgun", "progs/g_nail.mdl", 0),
+    ("weapon_supernailgun", "progs/g_nail2.mdl", 0),
+    ("weapon_grenadelauncher", "progs/g_rock.mdl", 0),
+    ("weapon_rocketlauncher", "progs/g_rock2.mdl", 0),
+    ("weapon_lightning", "progs/g_light.mdl", 0),
+    ("item_artifact_invulnerability", "progs/invulner.mdl", 0),
+    ("item_artifact_envirosuit", "progs/suit.mdl", 0),
+    ("item_artifact_invisibility", "progs/invisibl.mdl", 0),
+    ("item_artifact_super_damage", "progs/quaddama.mdl", 0),
+    ("light_torch_small_walltorch", "progs/flame.mdl", 0),
+    ("light_flame_large_yellow", "progs/flame2.mdl", 0),
+    ("light_flame_small_yellow", "progs/flame2.mdl", 0),
+    ("light_flame_small_white", "progs/flame2.mdl", 0),
+];
+
+// Alias models animate at 10 frames a second
+const FRAME_RATE: f32 = 10.0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(super) struct AliasVertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(super) struct AliasTex {
+    tex: [f32; 2],
+}
+
+pub struct AliasModels<B: Backend> {
+    models: Vec<AliasModel>,
+    pub entities: Vec<AliasEntity>,
+
+    // Every pose of every model, each pose is a triangle list
+    buffer: BufferBundle<B>,
+    // Texture coordinates shared by all poses of a model
+    buffer_tex: BufferBundle<B>,
+    pub skins: ImageBundle<B>,
+
+    time: f32,
+}
+
+struct AliasModel {
+    mdl: mdl::MdlFile,
+    vert_count: u32,
+    tex_start: u32,
+    // Vertex offset of the first pose of each frame
+    frame_starts: Vec<u32>,
+    // Location of every skin in the atlas, grouped skins
+    // have several
+    skins: Vec<Vec<atlas::Rect>>,
+}
+
+// An entity drawn with an alias model, such as a monster
+// or an item. The game moves and animates these by changing
+// the origin, angles and animation.
+#[derive(Debug, Clone)]
+pub struct AliasEntity {
+    // Index of the entity in the level's entity list
+    pub entity: usize,
+    pub model: usize,
+    pub origin: Vector3<f32>,
+    // Pitch, yaw and roll in degrees
+    pub angles: Vector3<f32>,
+    pub skin: usize,
+    // Frames the entity loops through
+    pub animation: Range<usize>,
+}
+
+impl <B> AliasModels<B>
+    where B: Backend,
+{
+    pub fn new(
+        files: &FileSystem,
+        level: &bsp::BspFile,
+        device: &B::Device,
+        queue: &mut queue::CommandQueue<B, hal::Graphics>,
+        command_pool: &mut CommandPool<B, hal::Graphics>,
+        allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>,
+    ) -> error::Result<AliasModels<B>>
+    {
+        let mut atlas = atlas::TextureAtlas::new(
+            super::ATLAS_SIZE as i32,
+            super::ATLAS_SIZE as i32
+        );
+        let mut skin_data = vec![0u8; (super::ATLAS_SIZE * super::ATLAS_SIZE) as usize];
+
+        let mut models = vec![];
+        let mut model_ids: HashMap<&str, Option<usize>> = HashMap::new();
+        let mut entities = vec![];
+        let mut verts = vec![];
+        let mut tex_verts = vec![];
+
+        for (idx, e) in level.entities.iter().enumerate() {
+            let (name, skin) = match e.classname()
+                .and_then(|c| ENTITY_MODELS.iter().find(|v| v.0 == c))
+            {
+                Some(&(_, name, skin)) => (name, skin),
+                None => continue,
+            };
+            let model = *model_ids.entry(name).or_insert_with(|| {
+                match load_model(files, name, &mut atlas, &mut skin_data, &mut verts, &mut tex_verts) {
+                    Ok(model) => {
+                        models.push(model);
+                        Some(models.len() - 1)
+                    },
+                    Err(err) => {
+                        warn!("Failed to load model {}: {}", name, err);
+                        None
+                    },
+                }
+            });
+            let model = match model {
+                Some(v) => v,
+                None => continue,
+            };
+            entities.push(AliasEntity {
+                entity: idx,
+                model: model,
+                origin: e.origin().unwrap_or(Vector3::zero()),
+                angles: Vector3::new(0.0, e.angle().unwrap_or(0.0), 0.0),
+                skin: skin,
+                animation: models[model].mdl.frame_sequence(0),
+            });
+        }
+
+        let buffer = unsafe { upload_vertex_buffer(device, queue, command_pool, allocator, &verts) };
+        let buffer_tex = unsafe { upload_vertex_buffer(device, queue, command_pool, allocator, &tex_verts) };
+
+        let skins = unsafe { upload_atlas(device, queue, command_pool, allocator, &skin_data) };
+
+        Ok(AliasModels {
+            models,
+            entities,
+            buffer,
+            buffer_tex,
+            skins,
+            time: 0.0,
+        })
+    }
+
+    pub fn draw(
+        &mut self,
+        delta: f32,
+        matrix: Matrix4<f32>,
+        light_styles: &[f32],
+        light_point: impl Fn(Vector3<f32>, &[f32]) -> f32,
+        layout: &B::PipelineLayout,
+        pipeline: &B::GraphicsPipeline,
+        encoder: &mut command::RenderPassInlineEncoder<B>,
+    ) {
+        self.time += delta / 60.0;
+        if self.entities.is_empty() {
+            return;
+        }
+
+        unsafe {
+            encoder.bind_graphics_pipeline(pipeline);
+            for entity in &self.entities {
+                let model = &self.models[entity.model];
+                let frames = model.mdl.frames.len();
+                let anim = &entity.animation;
+                if anim.start >= anim.end || anim.end > frames {
+                    continue;
+                }
+
+                // Steps through the animation blending each frame
+                // into the next one
+                let pos = self.time * FRAME_RATE;
+                let step = pos as usize;
+                let lerp = pos.fract();
+                let frame = anim.start + step % anim.len();
+                let next = anim.start + (step + 1) % anim.len();
+                let pose = |frame: usize| {
+                    let start = model.frame_starts[frame]
+                        + model.mdl.frames[frame].index_at_time(self.time) as u32 * model.vert_count;
+                    (start as usize * size_of::<AliasVertex>()) as u64
+                };
+
+                let skin_id = entity.skin.min(model.skins.len().saturating_sub(1));
+                let skin = match model.skins.get(skin_id) {
+                    Some(rects) => rects[model.mdl.skins[skin_id].index_at_time(self.time)],
+                    None => continue,
+                };
+
+                // Models are lit by the floor below them but never
+                // drawn fully dark
+                let light = light_point(entity.origin, light_styles)
+                    .max(0.1)
+                    .min(1.5);
+
+                let transform: [[f32; 4]; 4] = (matrix * entity_transform(entity.origin, entity.angles)).into();
+                encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 0, hal::memory::cast_slice(&[transform]));
+                encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 4*4*4 + 4*4, &[
+                    lerp.to_bits(),
+                    light.to_bits(),
+                    (skin.x as f32).to_bits(),
+                    (skin.y as f32).to_bits(),
+                ]);
+                encoder.bind_vertex_buffers(0, vec![
+                    (&*self.buffer.buffer, pose(frame)),
+                    (&*self.buffer.buffer, pose(next)),
+                    (&*self.buffer_tex.buffer, (model.tex_start as usize * size_of::<AliasTex>()) as u64),
+                ]);
+                encoder.draw(0 .. model.vert_count, 0..1);
+            }
+        }
+    }
+
+    pub unsafe fn destroy(self, device: &B::Device, allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>) {
+        self.buffer.destroy(device, allocator);
+        self.buffer_tex.destroy(device, allocator);
+        self.skins.destroy(device, allocator);
+    }
+}
+
+// Loads a model from the pak file, packing its skins into the
+// atlas and its poses into the vertex lists
+fn load_model(
+    files: &FileSystem,
+    name: &str,
+    atlas: &mut atlas::TextureAtlas,
+    skin_data: &mut [u8],
+    verts: &mut Vec<AliasVertex>,
+    tex_verts: &mut Vec<AliasTex>,
+) -> error::Result<AliasModel> {
+    let data = files.file(name)?;
+    let mdl = mdl::MdlFile::parse(&mut Cursor::new(data))?;
+    let (width, height) = (mdl.skin_width as usize, mdl.skin_height as usize);
+
+    let mut skins = Vec::with_capacity(mdl.skins.len());
+    for skin in &mdl.skins {
+        let pictures = match skin {
+            mdl::Skin::Single(data) => vec![data],
+            mdl::Skin::Group { skins, .. } => skins.iter().collect(),
+        };
+        let mut rects = Vec::with_capacity(pictures.len());
+        for data in pictures {
+            let rect = match atlas.find(width as i32, height as i32) {
+                Some(v) => v,
+                None => bail!("Out of space for skins"),
+            };
+            for y in 0 .. height {
+                let idx = rect.x as usize + (rect.y as usize + y) * super::ATLAS_SIZE as usize;
+                skin_data[idx .. idx + width].copy_from_slice(&data[y * width .. (y + 1) * width]);
+            }
+            rects.push(rect);
+        }
+        skins.push(rects);
+    }
+
+    // Each corner of a triangle gets its own vertex as the back
+    // half of the skin is used by corners on the seam of back
+    // facing triangles. The order is reversed to match the
+    // winding of the level.
+    let tex_start = tex_verts.len() as u32;
+    for tri in &mdl.triangles {
+        for &v in tri.vertices.iter().rev() {
+            let st = &mdl.st_verts[v];
+            let mut s = st.s as f32;
+            if st.on_seam && !tri.faces_front {
+                s += width as f32 / 2.0;
+            }
+            tex_verts.push(AliasTex {
+                tex: [s + 0.5, st.t as f32 + 0.5],
+            });
+        }
+    }
+    let vert_count = mdl.triangles.len() as u32 * 3;
+
+    let mut frame_starts = Vec::with_capacity(mdl.frames.len());
+    for frame in &mdl.frames {
+        frame_starts.push(verts.len() as u32);
+        for pose in frame.poses() {
+            let positions = pose.vertices.iter()
+                .map(|v| mdl.vertex_position(v))
+                .collect::<Vec<_>>();
+            // Smooth normals from the faces around each vertex,
+            // triangles are clockwise when seen from the front
+            let mut normals = vec![Vector3::zero(); positions.len()];
+            for tri in &mdl.triangles {
+                let [a, b, c] = tri.vertices;
+                let normal = (positions[c] - positions[a]).cross(positions[b] - positions[a]);
+                for &v in &tri.vertices {
+                    normals[v] += normal;
+                }
+            }
+            for tri in &mdl.triangles {
+                for &v in tri.vertices.iter().rev() {
+                    let normal = if normals[v].magnitude2() > 0.0 {
+                        normals[v].normalize()
+                    } else {
+                        Vector3::unit_z()
+                    };
+                    verts.push(AliasVertex {
+                        position: positions[v].into(),
+                        normal: normal.into(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(AliasModel {
+        mdl,
+        vert_count,
+        tex_start,
+        frame_starts,
+        skins,
+    })
+}
@@ -11,7 +11,8 @@ use hal::{
 };
 use crate::bitset::BitSet;
 
-const REGION_SIZE: u64 = 64 * 1024 * 1024; // 64mb
+// Also the largest single allocation that can be made
+pub const REGION_SIZE: u64 = 64 * 1024 * 1024; // 64mb
 
 pub struct GPUAlloc<B: Backend, A: RangeAlloc> {
     pub limits: Limits,
@@ -0,0 +1,130 @@
+use cgmath::Vector3;
+use hal::Backend;
+
+use crate::console::Console;
+use super::overlay::{Overlay, CHAR_SIZE, VIRTUAL_WIDTH};
+
+const SBAR_HEIGHT: f32 = 24.0;
+// Part of the screen covered by the open console
+const CONSOLE_HEIGHT: f32 = 0.5;
+// Width of the num_* pictures
+const NUM_WIDTH: f32 = 24.0;
+
+// The status bar and the information lines in the top corner.
+// The game fills in the player's state, the frame timings are
+// tracked here.
+pub struct Hud {
+    pub map_name: String,
+    pub health: i32,
+    pub armor: i32,
+    pub ammo: i32,
+
+    frames: u32,
+    frame_time: f32,
+    fps: u32,
+    average_frame_time: f32,
+}
+
+impl Default for Hud {
+    fn default() -> Hud {
+        Hud::new()
+    }
+}
+
+impl Hud {
+    pub fn new() -> Hud {
+        Hud {
+            map_name: String::new(),
+            health: 100,
+            armor: 0,
+            ammo: 25,
+
+            frames: 0,
+            frame_time: 0.0,
+            fps: 0,
+            average_frame_time: 0.0,
+        }
+    }
+
+    // Counts the frame, the timings shown are updated once
+    // a second
+    pub(super) fn update(&mut self, delta: f32) {
+        self.frames += 1;
+        self.frame_time += delta / 60.0;
+        if self.frame_time >= 1.0 {
+            self.fps = (self.frames as f32 / self.frame_time).round() as u32;
+            self.average_frame_time = self.frame_time / self.frames as f32;
+            self.frames = 0;
+            self.frame_time = 0.0;
+        }
+    }
+
+    pub(super) fn draw<B: Backend>(&self, overlay: &mut Overlay<B>, position: Vector3<f32>) {
+        let lines = [
+            format!("FPS: {} ({:.1}ms)", self.fps, self.average_frame_time * 1000.0),
+            format!("Map: {}", self.map_name),
+            format!("Pos: {:.0} {:.0} {:.0}", position.x, position.y, position.z),
+        ];
+        for (idx, line) in lines.iter().enumerate() {
+            overlay.draw_string(CHAR_SIZE, CHAR_SIZE * (idx + 1) as f32, line);
+        }
+
+        // Laid out like Quake's status bar, centered at the
+        // bottom of the screen
+        let (width, height) = overlay.size();
+        let left = ((width - VIRTUAL_WIDTH) / 2.0).floor();
+        let top = height - SBAR_HEIGHT;
+        overlay.draw_picture("ibar", left, top - SBAR_HEIGHT);
+        overlay.draw_picture("sbar", left, top);
+
+        if self.armor > 0 {
+            overlay.draw_picture("sb_armor1", left, top);
+        }
+        draw_number(overlay, left + 24.0, top, self.armor, 3);
+
+        let face = 5 - (self.health / 20).max(0).min(4);
+        overlay.draw_picture(&format!("face{}", face), left + 112.0, top);
+        draw_number(overlay, left + 136.0, top, self.health, 3);
+
+        overlay.draw_picture("sb_shells", left + 224.0, top);
+        draw_number(overlay, left + 248.0, top, self.ammo, 3);
+    }
+}
+
+// Draws a number right aligned in a space `digits` wide
+fn draw_number<B: Backend>(overlay: &mut Overlay<B>, x: f32, y: f32, value: i32, digits: usize) {
+    let text = value.to_string();
+    let text = &text[text.len().saturating_sub(digits) ..];
+    let mut x = x + (digits - text.len()) as f32 * NUM_WIDTH;
+    for c in text.bytes() {
+        let name = match c {
+            b'-' => "num_minus".to_owned(),
+            c => format!("num_{}", c - b'0'),
+        };
+        overlay.draw_picture(&name, x, y);
+        x += NUM_WIDTH;
+    }
+}
+
+// Draws the console dropped down over the top of the screen
+// with the newest lines at the bottom
+pub(super) fn draw_console<B: Backend>(console: &Console, overlay: &mut Overlay<B>, time: f32) {
+    if !console.open {
+        return;
+    }
+    let (width, height) = overlay.size();
+    let bottom = (height * CONSOLE_HEIGHT).floor();
+    // Only the bottom of the background is shown like Quake
+    overlay.draw_picture_scaled("gfx/conback.lmp", 0.0, bottom - height, width, height);
+
+    let mut y = bottom - CHAR_SIZE * 2.0;
+    let cursor = if (time * 4.0) as i32 % 2 == 0 { "_" } else { "" };
+    overlay.draw_string(CHAR_SIZE, y, &format!("]{}{}", console.input, cursor));
+    for line in console.lines().iter().rev() {
+        y -= CHAR_SIZE;
+        if y < 0.0 {
+            break;
+        }
+        overlay.draw_string(CHAR_SIZE, y, line);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::bsp;
+
+pub const MAX_LIGHT_STYLES: usize = 64;
+
+// Styles 32 and up are used by switchable lights
+const FIRST_SWITCHABLE: usize = 32;
+
+// The animated styles from Quake's world.qc, each letter is
+// a brightness from 'a' (dark) to 'z' (double bright) with
+// 'm' being normal.
+const DEFAULT_STYLES: &[(usize, &str)] = &[
+    // Normal
+    (0, "m"),
+    // Flicker (first variety)
+    (1, "mmnmmommommnonmmonqnmmo"),
+    // Slow strong pulse
+    (2, "abcdefghijklmnopqrstuvwxyzyxwvutsrqponmlkjihgfedcba"),
+    // Candle (first variety)
+    (3, "mmmmmaaaaammmmmaaaaaabcdefgabcdefg"),
+    // Fast strobe
+    (4, "mamamamamama"),
+    // Gentle pulse 1
+    (5, "jklmnopqrstuvwxyzyxwvutsrqponmlkj"),
+    // Flicker (second variety)
+    (6, "nmonqnmomnmomomno"),
+    // Candle (second variety)
+    (7, "mmmaaaabcdefgmmmmaaaammmaamm"),
+    // Candle (third variety)
+    (8, "mmmaaammmaaammmabcdefaaaammmmabcdefmmmaaaa"),
+    // Slow strobe (fourth variety)
+    (9, "aaaaaaaazzzzzzzz"),
+    // Fluorescent flicker
+    (10, "mmamammmmammamamaaamammma"),
+    // Slow pulse not fade to black
+    (11, "abcdefghijklmnopqrrqponmlkjihgfedcba"),
+    // Testing
+    (63, "a"),
+];
+
+pub struct LightStyles {
+    styles: Vec<Vec<u8>>,
+}
+
+impl Default for LightStyles {
+    fn default() -> LightStyles {
+        LightStyles::new()
+    }
+}
+
+impl LightStyles {
+    pub fn new() -> LightStyles {
+        let mut styles = LightStyles {
+            styles: vec![vec![]; MAX_LIGHT_STYLES],
+        };
+        for (style, pattern) in DEFAULT_STYLES {
+            styles.set(*style, pattern);
+        }
+        styles
+    }
+
+    // Resets the styles and applies the initial state of
+    // the switchable lights in the level.
+    pub fn setup_level(&mut self, entities: &[bsp::Entity]) {
+        *self = LightStyles::new();
+        for e in entities {
+            if !e.classname().map_or(false, |v| v.starts_with("light")) {
+                continue;
+            }
+            let style = match e.get("style").and_then(|v| v.parse::<usize>().ok()) {
+                Some(v) if v >= FIRST_SWITCHABLE && v < MAX_LIGHT_STYLES => v,
+                _ => continue,
+            };
+            let start_off = e.get("spawnflags")
+                .and_then(|v| v.parse::<i32>().ok())
+                .map_or(false, |v| v & 1 != 0);
+            self.set(style, if start_off { "a" } else { "m" });
+        }
+    }
+
+    pub fn set(&mut self, style: usize, pattern: &str) {
+        if let Some(s) = self.styles.get_mut(style) {
+            *s = pattern.bytes()
+                .filter(|v| v.is_ascii_lowercase())
+                .collect();
+        }
+    }
+
+    // Evaluates every style at the given time in seconds. Styles
+    // advance at 10 frames a second, 1.0 is normal brightness.
+    pub fn values(&self, time: f32) -> [f32; MAX_LIGHT_STYLES] {
+        let frame = (time * 10.0) as usize;
+        let mut values = [1.0; MAX_LIGHT_STYLES];
+        for (v, style) in values.iter_mut().zip(&self.styles) {
+            if style.is_empty() {
+                continue;
+            }
+            let c = style[frame % style.len()];
+            *v = (c - b'a') as f32 * 22.0 / 256.0;
+        }
+        values
+    }
+}
@@ -3,16 +3,32 @@ mod atlas;
 mod qmap;
 mod alloc;
 mod util;
+mod skybox;
+mod alias;
+mod sprite;
+mod overlay;
+mod hud;
+pub mod lightstyle;
+
+pub use self::qmap::BrushEntity;
+pub use self::alias::AliasEntity;
+pub use self::sprite::SpriteEntity;
+pub use self::hud::Hud;
 
 use util::*;
 
 use std::rc::Rc;
-use std::mem::{ManuallyDrop, size_of};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::mem::{self, ManuallyDrop, size_of};
 use log::*;
 
-use crate::pak::PackFile;
+use crate::vfs::FileSystem;
 use crate::error;
 use crate::bsp;
+use crate::tga;
+use crate::console::Console;
+use crate::player::VIEW_HEIGHT;
 
 use hal::{
     Backend,
@@ -61,16 +77,23 @@ use hal::{
 use cgmath;
 
 const ATLAS_SIZE: u32 = 1024;
+const LIGHT_STYLE_SIZE: u64 = (lightstyle::MAX_LIGHT_STYLES * 4) as u64;
 
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct Vertex {
     position: [f32; 3],
     tex: [u16; 2],
+    // Start of the alternate frames of an animated texture
+    tex_alt: [u16; 2],
     tex_info: [i16; 4],
     light_info: [i16; 2],
+    // Distance between the light maps of each style in the atlas
+    light_stride: i16,
     light: u8,
-    light_type: u8,
+    light_styles: [u8; 4],
+    // Number of primary and alternate animation frames
+    tex_frames: [u8; 2],
 }
 
 #[repr(C)]
@@ -88,13 +111,77 @@ pub struct Camera {
     pub rot_x: cgmath::Rad<f32>,
 }
 
+impl Camera {
+    // Moves the camera to the view of a spawn point entity
+    pub fn spawn_at(&mut self, entity: &bsp::Entity) {
+        use std::f32::consts::PI;
+        if let Some(origin) = entity.origin() {
+            self.x = origin.x;
+            self.y = origin.y;
+            self.z = origin.z;
+            // Intermission points are the view position
+            // themselves instead of a player's origin
+            if entity.classname() != Some("info_intermission") {
+                self.z += VIEW_HEIGHT;
+            }
+        }
+        // `mangle` is pitch, yaw, roll in degrees
+        let (pitch, yaw) = match entity.get_vector("mangle") {
+            Some(mangle) => (mangle.x, mangle.y),
+            None => (0.0, entity.angle().unwrap_or(0.0)),
+        };
+        // Quake's yaw starts on the x axis and turns towards
+        // the y axis, the camera starts on the y axis.
+        self.rot_y = cgmath::Rad(PI / 2.0 - yaw.to_radians());
+        self.rot_x = cgmath::Rad(PI + pitch.to_radians());
+    }
+
+    pub fn position(&self) -> cgmath::Vector3<f32> {
+        cgmath::Vector3::new(self.x, self.y, self.z)
+    }
+
+    pub fn set_position(&mut self, pos: cgmath::Vector3<f32>) {
+        self.x = pos.x;
+        self.y = pos.y;
+        self.z = pos.z;
+    }
+
+    // The direction the camera is looking in
+    pub fn forward(&self) -> cgmath::Vector3<f32> {
+        let pitch = -self.rot_x.0.cos();
+        cgmath::Vector3::new(
+            self.rot_y.0.sin() * pitch,
+            self.rot_y.0.cos() * pitch,
+            self.rot_x.0.sin(),
+        )
+    }
+
+    pub fn right(&self) -> cgmath::Vector3<f32> {
+        cgmath::Vector3::new(self.rot_y.0.cos(), -self.rot_y.0.sin(), 0.0)
+    }
+}
+
 pub struct Renderer<B: Backend> {
-    pak: Rc<PackFile>,
+    files: Rc<FileSystem>,
     level: ManuallyDrop<qmap::QMap<B>>,
+    models: ManuallyDrop<alias::AliasModels<B>>,
+    sprites: ManuallyDrop<sprite::Sprites<B>>,
+    overlay: ManuallyDrop<overlay::Overlay<B>>,
 
     pub camera: Camera,
+    pub hud: Hud,
+    pub light_styles: lightstyle::LightStyles,
+    // r_wateralpha, the opacity of water, slime, lava and teleporters
+    pub water_alpha: f32,
+    // Horizontal field of view in degrees
+    pub fov: f32,
+    // r_drawworld, levels can be hidden to look at the entities
+    pub draw_world: bool,
+    // Where to save the next frame drawn
+    screenshot: Option<PathBuf>,
     display_size: (u32, u32),
     frame: usize,
+    time: f32,
 
     adapter: Adapter<B>,
     pub(crate) surface: B::Surface,
@@ -106,12 +193,16 @@ pub struct Renderer<B: Backend> {
 }
 
 struct GfxState<B: Backend> {
+    format: format::Format,
     render_pass: B::RenderPass,
     framebuffers: Vec<B::Framebuffer>,
     frame_images: Vec<(B::Image, B::ImageView)>,
     depth_images: Vec<DepthImage<B>>,
 
     swap_chain: Option<B::Swapchain>,
+    // Whether frames can be copied out of the swap chain
+    // for screenshots, not every surface supports it
+    screenshots: bool,
 
     allocator: alloc::GPUAlloc<B, alloc::ChunkAlloc>,
 
@@ -124,8 +215,12 @@ struct GfxState<B: Backend> {
     cmd_buffers: Vec<CommandBuffer<B, hal::Graphics, command::MultiShot>>,
 
     pipeline: B::GraphicsPipeline,
-    depth_pipeline: B::GraphicsPipeline,
     sky_pipeline: B::GraphicsPipeline,
+    sky_box_pipeline: B::GraphicsPipeline,
+    turb_pipeline: B::GraphicsPipeline,
+    alias_pipeline: B::GraphicsPipeline,
+    sprite_pipeline: B::GraphicsPipeline,
+    overlay_pipeline: B::GraphicsPipeline,
     pipeline_layout: B::PipelineLayout,
 
     descriptor_set_layouts: Vec<B::DescriptorSetLayout>,
@@ -134,11 +229,15 @@ struct GfxState<B: Backend> {
 
     texture_colour_map: ImageBundle<B>,
     texture_palette_map: ImageBundle<B>,
+
+    // One slot of light style values per frame in flight
+    light_style_buffer: BufferBundle<B>,
+    light_style_stride: u64,
 }
 
 impl <B: Backend> Renderer<B> {
     pub fn new(
-        pak: Rc<PackFile>, level: bsp::BspFile,
+        files: Rc<FileSystem>, level: Rc<bsp::BspFile>,
         mut adapter: Adapter<B>,
         mut surface: B::Surface,
         size: (f64, f64),
@@ -222,7 +321,7 @@ impl <B: Backend> Renderer<B> {
                 .expect("Can't create render pass")
         };
 
-        let (swap_chain, framebuffers, frame_images, depth_images) = Self::make_swapchain(
+        let (swap_chain, screenshots, framebuffers, frame_images, depth_images) = Self::make_swapchain(
             &mut adapter, &device, &mut allocator, &mut surface, &render_pass, None,
             size.0, size.1,
         );
@@ -274,8 +373,8 @@ impl <B: Backend> Renderer<B> {
         }
 
         let (texture_colour_map, texture_palette_map) = unsafe {
-            let colour_map = pak.file("gfx/colormap.lmp")?;
-            let palette_map = pak.file("gfx/palette.lmp")?;
+            let colour_map = files.file("gfx/colormap.lmp")?;
+            let palette_map = files.file("gfx/palette.lmp")?;
 
             let texture_colour_map = ImageBundle::new(
                 &device, &mut allocator, 256, 64, 1,
@@ -445,7 +544,27 @@ impl <B: Backend> Renderer<B> {
         };
 
 
-        let level = qmap::QMap::new(level, &mut adapter, &device, &mut queue_group.queues[0], &mut cmd_pools[0], &mut allocator)?;
+        let mut light_styles = lightstyle::LightStyles::new();
+        light_styles.setup_level(&level.entities);
+        let sky_box = load_sky_box(&files, &level, &allocator.limits);
+        let models = alias::AliasModels::new(&files, &level, &device, &mut queue_group.queues[0], &mut cmd_pools[0], &mut allocator)?;
+        let sprites = sprite::Sprites::new(&files, &level, &device, &mut queue_group.queues[0], &mut cmd_pools[0], &mut allocator)?;
+        let overlay = overlay::Overlay::new(&files, frames_in_flight, &device, &mut queue_group.queues[0], &mut cmd_pools[0], &mut allocator)?;
+        let level = qmap::QMap::new(level, sky_box.as_ref(), &mut adapter, &device, &mut queue_group.queues[0], &mut cmd_pools[0], &mut allocator)?;
+
+        let light_style_stride = {
+            let align = allocator.limits.min_uniform_buffer_offset_alignment.max(1);
+            (LIGHT_STYLE_SIZE + align - 1) / align * align
+        };
+        let light_style_buffer = unsafe {
+            BufferBundle::new(
+                &device,
+                &mut allocator,
+                light_style_stride * frames_in_flight as u64,
+                hal::buffer::Usage::UNIFORM,
+                hal::memory::Properties::CPU_VISIBLE
+            )
+        };
 
         let mut compiler = shaderc::Compiler::new().unwrap();
         let vca = compiler
@@ -466,6 +585,43 @@ impl <B: Backend> Renderer<B> {
             .map_err(|e| {error!("{}", e); e})
             .unwrap();
 
+        let sb_fca = compiler
+            .compile_into_spirv(include_str!("shader/skybox.glslf"), shaderc::ShaderKind::Fragment, "skybox.glslf", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+
+        let t_fca = compiler
+            .compile_into_spirv(include_str!("shader/turb.glslf"), shaderc::ShaderKind::Fragment, "turb.glslf", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+
+        let a_vca = compiler
+            .compile_into_spirv(include_str!("shader/alias.glslv"), shaderc::ShaderKind::Vertex, "alias.glslv", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+        let a_fca = compiler
+            .compile_into_spirv(include_str!("shader/alias.glslf"), shaderc::ShaderKind::Fragment, "alias.glslf", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+
+        let sp_vca = compiler
+            .compile_into_spirv(include_str!("shader/sprite.glslv"), shaderc::ShaderKind::Vertex, "sprite.glslv", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+        let sp_fca = compiler
+            .compile_into_spirv(include_str!("shader/sprite.glslf"), shaderc::ShaderKind::Fragment, "sprite.glslf", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+
+        let o_vca = compiler
+            .compile_into_spirv(include_str!("shader/overlay.glslv"), shaderc::ShaderKind::Vertex, "overlay.glslv", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+        let o_fca = compiler
+            .compile_into_spirv(include_str!("shader/overlay.glslf"), shaderc::ShaderKind::Fragment, "overlay.glslf", "main", None)
+            .map_err(|e| {error!("{}", e); e})
+            .unwrap();
+
         let vsm = unsafe {
             device.create_shader_module(vca.as_binary_u8())
                 .unwrap()
@@ -482,6 +638,38 @@ impl <B: Backend> Renderer<B> {
             device.create_shader_module(s_fca.as_binary_u8())
                 .unwrap()
         };
+        let sb_fsm = unsafe {
+            device.create_shader_module(sb_fca.as_binary_u8())
+                .unwrap()
+        };
+        let t_fsm = unsafe {
+            device.create_shader_module(t_fca.as_binary_u8())
+                .unwrap()
+        };
+        let a_vsm = unsafe {
+            device.create_shader_module(a_vca.as_binary_u8())
+                .unwrap()
+        };
+        let a_fsm = unsafe {
+            device.create_shader_module(a_fca.as_binary_u8())
+                .unwrap()
+        };
+        let sp_vsm = unsafe {
+            device.create_shader_module(sp_vca.as_binary_u8())
+                .unwrap()
+        };
+        let sp_fsm = unsafe {
+            device.create_shader_module(sp_fca.as_binary_u8())
+                .unwrap()
+        };
+        let o_vsm = unsafe {
+            device.create_shader_module(o_vca.as_binary_u8())
+                .unwrap()
+        };
+        let o_fsm = unsafe {
+            device.create_shader_module(o_fca.as_binary_u8())
+                .unwrap()
+        };
 
         let vs_entry = EntryPoint {
             entry: "main",
@@ -500,12 +688,17 @@ impl <B: Backend> Renderer<B> {
             geometry: None,
             fragment: Some(fs_entry),
         };
-        let depth_shaders = GraphicsShaderSet {
+        let t_fs_entry = EntryPoint {
+            entry: "main",
+            module: &t_fsm,
+            specialization: hal::pso::Specialization::default(),
+        };
+        let t_shaders = GraphicsShaderSet {
             vertex: vs_entry,
             hull: None,
             domain: None,
             geometry: None,
-            fragment: None,
+            fragment: Some(t_fs_entry),
         };
 
         let s_vs_entry = EntryPoint {
@@ -519,12 +712,72 @@ impl <B: Backend> Renderer<B> {
             specialization: hal::pso::Specialization::default(),
         };
         let s_shaders = GraphicsShaderSet {
-            vertex: s_vs_entry,
+            vertex: s_vs_entry.clone(),
             hull: None,
             domain: None,
             geometry: None,
             fragment: Some(s_fs_entry),
         };
+        let sb_fs_entry = EntryPoint {
+            entry: "main",
+            module: &sb_fsm,
+            specialization: hal::pso::Specialization::default(),
+        };
+        let sb_shaders = GraphicsShaderSet {
+            vertex: s_vs_entry,
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(sb_fs_entry),
+        };
+
+        let a_shaders = GraphicsShaderSet {
+            vertex: EntryPoint {
+                entry: "main",
+                module: &a_vsm,
+                specialization: hal::pso::Specialization::default(),
+            },
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(EntryPoint {
+                entry: "main",
+                module: &a_fsm,
+                specialization: hal::pso::Specialization::default(),
+            }),
+        };
+
+        let sp_shaders = GraphicsShaderSet {
+            vertex: EntryPoint {
+                entry: "main",
+                module: &sp_vsm,
+                specialization: hal::pso::Specialization::default(),
+            },
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(EntryPoint {
+                entry: "main",
+                module: &sp_fsm,
+                specialization: hal::pso::Specialization::default(),
+            }),
+        };
+
+        let o_shaders = GraphicsShaderSet {
+            vertex: EntryPoint {
+                entry: "main",
+                module: &o_vsm,
+                specialization: hal::pso::Specialization::default(),
+            },
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(EntryPoint {
+                entry: "main",
+                module: &o_fsm,
+                specialization: hal::pso::Specialization::default(),
+            }),
+        };
 
         let vertex_buffers = vec![pso::VertexBufferDesc {
             binding: 0,
@@ -556,6 +809,7 @@ impl <B: Backend> Renderer<B> {
                     offset: (
                         size_of::<[f32; 3]>()
                         + size_of::<[u16; 2]>()
+                        + size_of::<[u16; 2]>()
                     ) as u32,
                 }
             },
@@ -567,6 +821,7 @@ impl <B: Backend> Renderer<B> {
                     offset: (
                         size_of::<[f32; 3]>()
                         + size_of::<[u16; 2]>()
+                        + size_of::<[u16; 2]>()
                         + size_of::<[i16; 4]>()
                     ) as u32,
                 }
@@ -579,8 +834,10 @@ impl <B: Backend> Renderer<B> {
                     offset: (
                         size_of::<[f32; 3]>()
                         + size_of::<[u16; 2]>()
+                        + size_of::<[u16; 2]>()
                         + size_of::<[i16; 4]>()
                         + size_of::<[i16; 2]>()
+                        + size_of::<i16>()
                     ) as u32,
                 }
             },
@@ -588,18 +845,173 @@ impl <B: Backend> Renderer<B> {
                 location: 5,
                 binding: 0,
                 element: pso::Element {
-                    format: format::Format::R8Uint,
+                    format: format::Format::Rgba8Uint,
+                    offset: (
+                        size_of::<[f32; 3]>()
+                        + size_of::<[u16; 2]>()
+                        + size_of::<[u16; 2]>()
+                        + size_of::<[i16; 4]>()
+                        + size_of::<[i16; 2]>()
+                        + size_of::<i16>()
+                        + size_of::<u8>()
+                    ) as u32,
+                }
+            },
+            pso::AttributeDesc {
+                location: 6,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::R16Sint,
+                    offset: (
+                        size_of::<[f32; 3]>()
+                        + size_of::<[u16; 2]>()
+                        + size_of::<[u16; 2]>()
+                        + size_of::<[i16; 4]>()
+                        + size_of::<[i16; 2]>()
+                    ) as u32,
+                }
+            },
+            pso::AttributeDesc {
+                location: 7,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rg16Uint,
+                    offset: (
+                        size_of::<[f32; 3]>()
+                        + size_of::<[u16; 2]>()
+                    ) as u32,
+                }
+            },
+            pso::AttributeDesc {
+                location: 8,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rg8Uint,
                     offset: (
                         size_of::<[f32; 3]>()
                         + size_of::<[u16; 2]>()
+                        + size_of::<[u16; 2]>()
                         + size_of::<[i16; 4]>()
                         + size_of::<[i16; 2]>()
+                        + size_of::<i16>()
                         + size_of::<u8>()
+                        + size_of::<[u8; 4]>()
                     ) as u32,
                 }
             },
         ];
 
+        // Alias models blend between two poses, the current frame
+        // is bound first and the next frame second. The texture
+        // coordinates are shared by every pose.
+        let alias_vertex_buffers = vec![
+            pso::VertexBufferDesc {
+                binding: 0,
+                stride: size_of::<alias::AliasVertex>() as u32,
+                rate: pso::VertexInputRate::Vertex,
+            },
+            pso::VertexBufferDesc {
+                binding: 1,
+                stride: size_of::<alias::AliasVertex>() as u32,
+                rate: pso::VertexInputRate::Vertex,
+            },
+            pso::VertexBufferDesc {
+                binding: 2,
+                stride: size_of::<alias::AliasTex>() as u32,
+                rate: pso::VertexInputRate::Vertex,
+            },
+        ];
+        let alias_attributes = vec![
+            pso::AttributeDesc {
+                location: 0,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rgb32Sfloat,
+                    offset: 0,
+                }
+            },
+            pso::AttributeDesc {
+                location: 1,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rgb32Sfloat,
+                    offset: size_of::<[f32; 3]>() as u32,
+                }
+            },
+            pso::AttributeDesc {
+                location: 2,
+                binding: 1,
+                element: pso::Element {
+                    format: format::Format::Rgb32Sfloat,
+                    offset: 0,
+                }
+            },
+            pso::AttributeDesc {
+                location: 3,
+                binding: 1,
+                element: pso::Element {
+                    format: format::Format::Rgb32Sfloat,
+                    offset: size_of::<[f32; 3]>() as u32,
+                }
+            },
+            pso::AttributeDesc {
+                location: 4,
+                binding: 2,
+                element: pso::Element {
+                    format: format::Format::Rg32Sfloat,
+                    offset: 0,
+                }
+            },
+        ];
+
+        let sprite_vertex_buffers = vec![pso::VertexBufferDesc {
+            binding: 0,
+            stride: size_of::<sprite::SpriteVertex>() as u32,
+            rate: pso::VertexInputRate::Vertex,
+        }];
+        let sprite_attributes = vec![
+            pso::AttributeDesc {
+                location: 0,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rg32Sfloat,
+                    offset: 0,
+                }
+            },
+            pso::AttributeDesc {
+                location: 1,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rg32Sfloat,
+                    offset: size_of::<[f32; 2]>() as u32,
+                }
+            },
+        ];
+
+        let overlay_vertex_buffers = vec![pso::VertexBufferDesc {
+            binding: 0,
+            stride: size_of::<overlay::OverlayVertex>() as u32,
+            rate: pso::VertexInputRate::Vertex,
+        }];
+        let overlay_attributes = vec![
+            pso::AttributeDesc {
+                location: 0,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rg32Sfloat,
+                    offset: 0,
+                }
+            },
+            pso::AttributeDesc {
+                location: 1,
+                binding: 0,
+                element: pso::Element {
+                    format: format::Format::Rg32Sfloat,
+                    offset: size_of::<[f32; 2]>() as u32,
+                }
+            },
+        ];
+
         let rasterizer = Rasterizer {
             depth_clamping: false,
             polygon_mode: pso::PolygonMode::Fill,
@@ -609,6 +1021,12 @@ impl <B: Backend> Renderer<B> {
             conservative: false,
         };
 
+        // Sprites can be seen from either side
+        let sprite_rasterizer = Rasterizer {
+            cull_face: pso::Face::NONE,
+            .. rasterizer.clone()
+        };
+
         let depth_stencil = pso::DepthStencilDesc {
             depth: pso::DepthTest::On {
                 fun: pso::Comparison::LessEqual,
@@ -622,6 +1040,27 @@ impl <B: Backend> Renderer<B> {
             logic_op: Some(pso::LogicOp::Copy),
             targets: vec![pso::ColorBlendDesc(pso::ColorMask::ALL, pso::BlendState::Off)],
         };
+
+        // Liquids are drawn after the rest of the level so they
+        // can be see through
+        let turb_depth_stencil = pso::DepthStencilDesc {
+            depth: pso::DepthTest::On {
+                fun: pso::Comparison::LessEqual,
+                write: false
+            },
+            depth_bounds: false,
+            stencil: pso::StencilTest::Off,
+        };
+        // The overlay is drawn over everything else
+        let overlay_depth_stencil = pso::DepthStencilDesc {
+            depth: pso::DepthTest::Off,
+            depth_bounds: false,
+            stencil: pso::StencilTest::Off,
+        };
+        let turb_blender = pso::BlendDesc {
+            logic_op: None,
+            targets: vec![pso::ColorBlendDesc(pso::ColorMask::ALL, pso::BlendState::ALPHA)],
+        };
         let baked_states = pso::BakedStates::default();
 
         let descriptor_set_layouts = unsafe { vec![
@@ -683,32 +1122,99 @@ impl <B: Backend> Renderer<B> {
                         stage_flags: pso::ShaderStageFlags::FRAGMENT,
                         immutable_samplers: false,
                     },
-                ],
-                Vec::<B::Sampler>::new(),
-            ).unwrap(),
-        ] };
-        let mut descriptor_pool = unsafe {
-            device.create_descriptor_pool(
-                1,
-                &[
-                    pso::DescriptorRangeDesc {
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 8,
+                        ty: pso::DescriptorType::UniformBufferDynamic,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::VERTEX,
+                        immutable_samplers: false,
+                    },
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 9,
                         ty: pso::DescriptorType::SampledImage,
-                        count: 4,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
                     },
-                    pso::DescriptorRangeDesc {
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 10,
                         ty: pso::DescriptorType::Sampler,
-                        count: 4,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
                     },
-                ],
-                pso::DescriptorPoolCreateFlags::empty(),
-            ).unwrap()
-        };
-
-        let descriptor_set = unsafe {
-            descriptor_pool.allocate_set(&descriptor_set_layouts[0]).unwrap()
-        };
-
-        unsafe {
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 11,
+                        ty: pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 12,
+                        ty: pso::DescriptorType::Sampler,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 13,
+                        ty: pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 14,
+                        ty: pso::DescriptorType::Sampler,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 15,
+                        ty: pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    pso::DescriptorSetLayoutBinding {
+                        binding: 16,
+                        ty: pso::DescriptorType::Sampler,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                ],
+                Vec::<B::Sampler>::new(),
+            ).unwrap(),
+        ] };
+        let mut descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                1,
+                &[
+                    pso::DescriptorRangeDesc {
+                        ty: pso::DescriptorType::SampledImage,
+                        count: 8,
+                    },
+                    pso::DescriptorRangeDesc {
+                        ty: pso::DescriptorType::Sampler,
+                        count: 8,
+                    },
+                    pso::DescriptorRangeDesc {
+                        ty: pso::DescriptorType::UniformBufferDynamic,
+                        count: 1,
+                    },
+                ],
+                pso::DescriptorPoolCreateFlags::empty(),
+            ).unwrap()
+        };
+
+        let descriptor_set = unsafe {
+            descriptor_pool.allocate_set(&descriptor_set_layouts[0]).unwrap()
+        };
+
+        unsafe {
             device.write_descriptor_sets(vec![
                 pso::DescriptorSetWrite {
                     set: &descriptor_set,
@@ -778,6 +1284,83 @@ impl <B: Backend> Renderer<B> {
                         &*level.texture.sampler,
                     )),
                 },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 8,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Buffer(
+                        &*light_style_buffer.buffer,
+                        Some(0) .. Some(LIGHT_STYLE_SIZE),
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 9,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*level.sky_box.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 10,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*level.sky_box.sampler,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 11,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*models.skins.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 12,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*models.skins.sampler,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 13,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*sprites.atlas.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 14,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*sprites.atlas.sampler,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 15,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*overlay.atlas.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &descriptor_set,
+                    binding: 16,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*overlay.atlas.sampler,
+                    )),
+                },
             ])
         }
 
@@ -787,6 +1370,7 @@ impl <B: Backend> Renderer<B> {
                 &[
                     (pso::ShaderStageFlags::VERTEX, 0..4*4),
                     (pso::ShaderStageFlags::FRAGMENT, 4*4..4*4+4),
+                    (pso::ShaderStageFlags::VERTEX, 4*4+4..4*4+12),
                 ],
             )
                 .unwrap()
@@ -818,9 +1402,113 @@ impl <B: Backend> Renderer<B> {
             }
         };
 
-        let depth_pipeline = {
+        let turb_pipeline = {
             let desc = pso::GraphicsPipelineDesc {
-                shaders: depth_shaders,
+                shaders: t_shaders,
+                rasterizer: rasterizer.clone(),
+                vertex_buffers: vertex_buffers.clone(),
+                attributes: attributes.clone(),
+                input_assembler: pso::InputAssemblerDesc::new(hal::Primitive::TriangleList),
+                blender: turb_blender,
+                depth_stencil: turb_depth_stencil,
+                multisampling: None,
+                baked_states: baked_states.clone(),
+                layout: &pipeline_layout,
+                subpass: pass::Subpass {
+                    index: 0,
+                    main_pass: &render_pass,
+                },
+                flags: pso::PipelineCreationFlags::empty(),
+                parent: pso::BasePipeline::None,
+            };
+
+            unsafe {
+                device.create_graphics_pipeline(&desc, None)
+                    .unwrap()
+            }
+        };
+
+        let alias_pipeline = {
+            let desc = pso::GraphicsPipelineDesc {
+                shaders: a_shaders,
+                rasterizer: rasterizer.clone(),
+                vertex_buffers: alias_vertex_buffers,
+                attributes: alias_attributes,
+                input_assembler: pso::InputAssemblerDesc::new(hal::Primitive::TriangleList),
+                blender: blender.clone(),
+                depth_stencil,
+                multisampling: None,
+                baked_states: baked_states.clone(),
+                layout: &pipeline_layout,
+                subpass: pass::Subpass {
+                    index: 0,
+                    main_pass: &render_pass,
+                },
+                flags: pso::PipelineCreationFlags::empty(),
+                parent: pso::BasePipeline::None,
+            };
+
+            unsafe {
+                device.create_graphics_pipeline(&desc, None)
+                    .unwrap()
+            }
+        };
+
+        let sprite_pipeline = {
+            let desc = pso::GraphicsPipelineDesc {
+                shaders: sp_shaders,
+                rasterizer: sprite_rasterizer.clone(),
+                vertex_buffers: sprite_vertex_buffers,
+                attributes: sprite_attributes,
+                input_assembler: pso::InputAssemblerDesc::new(hal::Primitive::TriangleList),
+                blender: blender.clone(),
+                depth_stencil,
+                multisampling: None,
+                baked_states: baked_states.clone(),
+                layout: &pipeline_layout,
+                subpass: pass::Subpass {
+                    index: 0,
+                    main_pass: &render_pass,
+                },
+                flags: pso::PipelineCreationFlags::empty(),
+                parent: pso::BasePipeline::None,
+            };
+
+            unsafe {
+                device.create_graphics_pipeline(&desc, None)
+                    .unwrap()
+            }
+        };
+
+        let overlay_pipeline = {
+            let desc = pso::GraphicsPipelineDesc {
+                shaders: o_shaders,
+                rasterizer: sprite_rasterizer.clone(),
+                vertex_buffers: overlay_vertex_buffers,
+                attributes: overlay_attributes,
+                input_assembler: pso::InputAssemblerDesc::new(hal::Primitive::TriangleList),
+                blender: blender.clone(),
+                depth_stencil: overlay_depth_stencil,
+                multisampling: None,
+                baked_states: baked_states.clone(),
+                layout: &pipeline_layout,
+                subpass: pass::Subpass {
+                    index: 0,
+                    main_pass: &render_pass,
+                },
+                flags: pso::PipelineCreationFlags::empty(),
+                parent: pso::BasePipeline::None,
+            };
+
+            unsafe {
+                device.create_graphics_pipeline(&desc, None)
+                    .unwrap()
+            }
+        };
+
+        let sky_box_pipeline = {
+            let desc = pso::GraphicsPipelineDesc {
+                shaders: sb_shaders,
                 rasterizer: rasterizer.clone(),
                 vertex_buffers: vertex_buffers.clone(),
                 attributes: attributes.clone(),
@@ -875,11 +1563,22 @@ impl <B: Backend> Renderer<B> {
             device.destroy_shader_module(fsm);
             device.destroy_shader_module(s_vsm);
             device.destroy_shader_module(s_fsm);
+            device.destroy_shader_module(sb_fsm);
+            device.destroy_shader_module(t_fsm);
+            device.destroy_shader_module(a_vsm);
+            device.destroy_shader_module(a_fsm);
+            device.destroy_shader_module(sp_vsm);
+            device.destroy_shader_module(sp_fsm);
+            device.destroy_shader_module(o_vsm);
+            device.destroy_shader_module(o_fsm);
         }
 
         Ok(Renderer {
-            pak: pak,
+            files: files,
             level: ManuallyDrop::new(level),
+            models: ManuallyDrop::new(models),
+            sprites: ManuallyDrop::new(sprites),
+            overlay: ManuallyDrop::new(overlay),
             display_size: size,
             frame: 0,
 
@@ -890,6 +1589,13 @@ impl <B: Backend> Renderer<B> {
                 rot_y: cgmath::Rad(0.0),
                 rot_x: cgmath::Rad(::std::f32::consts::PI),
             },
+            hud: Hud::new(),
+            light_styles,
+            water_alpha: 1.0,
+            fov: 90.0,
+            draw_world: true,
+            screenshot: None,
+            time: 0.0,
 
             adapter,
             surface,
@@ -900,11 +1606,13 @@ impl <B: Backend> Renderer<B> {
             gfx: ManuallyDrop::new(GfxState {
                 allocator,
 
+                format,
                 render_pass,
                 framebuffers,
                 frame_images,
                 depth_images,
                 swap_chain: Some(swap_chain),
+                screenshots,
 
                 free_acquire_semaphore,
                 image_acquire_semaphores,
@@ -915,8 +1623,12 @@ impl <B: Backend> Renderer<B> {
                 cmd_buffers,
 
                 pipeline,
-                depth_pipeline,
                 sky_pipeline,
+                sky_box_pipeline,
+                turb_pipeline,
+                alias_pipeline,
+                sprite_pipeline,
+                overlay_pipeline,
                 pipeline_layout,
 
                 descriptor_set_layouts,
@@ -925,6 +1637,9 @@ impl <B: Backend> Renderer<B> {
 
                 texture_colour_map,
                 texture_palette_map,
+
+                light_style_buffer,
+                light_style_stride,
             }),
         })
     }
@@ -939,6 +1654,7 @@ impl <B: Backend> Renderer<B> {
         width: u32, height: u32,
     ) -> (
         B::Swapchain,
+        bool,
         Vec<B::Framebuffer>,
         Vec<(B::Image, B::ImageView)>,
         Vec<DepthImage<B>>,
@@ -958,6 +1674,11 @@ impl <B: Backend> Renderer<B> {
             height,
         });
         // swap_config.present_mode = hal::PresentMode::Immediate;
+        // Frames are copied out of the swap chain for screenshots
+        let screenshots = caps.usage.contains(image::Usage::TRANSFER_SRC);
+        if screenshots {
+            swap_config.image_usage |= image::Usage::TRANSFER_SRC;
+        }
         let extent = swap_config.extent.to_extent();
 
         let (swap_chain, images) = unsafe { device.create_swapchain(surface, swap_config, previous) }
@@ -998,14 +1719,18 @@ impl <B: Backend> Renderer<B> {
                 .collect();
             (pairs, depth_images, fbos)
         };
-        (swap_chain, framebuffers, frame_images, depth_images)
+        (swap_chain, screenshots, framebuffers, frame_images, depth_images)
     }
 
+    // Returns where a screenshot was saved to and whether it
+    // worked when one was taken this frame
     pub fn draw(&mut self,
         delta: f32,
         display_size: (u32, u32),
-    ) {
+        console: &Console,
+    ) -> Option<(PathBuf, error::Result<()>)> {
         let gfx = &mut *self.gfx;
+        self.time += delta / 60.0;
         if self.display_size != display_size || self.recreate_swapchain {
             self.recreate_swapchain = false;
             self.display_size = display_size;
@@ -1022,13 +1747,14 @@ impl <B: Backend> Renderer<B> {
                     depth.destroy(&self.device, &mut gfx.allocator);
                 }
             }
-            let (swap_chain, framebuffers, frame_images, depth_images) = Self::make_swapchain(
+            let (swap_chain, screenshots, framebuffers, frame_images, depth_images) = Self::make_swapchain(
                 &mut self.adapter, &self.device, &mut gfx.allocator, &mut self.surface, &gfx.render_pass,
                 gfx.swap_chain.take(),
                 display_size.0, display_size.1
             );
 
             gfx.swap_chain = Some(swap_chain);
+            gfx.screenshots = screenshots;
             gfx.framebuffers = framebuffers;
             gfx.frame_images = frame_images;
             gfx.depth_images = depth_images;
@@ -1049,7 +1775,7 @@ impl <B: Backend> Renderer<B> {
                 Ok(i) => i.0 as usize,
                 Err(_) => {
                     self.recreate_swapchain = true;
-                    return;
+                    return None;
                 }
             }
         };
@@ -1069,15 +1795,38 @@ impl <B: Backend> Renderer<B> {
             gfx.cmd_pools[frame_idx].reset();
         }
 
-        let cmd_buffer = &mut gfx.cmd_buffers[frame_idx];
+        // The fence above guarantees the previous use of this
+        // frame's slot has finished
+        let light_style_offset = frame_idx as u64 * gfx.light_style_stride;
+        let light_styles = self.light_styles.values(self.time);
         unsafe {
+            let start = gfx.light_style_buffer.memory.range.start + light_style_offset;
+            let mut data_target = self.device.acquire_mapping_writer::<f32>(
+                gfx.light_style_buffer.memory.memory(),
+                start .. start + LIGHT_STYLE_SIZE,
+            ).unwrap();
+            data_target[..lightstyle::MAX_LIGHT_STYLES].copy_from_slice(&light_styles);
+            self.device.release_mapping_writer(data_target).unwrap();
+        }
+
+        self.hud.update(delta);
+        self.overlay.begin(self.display_size);
+        self.hud.draw(&mut self.overlay, self.camera.position());
+        hud::draw_console(console, &mut self.overlay, self.time);
+
+        let cmd_buffer = &mut gfx.cmd_buffers[frame_idx];
+        let saved = unsafe {
             cmd_buffer.begin(false);
             cmd_buffer.set_viewports(0, &[viewport.clone()]);
             cmd_buffer.set_scissors(0, &[viewport.rect]);
 
+            // The field of view is horizontal like Quake's so wider
+            // windows see more instead of less
+            let aspect = self.display_size.0 as f32 / self.display_size.1 as f32;
+            let fov_x: cgmath::Rad<f32> = cgmath::Deg(self.fov.max(1.0).min(179.0)).into();
             let p_matrix: cgmath::Matrix4<f32> = cgmath::PerspectiveFov {
-                fovy: cgmath::Deg(75.0).into(),
-                aspect: self.display_size.0 as f32 / self.display_size.1 as f32,
+                fovy: cgmath::Rad(2.0 * ((fov_x.0 / 2.0).tan() / aspect).atan()),
+                aspect: aspect,
                 near: 0.1,
                 far: 10_000.0,
             }.into();
@@ -1109,20 +1858,77 @@ impl <B: Backend> Renderer<B> {
                     &gfx.pipeline_layout,
                     0,
                     Some(&gfx.descriptor_set),
-                    &[],
+                    &[light_style_offset as u32],
                 );
 
-                self.level.draw(
+                if self.draw_world {
+                    self.level.draw(
+                        delta,
+                        self.camera.position(),
+                        p_matrix * u_matrix,
+                        self.water_alpha.max(0.0).min(1.0),
+                        &self.device,
+                        &gfx.pipeline_layout,
+                        &gfx.pipeline,
+                        &gfx.sky_pipeline,
+                        &gfx.sky_box_pipeline,
+                        &mut encoder,
+                    ).unwrap();
+                }
+
+                let level = &self.level;
+                self.models.draw(
                     delta,
+                    p_matrix * u_matrix,
+                    &light_styles,
+                    |pos, styles| level.light_point(pos, styles),
+                    &gfx.pipeline_layout,
+                    &gfx.alias_pipeline,
+                    &mut encoder,
+                );
+                self.sprites.draw(
+                    delta,
+                    p_matrix * u_matrix,
+                    &self.camera,
+                    &gfx.pipeline_layout,
+                    &gfx.sprite_pipeline,
+                    &mut encoder,
+                );
+
+                if self.draw_world {
+                    self.level.draw_liquids(
+                        p_matrix * u_matrix,
+                        &gfx.pipeline_layout,
+                        &gfx.turb_pipeline,
+                        &mut encoder,
+                    );
+                }
+
+                self.overlay.draw(
                     &self.device,
+                    frame_idx,
                     &gfx.pipeline_layout,
-                    &gfx.pipeline,
-                    &gfx.depth_pipeline,
-                    &gfx.sky_pipeline,
+                    &gfx.overlay_pipeline,
                     &mut encoder,
-                ).unwrap();
+                );
             }
 
+            let mut saved = None;
+            let screenshot = match self.screenshot.take() {
+                Some(path) if gfx.screenshots => {
+                    let image = &gfx.frame_images[swap_image].0;
+                    let buffer = copy_frame(&self.device, &mut gfx.allocator, cmd_buffer, image, self.display_size);
+                    Some((path, buffer))
+                },
+                // The swap chain was recreated without support
+                // since the screenshot was requested
+                Some(path) => {
+                    saved = Some((path, Err("Screenshots aren't supported by this display".into())));
+                    None
+                },
+                None => None,
+            };
+
             cmd_buffer.finish();
 
             let submission = Submission {
@@ -1142,30 +1948,112 @@ impl <B: Backend> Renderer<B> {
             ) {
                 self.recreate_swapchain = true;
             }
-        }
+
+            if let Some((path, buffer)) = screenshot {
+                self.device
+                    .wait_for_fence(&gfx.submission_complete_fences[frame_idx], !0)
+                    .expect("Failed to wait for fence");
+                let result = save_frame(&self.device, &buffer, gfx.format, self.display_size, &path);
+                buffer.destroy(&self.device, &mut gfx.allocator);
+                saved = Some((path, result));
+            }
+            saved
+        };
 
         self.frame = self.frame.wrapping_add(1);
+        saved
+    }
+
+    // Saves the next frame drawn to a tga file, `draw` returns
+    // the result once it has been written
+    pub fn screenshot(&mut self, path: PathBuf) -> error::Result<()> {
+        if !self.gfx.screenshots {
+            bail!("Screenshots aren't supported by this display");
+        }
+        if self.screenshot.is_some() {
+            bail!("A screenshot is already being saved");
+        }
+        self.screenshot = Some(path);
+        Ok(())
+    }
+
+    // The doors, lifts and other brush entities of the level,
+    // the game moves these by changing their transforms.
+    pub fn brush_entities(&mut self) -> &mut [BrushEntity] {
+        &mut self.level.brush_entities
+    }
+
+    // The monsters, items and other entities drawn with
+    // alias models
+    pub fn alias_entities(&mut self) -> &mut [AliasEntity] {
+        &mut self.models.entities
+    }
+
+    // Sprites in the level, the game can add its own effects
+    // using the sprites found with `find_sprite`
+    pub fn sprite_entities(&mut self) -> &mut Vec<SpriteEntity> {
+        &mut self.sprites.entities
+    }
+
+    pub fn find_sprite(&self, name: &str) -> Option<usize> {
+        self.sprites.find(name)
+    }
+
+    // Used by the game to show the alternate frames of the
+    // animated textures on a brush model, like a pressed button
+    pub fn set_alternate_frames(&mut self, model: usize, alternate: bool) {
+        self.level.set_alternate_frames(model, alternate);
     }
 
     pub fn change_level(
         &mut self,
-        level: bsp::BspFile,
+        level: Rc<bsp::BspFile>,
     ) -> error::Result<()>
     {
-        use std::ptr;
         unsafe {
             let gfx = &mut *self.gfx;
             self.device.wait_idle().unwrap();
-            let old_level = ManuallyDrop::into_inner(ptr::read(&self.level));
-            old_level.destroy(&self.device, &mut gfx.allocator);
+            // The new level is built before the old one is destroyed
+            // so a level that fails to load leaves the old one drawn
             let frame_idx = self.frame as usize % gfx.submission_complete_fences.len();
-            let level = qmap::QMap::new(
+            let bsp = level.clone();
+            let sky_box = load_sky_box(&self.files, &level, &gfx.allocator.limits);
+            let models = alias::AliasModels::new(
+                &self.files, &level,
+                &self.device,
+                &mut self.queue_group.queues[0],
+                &mut gfx.cmd_pools[frame_idx],
+                &mut gfx.allocator
+            )?;
+            let sprites = match sprite::Sprites::new(
+                &self.files, &level,
+                &self.device,
+                &mut self.queue_group.queues[0],
+                &mut gfx.cmd_pools[frame_idx],
+                &mut gfx.allocator
+            ) {
+                Ok(v) => v,
+                Err(err) => {
+                    models.destroy(&self.device, &mut gfx.allocator);
+                    return Err(err);
+                },
+            };
+            let level = match qmap::QMap::new(
                 level,
+                sky_box.as_ref(),
                 &mut self.adapter, &self.device,
                 &mut self.queue_group.queues[0],
                 &mut gfx.cmd_pools[frame_idx],
                 &mut gfx.allocator
-            )?;
+            ) {
+                Ok(v) => v,
+                Err(err) => {
+                    models.destroy(&self.device, &mut gfx.allocator);
+                    sprites.destroy(&self.device, &mut gfx.allocator);
+                    return Err(err);
+                },
+            };
+            self.light_styles.setup_level(&bsp.entities);
 
             self.device.write_descriptor_sets(vec![
                 pso::DescriptorSetWrite {
@@ -1202,9 +2090,65 @@ impl <B: Backend> Renderer<B> {
                         &*level.texture.sampler,
                     )),
                 },
+                pso::DescriptorSetWrite {
+                    set: &gfx.descriptor_set,
+                    binding: 9,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*level.sky_box.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &gfx.descriptor_set,
+                    binding: 10,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*level.sky_box.sampler,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &gfx.descriptor_set,
+                    binding: 11,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*models.skins.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &gfx.descriptor_set,
+                    binding: 12,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*models.skins.sampler,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &gfx.descriptor_set,
+                    binding: 13,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Image(
+                        &*sprites.atlas.image_view,
+                        image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                pso::DescriptorSetWrite {
+                    set: &gfx.descriptor_set,
+                    binding: 14,
+                    array_offset: 0,
+                    descriptors: Some(pso::Descriptor::Sampler(
+                        &*sprites.atlas.sampler,
+                    )),
+                },
             ]);
 
-            self.level = ManuallyDrop::new(level);
+            let old_level = mem::replace(&mut self.level, ManuallyDrop::new(level));
+            ManuallyDrop::into_inner(old_level).destroy(&self.device, &mut gfx.allocator);
+            let old_models = mem::replace(&mut self.models, ManuallyDrop::new(models));
+            ManuallyDrop::into_inner(old_models).destroy(&self.device, &mut gfx.allocator);
+            let old_sprites = mem::replace(&mut self.sprites, ManuallyDrop::new(sprites));
+            ManuallyDrop::into_inner(old_sprites).destroy(&self.device, &mut gfx.allocator);
             self.device.wait_idle().unwrap();
             Ok(())
         }
@@ -1219,14 +2163,25 @@ impl <B: Backend> Drop for Renderer<B> {
             let mut gfx = ManuallyDrop::into_inner(ptr::read(&mut self.gfx));
             let level = ManuallyDrop::into_inner(ptr::read(&mut self.level));
             level.destroy(&self.device, &mut gfx.allocator);
+            let models = ManuallyDrop::into_inner(ptr::read(&mut self.models));
+            models.destroy(&self.device, &mut gfx.allocator);
+            let sprites = ManuallyDrop::into_inner(ptr::read(&mut self.sprites));
+            sprites.destroy(&self.device, &mut gfx.allocator);
+            let overlay = ManuallyDrop::into_inner(ptr::read(&mut self.overlay));
+            overlay.destroy(&self.device, &mut gfx.allocator);
 
             gfx.texture_colour_map.destroy(&self.device, &mut gfx.allocator);
             gfx.texture_palette_map.destroy(&self.device, &mut gfx.allocator);
+            gfx.light_style_buffer.destroy(&self.device, &mut gfx.allocator);
 
             self.device.destroy_pipeline_layout(gfx.pipeline_layout);
             self.device.destroy_graphics_pipeline(gfx.pipeline);
             self.device.destroy_graphics_pipeline(gfx.sky_pipeline);
-            self.device.destroy_graphics_pipeline(gfx.depth_pipeline);
+            self.device.destroy_graphics_pipeline(gfx.sky_box_pipeline);
+            self.device.destroy_graphics_pipeline(gfx.turb_pipeline);
+            self.device.destroy_graphics_pipeline(gfx.alias_pipeline);
+            self.device.destroy_graphics_pipeline(gfx.sprite_pipeline);
+            self.device.destroy_graphics_pipeline(gfx.overlay_pipeline);
 
             self.device.destroy_descriptor_pool(gfx.descriptor_pool);
             for d in gfx.descriptor_set_layouts {
@@ -1263,4 +2218,112 @@ impl <B: Backend> Drop for Renderer<B> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// Levels can replace their sky texture with a sky box
+// named by the sky key of worldspawn
+fn load_sky_box(files: &FileSystem, level: &bsp::BspFile, limits: &hal::Limits) -> Option<skybox::SkyBox> {
+    let name = level.worldspawn()?.get("sky")?;
+    match skybox::SkyBox::load(files, name, limits) {
+        Ok(sky_box) => Some(sky_box),
+        Err(err) => {
+            warn!("Failed to load sky box {}: {}", name, err);
+            None
+        },
+    }
+}
+
+// Records a copy of a drawn swap chain image into a buffer
+// the CPU can read
+unsafe fn copy_frame<B: Backend>(
+    device: &B::Device,
+    allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>,
+    cmd_buffer: &mut CommandBuffer<B, hal::Graphics, command::MultiShot>,
+    image: &B::Image,
+    size: (u32, u32),
+) -> BufferBundle<B> {
+    let buffer = BufferBundle::new(
+        device,
+        allocator,
+        (size.0 * size.1 * 4) as u64,
+        hal::buffer::Usage::TRANSFER_DST,
+        hal::memory::Properties::CPU_VISIBLE
+    );
+    let range = image::SubresourceRange {
+        aspects: format::Aspects::COLOR,
+        levels: 0..1,
+        layers: 0..1,
+    };
+    cmd_buffer.pipeline_barrier(
+        pso::PipelineStage::COLOR_ATTACHMENT_OUTPUT .. pso::PipelineStage::TRANSFER,
+        hal::memory::Dependencies::empty(),
+        &[hal::memory::Barrier::Image {
+            states: (image::Access::COLOR_ATTACHMENT_WRITE, image::Layout::Present)
+                .. (image::Access::TRANSFER_READ, image::Layout::TransferSrcOptimal),
+            target: image,
+            families: None,
+            range: range.clone(),
+        }],
+    );
+    cmd_buffer.copy_image_to_buffer(
+        image,
+        image::Layout::TransferSrcOptimal,
+        &buffer.buffer,
+        &[command::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_width: size.0,
+            buffer_height: size.1,
+            image_layers: image::SubresourceLayers {
+                aspects: format::Aspects::COLOR,
+                level: 0,
+                layers: 0..1,
+            },
+            image_offset: image::Offset { x: 0, y: 0, z: 0 },
+            image_extent: image::Extent {
+                width: size.0,
+                height: size.1,
+                depth: 1,
+            },
+        }],
+    );
+    cmd_buffer.pipeline_barrier(
+        pso::PipelineStage::TRANSFER .. pso::PipelineStage::BOTTOM_OF_PIPE,
+        hal::memory::Dependencies::empty(),
+        &[hal::memory::Barrier::Image {
+            states: (image::Access::TRANSFER_READ, image::Layout::TransferSrcOptimal)
+                .. (image::Access::empty(), image::Layout::Present),
+            target: image,
+            families: None,
+            range: range,
+        }],
+    );
+    buffer
+}
+
+// Writes a frame copied by `copy_frame` to a tga file once
+// the copy has finished
+unsafe fn save_frame<B: Backend>(
+    device: &B::Device,
+    buffer: &BufferBundle<B>,
+    format: format::Format,
+    size: (u32, u32),
+    path: &Path,
+) -> error::Result<()> {
+    let len = (size.0 * size.1 * 4) as usize;
+    let reader = device.acquire_mapping_reader::<u8>(buffer.memory.memory(), buffer.memory.range.clone()).unwrap();
+    let mut data = reader[..len].to_vec();
+    device.release_mapping_reader(reader);
+
+    if format.base_format().0 == format::SurfaceType::B8_G8_R8_A8 {
+        for pixel in data.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+    let image = tga::Image {
+        width: size.0,
+        height: size.1,
+        data: data,
+    };
+    image.write(&mut File::create(path)?)?;
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use log::*;
+
+use hal::{
+    Backend,
+    Device,
+    CommandPool,
+    pso,
+    command,
+    queue,
+};
+
+use crate::error;
+use crate::wad;
+use crate::vfs::FileSystem;
+use super::atlas;
+use super::alloc;
+use super::{BufferBundle, ImageBundle, upload_atlas};
+
+// The overlay is laid out on a virtual screen of at least this
+// size which is scaled up to fill the window
+pub const VIRTUAL_WIDTH: f32 = 320.0;
+pub const VIRTUAL_HEIGHT: f32 = 200.0;
+
+pub const CHAR_SIZE: f32 = 8.0;
+
+// Pictures loaded from their own files instead of gfx.wad
+const LMP_PICTURES: &[&str] = &[
+    "gfx/conback.lmp",
+];
+
+// The most quads that can be drawn in a single frame
+const MAX_QUADS: usize = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(super) struct OverlayVertex {
+    // Position on the virtual screen, y is down
+    position: [f32; 2],
+    tex: [f32; 2],
+}
+
+// Pictures and text drawn on top of the level. Everything queued
+// with the draw_* methods since the last `begin` is drawn at the
+// end of the frame.
+pub struct Overlay<B: Backend> {
+    pictures: HashMap<String, atlas::Rect>,
+    // Location of the 16x16 grid of characters in the atlas
+    chars: atlas::Rect,
+    verts: Vec<OverlayVertex>,
+    size: (f32, f32),
+
+    // A slot of vertices per frame in flight
+    buffer: BufferBundle<B>,
+    buffer_stride: u64,
+    pub atlas: ImageBundle<B>,
+}
+
+impl <B> Overlay<B>
+    where B: Backend,
+{
+    pub fn new(
+        files: &FileSystem,
+        frames_in_flight: usize,
+        device: &B::Device,
+        queue: &mut queue::CommandQueue<B, hal::Graphics>,
+        command_pool: &mut CommandPool<B, hal::Graphics>,
+        allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>,
+    ) -> error::Result<Overlay<B>>
+    {
+        let gfx = wad::WadFile::parse(files.file("gfx.wad")?.to_vec())?;
+
+        let mut atlas = atlas::TextureAtlas::new_padded(
+            super::ATLAS_SIZE as i32,
+            super::ATLAS_SIZE as i32,
+            1
+        );
+        let mut atlas_data = vec![255u8; (super::ATLAS_SIZE * super::ATLAS_SIZE) as usize];
+
+        // The font uses colour 0 for its background instead of 255
+        let mut conchars = gfx.picture("conchars")?;
+        conchars.data = conchars.data.iter()
+            .map(|&v| if v == 0 { 255 } else { v })
+            .collect::<Vec<_>>()
+            .into();
+        let chars = place_picture(&conchars, &mut atlas, &mut atlas_data)?;
+
+        let mut pictures = HashMap::new();
+        for lump in gfx.lumps() {
+            if lump.kind != wad::LumpType::QPic {
+                continue;
+            }
+            let picture = match gfx.picture(&lump.name) {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("Failed to load picture {}: {}", lump.name, err);
+                    continue;
+                },
+            };
+            let rect = place_picture(&picture, &mut atlas, &mut atlas_data)?;
+            pictures.insert(lump.name.clone(), rect);
+        }
+        for &name in LMP_PICTURES {
+            let picture = match load_lmp(files, name) {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("Failed to load picture {}: {}", name, err);
+                    continue;
+                },
+            };
+            let rect = place_picture(&picture, &mut atlas, &mut atlas_data)?;
+            pictures.insert(name.to_owned(), rect);
+        }
+
+        let buffer_stride = (MAX_QUADS * 6 * size_of::<OverlayVertex>()) as u64;
+        let buffer = unsafe {
+            BufferBundle::new(
+                device,
+                allocator,
+                buffer_stride * frames_in_flight as u64,
+                hal::buffer::Usage::VERTEX,
+                hal::memory::Properties::CPU_VISIBLE
+            )
+        };
+        let atlas = unsafe { upload_atlas(device, queue, command_pool, allocator, &atlas_data) };
+
+        Ok(Overlay {
+            pictures,
+            chars,
+            verts: Vec::with_capacity(MAX_QUADS * 6),
+            size: (VIRTUAL_WIDTH, VIRTUAL_HEIGHT),
+            buffer,
+            buffer_stride,
+            atlas,
+        })
+    }
+
+    // Clears the queued quads and sizes the virtual screen to
+    // match the window's aspect ratio
+    pub fn begin(&mut self, display_size: (u32, u32)) {
+        self.verts.clear();
+        let scale = (display_size.0 as f32 / VIRTUAL_WIDTH)
+            .min(display_size.1 as f32 / VIRTUAL_HEIGHT)
+            .max(1.0);
+        self.size = (
+            (display_size.0 as f32 / scale).max(VIRTUAL_WIDTH),
+            (display_size.1 as f32 / scale).max(VIRTUAL_HEIGHT),
+        );
+    }
+
+    // The size of the virtual screen
+    pub fn size(&self) -> (f32, f32) {
+        self.size
+    }
+
+    pub fn draw_picture(&mut self, name: &str, x: f32, y: f32) {
+        if let Some(&rect) = self.pictures.get(name) {
+            self.push_quad(x, y, rect.width as f32, rect.height as f32, rect);
+        }
+    }
+
+    // Draws a picture stretched to the given size
+    pub fn draw_picture_scaled(&mut self, name: &str, x: f32, y: f32, w: f32, h: f32) {
+        if let Some(&rect) = self.pictures.get(name) {
+            self.push_quad(x, y, w, h, rect);
+        }
+    }
+
+    pub fn draw_char(&mut self, x: f32, y: f32, c: u8) {
+        if c == b' ' {
+            return;
+        }
+        let size = CHAR_SIZE as i32;
+        let rect = atlas::Rect {
+            x: self.chars.x + (c % 16) as i32 * size,
+            y: self.chars.y + (c / 16) as i32 * size,
+            width: size,
+            height: size,
+        };
+        self.push_quad(x, y, CHAR_SIZE, CHAR_SIZE, rect);
+    }
+
+    pub fn draw_string(&mut self, x: f32, y: f32, text: &str) {
+        for (idx, c) in text.bytes().enumerate() {
+            self.draw_char(x + idx as f32 * CHAR_SIZE, y, c);
+        }
+    }
+
+    // Draws the part of the atlas covered by `tex` stretched
+    // over the quad
+    fn push_quad(&mut self, x: f32, y: f32, w: f32, h: f32, tex: atlas::Rect) {
+        if self.verts.len() + 6 > MAX_QUADS * 6 {
+            return;
+        }
+        // Corners given as 0 or 1 across the quad
+        let corner = |cx: f32, cy: f32| OverlayVertex {
+            position: [x + cx * w, y + cy * h],
+            tex: [
+                tex.x as f32 + cx * tex.width as f32,
+                tex.y as f32 + cy * tex.height as f32,
+            ],
+        };
+        self.verts.extend_from_slice(&[
+            corner(0.0, 0.0), corner(0.0, 1.0), corner(1.0, 1.0),
+            corner(0.0, 0.0), corner(1.0, 1.0), corner(1.0, 0.0),
+        ]);
+    }
+
+    pub fn draw(
+        &mut self,
+        device: &B::Device,
+        frame_idx: usize,
+        layout: &B::PipelineLayout,
+        pipeline: &B::GraphicsPipeline,
+        encoder: &mut command::RenderPassInlineEncoder<B>,
+    ) {
+        if self.verts.is_empty() {
+            return;
+        }
+        let offset = frame_idx as u64 * self.buffer_stride;
+        unsafe {
+            let start = self.buffer.memory.range.start + offset;
+            let size = (self.verts.len() * size_of::<OverlayVertex>()) as u64;
+            let mut data_target = device.acquire_mapping_writer::<OverlayVertex>(
+                self.buffer.memory.memory(),
+                start .. start + size,
+            ).unwrap();
+            data_target[..self.verts.len()].copy_from_slice(&self.verts);
+            device.release_mapping_writer(data_target).unwrap();
+
+            let matrix: [[f32; 4]; 4] = cgmath::ortho(0.0, self.size.0, 0.0, self.size.1, -1.0, 1.0).into();
+            encoder.bind_graphics_pipeline(pipeline);
+            encoder.bind_vertex_buffers(0, Some((&*self.buffer.buffer, offset)));
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 0, hal::memory::cast_slice(&[matrix]));
+            encoder.draw(0 .. self.verts.len() as u32, 0..1);
+        }
+    }
+
+    pub unsafe fn destroy(self, device: &B::Device, allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>) {
+        self.buffer.destroy(device, allocator);
+        self.atlas.destroy(device, allocator);
+    }
+}
+
+fn place_picture(
+    picture: &wad::Picture,
+    atlas: &mut atlas::TextureAtlas,
+    atlas_data: &mut [u8],
+) -> error::Result<atlas::Rect> {
+    let (width, height) = (picture.width as usize, picture.height as usize);
+    let rect = match atlas.find(width as i32, height as i32) {
+        Some(v) => v,
+        None => bail!("Out of space for pictures"),
+    };
+    for y in 0 .. height {
+        let idx = rect.x as usize + (rect.y as usize + y) * super::ATLAS_SIZE as usize;
+        atlas_data[idx .. idx + width].copy_from_slice(&picture.data[y * width .. (y + 1) * width]);
+    }
+    Ok(rect)
+}
+
+fn load_lmp(files: &FileSystem, name: &str) -> error::Result<wad::Picture> {
+    wad::parse_qpic(&files.file(name)?)
+}
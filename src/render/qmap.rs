@@ -2,8 +2,11 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::mem::size_of;
+use std::ops::Range;
+use std::rc::Rc;
 use cgmath::prelude::*;
-use cgmath::Vector3;
+use cgmath::{Vector3, Matrix4, Deg};
+use log::*;
 
 use hal::{
     Backend,
@@ -61,28 +64,50 @@ use hal::{
 use crate::error;
 use super::atlas;
 use crate::bsp;
+use crate::bitset::BitSet;
 use super::alloc;
+use super::skybox;
 use super::{BufferBundle, ImageBundle};
 
 pub struct QMap<B: Backend> {
+    bsp: Rc<bsp::BspFile>,
+
     buffer: BufferBundle<B>,
-    buffer_count: usize,
+    // Vertex range of each face of the world model, empty
+    // for faces that aren't drawn
+    face_verts: Vec<Range<u32>>,
+    // Vertex range of each model. The submodels aren't part of
+    // the leaves so they are always drawn.
+    model_verts: Vec<Range<u32>>,
+    model_turb_verts: Vec<Range<u32>>,
+    // Faces drawn with the turbulent warp of liquids
+    turb_faces: BitSet,
+    pub brush_entities: Vec<BrushEntity>,
+    // Shows the alternate frames of the world's animated textures
+    pub alternate_frames: bool,
+    vis_leaf: Option<usize>,
+    visible_faces: BitSet,
+    visible_ranges: Vec<Range<u32>>,
+    visible_turb_ranges: Vec<Range<u32>>,
     buffer_sky: BufferBundle<B>,
     buffer_sky_count: usize,
-    buffer_sky_box: BufferBundle<B>,
-    buffer_sky_box_count: usize,
 
     pub texture: ImageBundle<B>,
     pub texture_light: ImageBundle<B>,
+    // Faces of the level's sky box, a single black pixel
+    // for each face when the level uses its sky texture
+    pub sky_box: ImageBundle<B>,
+    has_sky_box: bool,
 
-    time_offset: f32,
+    time: f32,
 }
 
 impl <B> QMap<B>
     where B: Backend,
 {
     pub fn new(
-        b: bsp::BspFile,
+        b: Rc<bsp::BspFile>,
+        sky_box: Option<&skybox::SkyBox>,
         adapter: &mut Adapter<B>,
         device: &B::Device,
         queue: &mut queue::CommandQueue<B, hal::Graphics>,
@@ -108,30 +133,62 @@ impl <B> QMap<B>
             vec![0u8; size * size]
         }).collect::<Vec<_>>();
 
+        let (animations, texture_animation) = texture_animations(&b.textures);
+
+        // The frames of an animation are packed next to each other
+        // so the vertex shader can step through them by the width
+        // of the texture.
         let mut t_list = b.textures.iter()
-            .filter(|v| v.id != -1)
+            .filter(|v| v.id != -1 && !texture_animation.contains_key(&v.id))
             .map(|v| TSortable {
                 idx: v.id,
                 width: v.width,
                 height: v.height,
+                layers: 1,
             })
+            .chain(animations.iter()
+                .flat_map(|v| v.iter())
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    let tex = &b.textures[v[0] as usize];
+                    TSortable {
+                        idx: tex.id,
+                        width: tex.width,
+                        height: tex.height,
+                        layers: v.len() as u32,
+                    }
+                })
+            )
             .collect::<Vec<_>>();
         t_list.sort();
 
         for t in t_list {
-            let tex = &b.textures[t.idx as usize];
-            let rect = atlas.find(tex.width as i32, tex.height as i32).unwrap();
-            textures[tex.id as usize] = rect;
-
-            for (mip, pic) in tex.pictures.iter().enumerate().take(3) {
-                let target = &mut texture_data[mip];
-                for y in 0 .. pic.height {
-                    for x in 0 .. pic.width {
-                        let idx = (rect.x as usize >> mip) + x as usize
-                            + ((rect.y as usize >> mip) + y as usize)
-                            * (super::ATLAS_SIZE as usize >> mip);
-                        let sidx = x as usize + y as usize * pic.width as usize;
-                        target[idx] = pic.data[sidx];
+            let strip = atlas.find((t.width * t.layers) as i32, t.height as i32).unwrap();
+            let frames = match texture_animation.get(&t.idx) {
+                Some(&(anim, alternate)) => &animations[anim][alternate as usize][..],
+                None => &[t.idx][..],
+            };
+
+            for (frame, id) in frames.iter().enumerate() {
+                let tex = &b.textures[*id as usize];
+                let rect = atlas::Rect {
+                    x: strip.x + frame as i32 * t.width as i32,
+                    y: strip.y,
+                    width: t.width as i32,
+                    height: t.height as i32,
+                };
+                textures[tex.id as usize] = rect;
+
+                for (mip, pic) in tex.pictures.iter().enumerate().take(3) {
+                    let target = &mut texture_data[mip];
+                    for y in 0 .. pic.height {
+                        for x in 0 .. pic.width {
+                            let idx = (rect.x as usize >> mip) + x as usize
+                                + ((rect.y as usize >> mip) + y as usize)
+                                * (super::ATLAS_SIZE as usize >> mip);
+                            let sidx = x as usize + y as usize * pic.width as usize;
+                            target[idx] = pic.data[sidx];
+                        }
                     }
                 }
             }
@@ -146,7 +203,7 @@ impl <B> QMap<B>
                 if tex.id == -1 || tex.name == "trigger" {
                     continue;
                 }
-                if face.light_map == -1 || face.type_light == 0xFF {
+                if face.light_map == -1 || face.light_map_count() == 0 {
                     continue;
                 }
 
@@ -183,6 +240,7 @@ impl <B> QMap<B>
                     idx: face.light_map,
                     width: width as u32,
                     height: height as u32,
+                    layers: face.light_map_count() as u32,
                 });
             }
         }
@@ -192,30 +250,42 @@ impl <B> QMap<B>
         lights.sort();
         let lights = lights.into_iter()
             .map(|v| {
-                let rect = light_atlas.find(v.width as i32, v.height as i32).unwrap();
-                for y in -1.. v.height as i32 + 1 {
-                    for x in -1 .. v.width as i32 + 1 {
-                        let idx = (rect.x + x) as usize
-                            + (rect.y  + y) as usize
-                            * (super::ATLAS_SIZE as usize);
-                        let y = max(min(y, v.height as i32 - 1), 0);
-                        let x = max(min(x, v.width as i32 - 1), 0);
-                        let sidx = x as usize + y as usize * v.width as usize;
-                        light_map_data[idx] = b.light_maps[v.idx as usize + sidx];
+                // Each style's light map is stacked below the previous
+                // one with its own border to stop filtering from bleeding
+                // between them.
+                let stride = v.height as i32 + 2;
+                let rect = light_atlas.find(v.width as i32, stride * v.layers as i32 - 2).unwrap();
+                for layer in 0 .. v.layers as i32 {
+                    let offset = v.idx as usize + (layer * v.width as i32 * v.height as i32) as usize;
+                    for y in -1.. v.height as i32 + 1 {
+                        for x in -1 .. v.width as i32 + 1 {
+                            let idx = (rect.x + x) as usize
+                                + (rect.y + layer * stride + y) as usize
+                                * (super::ATLAS_SIZE as usize);
+                            let y = max(min(y, v.height as i32 - 1), 0);
+                            let x = max(min(x, v.width as i32 - 1), 0);
+                            let sidx = x as usize + y as usize * v.width as usize;
+                            light_map_data[idx] = b.light_maps[offset + sidx];
+                        }
                     }
                 }
-                (v.idx, rect)
+                (v.idx, (rect, stride))
             })
             .collect::<HashMap<_, _>>();
 
         let mut verts = vec![];
         let mut verts_sky = vec![];
-        let mut sky_texture = None;
-        let mut sky_min: Vector3<f32> = Vector3::zero();
-        let mut sky_max: Vector3<f32> = Vector3::zero();
+        let mut verts_turb = vec![];
+        let mut face_verts = vec![0..0; b.faces.len()];
+        let mut turb_faces = BitSet::new(b.faces.len());
+        let mut model_verts = vec![];
+        let mut model_turb_verts = vec![];
 
         for model in &b.models {
-            for face in &b.faces[model.faces.clone()] {
+            let model_start = verts.len() as u32;
+            let model_turb_start = verts_turb.len() as u32;
+            for face_id in model.faces.clone() {
+                let face = &b.faces[face_id];
                 let tex_info = &b.texture_info[face.texture_info];
                 let tex = &b.textures[tex_info.texture];
                 if tex.id == -1 || tex.name == "trigger" {
@@ -223,15 +293,19 @@ impl <B> QMap<B>
                 }
 
                 let (buffer, is_sky) = if tex.name.starts_with("sky") {
-                    sky_texture = Some(tex.id);
                     (&mut verts_sky, true)
+                } else if tex.name.starts_with('*') {
+                    turb_faces.set(face_id, true);
+                    (&mut verts_turb, false)
                 } else {
                     (&mut verts, false)
                 };
 
-                let (base_light, type_light) = match tex.name.chars().next() {
-                    Some('+') | Some('*') => (127, 0xFF),
-                    _ => (face.base_light, face.type_light),
+                let fullbright = tex.name.starts_with('*');
+                let light_styles = if fullbright {
+                    [0xFF; 4]
+                } else {
+                    face.styles
                 };
 
                 let mut center_x = 0.0;
@@ -252,16 +326,18 @@ impl <B> QMap<B>
                 center_y /= ec;
                 center_z /= ec;
 
-                let light = if base_light == 255 {
-                    0
-                } else { face.base_light };
+                // Faces without a light map are drawn at normal brightness
+                let light = if light_styles[0] == 0xFF || face.light_map == -1 {
+                    127
+                } else { 0 };
 
                 let mut t_offset_x = 0.0;
                 let mut t_offset_y = 0.0;
                 let mut light_s = 0.0;
                 let mut light_t = 0.0;
+                let mut light_stride = 0;
 
-                if type_light != 0xFF && face.light_map != -1 {
+                if light_styles[0] != 0xFF && face.light_map != -1 {
                     let mut min_s = f32::INFINITY;
                     let mut min_t = f32::INFINITY;
 
@@ -282,15 +358,43 @@ impl <B> QMap<B>
                     light_s = (min_s / 16.0).floor();
                     light_t = (min_t / 16.0).floor();
 
-                    let tex = &lights[&face.light_map];
+                    let (tex, stride) = lights[&face.light_map];
                     t_offset_x = tex.x as f32;
                     t_offset_y = tex.y as f32;
+                    light_stride = stride as i16;
                 }
 
                 let s = tex_info.vector_s;
                 let t = tex_info.vector_t;
 
-                let trect = textures[tex.id as usize];
+                // Animated faces start at the first frame of their own
+                // sequence and can switch over to the other one.
+                let (trect, trect_alt, tex_frames) = if is_sky {
+                    // The back half of the sky is the solid layer and the
+                    // front half is the layer that scrolls over it
+                    let rect = textures[tex.id as usize];
+                    let half = rect.width / 2;
+                    (
+                        atlas::Rect { x: rect.x + half, width: half, .. rect },
+                        atlas::Rect { width: half, .. rect },
+                        [1, 0],
+                    )
+                } else {
+                    match texture_animation.get(&tex.id) {
+                        Some(&(anim, alternate)) => {
+                            let own = &animations[anim][alternate as usize];
+                            let other = &animations[anim][!alternate as usize];
+                            let rect = textures[own[0] as usize];
+                            (
+                                rect,
+                                other.first().map_or(rect, |v| textures[*v as usize]),
+                                [own.len() as u8, other.len() as u8],
+                            )
+                        },
+                        None => (textures[tex.id as usize], textures[tex.id as usize], [1, 0]),
+                    }
+                };
+                let start = buffer.len() as u32;
 
                 for ledge in &b.ledges[face.ledges.clone()] {
                     let e = &b.edges[(*ledge).abs() as usize];
@@ -300,17 +404,6 @@ impl <B> QMap<B>
                         (e.0, e.1)
                     };
 
-                    if is_sky {
-                        for v in &[av, bv] {
-                            sky_min.x = sky_min.x.min(model.origin.x + v.x);
-                            sky_min.y = sky_min.y.min(model.origin.y + v.y);
-                            sky_min.z = sky_min.z.min(model.origin.z + v.z);
-                            sky_max.x = sky_max.x.max(model.origin.x + v.x);
-                            sky_max.y = sky_max.y.max(model.origin.y + v.y);
-                            sky_max.z = sky_max.z.max(model.origin.z + v.z);
-                        }
-                    }
-
                     let a_s = av.dot(s) + tex_info.dist_s;
                     let a_t = av.dot(t) + tex_info.dist_t;
 
@@ -325,11 +418,13 @@ impl <B> QMap<B>
 
                     buffer.push(super::Vertex {
                         position: [
-                            model.origin.x + av.x,
-                            model.origin.y + av.y,
-                            model.origin.z + av.z,
+                            av.x,
+                            av.y,
+                            av.z,
                         ],
                         tex: [trect.x as u16, trect.y as u16],
+                        tex_alt: [trect_alt.x as u16, trect_alt.y as u16],
+                        tex_frames: tex_frames,
                         tex_info: [
                             a_s as i16,
                             a_t as i16,
@@ -340,8 +435,9 @@ impl <B> QMap<B>
                             (t_offset_x + a_tx) as i16,
                             (t_offset_y + a_ty) as i16,
                         ],
+                        light_stride: light_stride,
                         light: light,
-                        light_type: type_light,
+                        light_styles: light_styles,
                     });
 
                     let b_s = bv.dot(s) + tex_info.dist_s;
@@ -358,11 +454,13 @@ impl <B> QMap<B>
 
                     buffer.push(super::Vertex {
                         position: [
-                            model.origin.x + bv.x,
-                            model.origin.y + bv.y,
-                            model.origin.z + bv.z,
+                            bv.x,
+                            bv.y,
+                            bv.z,
                         ],
                         tex: [trect.x as u16, trect.y as u16],
+                        tex_alt: [trect_alt.x as u16, trect_alt.y as u16],
+                        tex_frames: tex_frames,
                         tex_info: [
                             b_s as i16,
                             b_t as i16,
@@ -373,8 +471,9 @@ impl <B> QMap<B>
                             (t_offset_x + b_tx) as i16,
                             (t_offset_y + b_ty) as i16,
                         ],
+                        light_stride: light_stride,
                         light: light,
-                        light_type: type_light,
+                        light_styles: light_styles,
                     });
 
                     let center = Vector3::new(
@@ -397,11 +496,13 @@ impl <B> QMap<B>
 
                     buffer.push(super::Vertex {
                         position: [
-                            model.origin.x + center.x,
-                            model.origin.y + center.y,
-                            model.origin.z + center.z,
+                            center.x,
+                            center.y,
+                            center.z,
                         ],
                         tex: [trect.x as u16, trect.y as u16],
+                        tex_alt: [trect_alt.x as u16, trect_alt.y as u16],
+                        tex_frames: tex_frames,
                         tex_info: [
                             c_s as i16,
                             c_t as i16,
@@ -412,13 +513,33 @@ impl <B> QMap<B>
                             (t_offset_x + c_tx) as i16,
                             (t_offset_y + c_ty) as i16,
                         ],
+                        light_stride: light_stride,
                         light: light,
-                        light_type: type_light,
+                        light_styles: light_styles,
                     });
                 }
+
+                if !is_sky {
+                    face_verts[face_id] = start .. buffer.len() as u32;
+                }
             }
+            model_verts.push(model_start .. verts.len() as u32);
+            model_turb_verts.push(model_turb_start .. verts_turb.len() as u32);
         }
 
+        // Liquids are placed after the rest of the level in the
+        // vertex buffer as they are drawn with their own pipeline
+        let turb_base = verts.len() as u32;
+        for (face, range) in face_verts.iter_mut().enumerate() {
+            if turb_faces.get(face) {
+                *range = range.start + turb_base .. range.end + turb_base;
+            }
+        }
+        for range in &mut model_turb_verts {
+            *range = range.start + turb_base .. range.end + turb_base;
+        }
+        verts.extend(verts_turb);
+
         let buffer = unsafe {
             let staging_buffer = BufferBundle::new(
                 device,
@@ -504,51 +625,6 @@ impl <B> QMap<B>
         };
 
 
-        let sky_box_verts = sky_texture.map_or_else(Vec::new, |v| Self::gen_sky_box(
-            &textures, v, sky_min + Vector3::new(-2000.0, -2000.0, 0.0), sky_max + Vector3::new(2000.0, 2000.0, 0.0),
-        ));
-        let buffer_sky_box = unsafe {
-            let staging_buffer = BufferBundle::new(
-                device,
-                allocator,
-                (size_of::<super::Vertex>() * sky_box_verts.len()) as u64,
-                buffer::Usage::TRANSFER_SRC,
-                memory::Properties::CPU_VISIBLE
-            );
-
-            {
-                let mut data_target = device.acquire_mapping_writer(staging_buffer.memory.memory(), staging_buffer.memory.range.clone()).unwrap();
-                data_target[..sky_box_verts.len()].copy_from_slice(&sky_box_verts);
-                device.release_mapping_writer(data_target).unwrap();
-            }
-
-            let buffer = BufferBundle::new(
-                device,
-                allocator,
-                (size_of::<super::Vertex>() * sky_box_verts.len()) as u64,
-                buffer::Usage::VERTEX | buffer::Usage::TRANSFER_DST,
-                memory::Properties::DEVICE_LOCAL
-            );
-
-            // Copy from staging to real buffer
-            let mut cmd = command_pool.acquire_command_buffer::<command::OneShot>();
-            cmd.begin();
-            cmd.copy_buffer(&staging_buffer.buffer, &buffer.buffer, Some(command::BufferCopy {
-                src: 0,
-                dst: 0,
-                size: (size_of::<super::Vertex>() * sky_box_verts.len()) as u64,
-            }));
-            cmd.finish();
-
-            queue.submit_nosemaphores(Some(&cmd), None);
-            queue.wait_idle().unwrap();
-
-            command_pool.free(Some(cmd));
-            staging_buffer.destroy(device, allocator);
-
-            buffer
-        };
-
         let (texture, texture_light) = unsafe {
             let texture_light = ImageBundle::new(
                 device, allocator, super::ATLAS_SIZE, super::ATLAS_SIZE, 1,
@@ -710,188 +786,353 @@ impl <B> QMap<B>
             (texture, texture_light)
         };
 
+        let sky_box_image = unsafe {
+            let black = [0, 0, 0, 255].repeat(6);
+            let (size, data) = match sky_box {
+                Some(v) => (v.size, &v.data[..]),
+                None => (1, &black[..]),
+            };
+            let width = size * 6;
+            let sky_box_image = ImageBundle::new(
+                device, allocator, width, size, 4,
+                format::Format::Rgba8Srgb,
+                hal::image::Filter::Linear
+            );
+
+            let staging_buffer = BufferBundle::new(
+                device,
+                allocator,
+                (sky_box_image.row_pitch * size) as u64,
+                buffer::Usage::TRANSFER_SRC,
+                memory::Properties::CPU_VISIBLE
+            );
+
+            {
+                let mut data_target = device.acquire_mapping_writer(staging_buffer.memory.memory(), staging_buffer.memory.range.clone()).unwrap();
+                for y in 0 .. size {
+                    let idx = y * width * 4;
+                    let data = &data[idx as usize .. (idx + width * 4) as usize];
+                    let d_idx = y * sky_box_image.row_pitch;
+                    data_target[d_idx as usize..(d_idx + width * 4) as usize].copy_from_slice(&data);
+                }
+                device.release_mapping_writer(data_target).unwrap();
+            }
+
+            // Copy from staging to image
+            let mut cmd = command_pool.acquire_command_buffer::<command::OneShot>();
+            cmd.begin();
+            cmd.pipeline_barrier(
+                pso::PipelineStage::TOP_OF_PIPE .. pso::PipelineStage::TRANSFER,
+                memory::Dependencies::empty(),
+                &[
+                    memory::Barrier::Image {
+                        states: (image::Access::empty(), image::Layout::Undefined)
+                            .. (image::Access::TRANSFER_WRITE, image::Layout::TransferDstOptimal),
+                        target: &*sky_box_image.image,
+                        families: None,
+                        range: image::SubresourceRange {
+                            aspects: format::Aspects::COLOR,
+                            levels: 0..1,
+                            layers: 0..1,
+                        },
+                    },
+                ]
+            );
+            cmd.copy_buffer_to_image(
+                &staging_buffer.buffer,
+                &sky_box_image.image,
+                image::Layout::TransferDstOptimal,
+                &[command::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_width: sky_box_image.row_pitch / 4,
+                    buffer_height: size,
+                    image_layers: image::SubresourceLayers {
+                        aspects: format::Aspects::COLOR,
+                        level: 0,
+                        layers: 0..1,
+                    },
+                    image_offset: image::Offset { x: 0, y: 0, z: 0},
+                    image_extent: image::Extent {
+                        width: width,
+                        height: size,
+                        depth: 1,
+                    },
+                }],
+            );
+            cmd.pipeline_barrier(
+                pso::PipelineStage::TRANSFER .. pso::PipelineStage::FRAGMENT_SHADER,
+                memory::Dependencies::empty(),
+                &[
+                    memory::Barrier::Image {
+                        states: (image::Access::TRANSFER_WRITE, image::Layout::TransferDstOptimal)
+                            .. (image::Access::SHADER_READ, image::Layout::ShaderReadOnlyOptimal),
+                        target: &*sky_box_image.image,
+                        families: None,
+                        range: image::SubresourceRange {
+                            aspects: format::Aspects::COLOR,
+                            levels: 0..1,
+                            layers: 0..1,
+                        },
+                    },
+                ]
+            );
+            cmd.finish();
+
+            queue.submit_nosemaphores(Some(&cmd), None);
+            queue.wait_idle().unwrap();
+
+            command_pool.free(Some(cmd));
+            staging_buffer.destroy(device, allocator);
+
+            sky_box_image
+        };
+
         Ok(QMap {
+            visible_faces: BitSet::new(b.faces.len()),
+            brush_entities: BrushEntity::from_entities(&b),
+            alternate_frames: false,
+            bsp: b,
+
             buffer,
-            buffer_count: verts.len(),
+            face_verts,
+            model_verts,
+            model_turb_verts,
+            turb_faces,
+            vis_leaf: None,
+            visible_ranges: vec![],
+            visible_turb_ranges: vec![],
             buffer_sky,
             buffer_sky_count: verts_sky.len(),
-            buffer_sky_box,
-            buffer_sky_box_count: sky_box_verts.len(),
             texture,
             texture_light,
+            sky_box: sky_box_image,
+            has_sky_box: sky_box.is_some(),
 
-            time_offset: 0.0,
+            time: 0.0,
         })
     }
 
     pub fn draw(
         &mut self,
         delta: f32,
+        position: Vector3<f32>,
+        matrix: Matrix4<f32>,
+        water_alpha: f32,
         device: &B::Device,
         layout: &B::PipelineLayout,
         pipeline: &B::GraphicsPipeline,
-        depth_pipeline: &B::GraphicsPipeline,
         sky_pipeline: &B::GraphicsPipeline,
+        sky_box_pipeline: &B::GraphicsPipeline,
         encoder: &mut command::RenderPassInlineEncoder<B>,
     ) -> error::Result<()>
     {
-        self.time_offset += delta * 0.0007;
+        self.time += delta / 60.0;
+        // Texture animations run at 5 frames a second
+        let texture_frame = (self.time * 5.0) as u32;
+
+        let leaf = self.bsp.find_leaf(position);
+        if self.vis_leaf != Some(leaf) {
+            self.vis_leaf = Some(leaf);
+            self.update_visible_faces(leaf);
+        }
 
         unsafe {
-            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::FRAGMENT, 4*4*4, &[self.time_offset.to_bits()]);
-            // Skybox
-            encoder.bind_graphics_pipeline(sky_pipeline);
-            encoder.bind_vertex_buffers(0, Some((&*self.buffer_sky_box.buffer, 0)));
-            encoder.draw(0..self.buffer_sky_box_count as u32, 0..1);
-
-            // Fill the depth buffer with the sky areas.
-            // This cuts holes into the level to allow the sky
-            // to show as the sky box sometimes covers parts of the
-            // level in quake.
-            encoder.bind_graphics_pipeline(depth_pipeline);
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::FRAGMENT, 4*4*4 + 4, &[self.time.to_bits(), water_alpha.to_bits()]);
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 4*4*4 + 4*4, &[texture_frame, self.alternate_frames as u32]);
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 4*4*4 + 4*8, &[
+                position.x.to_bits(),
+                position.y.to_bits(),
+                position.z.to_bits(),
+                0,
+            ]);
+
+            // The sky faces are drawn first and fill the depth buffer,
+            // cutting holes into any of the level behind them as Quake
+            // sometimes places geometry there.
+            encoder.bind_graphics_pipeline(if self.has_sky_box {
+                sky_box_pipeline
+            } else {
+                sky_pipeline
+            });
             encoder.bind_vertex_buffers(0, Some((&*self.buffer_sky.buffer, 0)));
             encoder.draw(0..self.buffer_sky_count as u32, 0..1);
 
             // Render the level
             encoder.bind_graphics_pipeline(pipeline);
             encoder.bind_vertex_buffers(0, Some((&*self.buffer.buffer, 0)));
-            encoder.draw(0..self.buffer_count as u32, 0..1);
+            for range in &self.visible_ranges {
+                encoder.draw(range.clone(), 0..1);
+            }
+            self.draw_brush_entities(&self.model_verts, matrix, texture_frame, layout, encoder);
         }
         Ok(())
     }
 
+    // Liquids are drawn after everything else in the level
+    // so it shows through them
+    pub fn draw_liquids(
+        &self,
+        matrix: Matrix4<f32>,
+        layout: &B::PipelineLayout,
+        turb_pipeline: &B::GraphicsPipeline,
+        encoder: &mut command::RenderPassInlineEncoder<B>,
+    ) {
+        let texture_frame = (self.time * 5.0) as u32;
+        unsafe {
+            encoder.bind_graphics_pipeline(turb_pipeline);
+            encoder.bind_vertex_buffers(0, Some((&*self.buffer.buffer, 0)));
+            let world: [[f32; 4]; 4] = matrix.into();
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 0, hal::memory::cast_slice(&[world]));
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 4*4*4 + 4*4, &[texture_frame, self.alternate_frames as u32]);
+            for range in &self.visible_turb_ranges {
+                encoder.draw(range.clone(), 0..1);
+            }
+            self.draw_brush_entities(&self.model_turb_verts, matrix, texture_frame, layout, encoder);
+        }
+    }
+
+    // Switches the animated textures of a model to their alternate
+    // frames, the world for model 0 or else every brush entity
+    // using the submodel
+    pub fn set_alternate_frames(&mut self, model: usize, alternate: bool) {
+        if model == 0 {
+            self.alternate_frames = alternate;
+        }
+        for entity in self.brush_entities.iter_mut().filter(|v| v.model == model) {
+            entity.alternate_frames = alternate;
+        }
+    }
+
+    pub fn light_point(&self, point: Vector3<f32>, styles: &[f32]) -> f32 {
+        self.bsp.light_point(point, styles)
+    }
+
+    // Draws the submodel of each brush entity with the
+    // entity's transform
+    unsafe fn draw_brush_entities(
+        &self,
+        model_verts: &[Range<u32>],
+        matrix: Matrix4<f32>,
+        texture_frame: u32,
+        layout: &B::PipelineLayout,
+        encoder: &mut command::RenderPassInlineEncoder<B>,
+    ) {
+        for entity in &self.brush_entities {
+            let range = match model_verts.get(entity.model) {
+                Some(range) if range.start != range.end => range.clone(),
+                _ => continue,
+            };
+            let transform: [[f32; 4]; 4] = (matrix * entity.transform()).into();
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 0, hal::memory::cast_slice(&[transform]));
+            encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 4*4*4 + 4*4, &[texture_frame, entity.alternate_frames as u32]);
+            encoder.draw(range, 0..1);
+        }
+    }
+
+    fn update_visible_faces(&mut self, leaf: usize) {
+        let vis = self.bsp.leaf_visibility(leaf);
+
+        self.visible_faces.clear();
+        for (id, leaf) in self.bsp.leaves.iter().enumerate() {
+            if !vis.get(id) {
+                continue;
+            }
+            for face in &self.bsp.mark_surfaces[leaf.mark_surfaces.clone()] {
+                self.visible_faces.set(*face, true);
+            }
+        }
+
+        // Faces are stored in order in the vertex buffer so
+        // neighbouring faces can be merged into a single draw
+        self.visible_ranges.clear();
+        self.visible_turb_ranges.clear();
+        for face in self.bsp.models[0].faces.clone() {
+            let range = self.face_verts[face].clone();
+            if range.start == range.end || !self.visible_faces.get(face) {
+                continue;
+            }
+            let ranges = if self.turb_faces.get(face) {
+                &mut self.visible_turb_ranges
+            } else {
+                &mut self.visible_ranges
+            };
+            if let Some(last) = ranges.last_mut() {
+                if last.end == range.start {
+                    last.end = range.end;
+                    continue;
+                }
+            }
+            ranges.push(range);
+        }
+    }
+
     pub unsafe fn destroy(self, device: &B::Device, allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>) {
         self.buffer.destroy(device, allocator);
         self.buffer_sky.destroy(device, allocator);
-        self.buffer_sky_box.destroy(device, allocator);
 
         self.texture.destroy(device, allocator);
         self.texture_light.destroy(device, allocator);
+        self.sky_box.destroy(device, allocator);
     }
+}
 
-    fn gen_sky_box(textures: &Vec<atlas::Rect>, tex: i32, min: Vector3<f32>, max: Vector3<f32>) -> Vec<super::Vertex> {
-        let tex = textures[tex as usize];
-
-        let mut verts = vec![];
-
-        let width = (tex.width / 2) as u16;
-
-        for z in 0 .. 2 {
-            let offset = z as f32 * 100.0;
-            verts.push(super::Vertex {
-                position: [
-                    min.x,
-                    min.y,
-                    max.z + offset
-                ],
-                tex: [tex.x as u16 + width * z, tex.y as u16],
-                tex_info: [
-                    0,
-                    0,
-                    width as i16,
-                    tex.height as i16,
-                ],
-                light_info: [0, 0],
-                light: 0,
-                light_type: z as u8,
-            });
-            verts.push(super::Vertex {
-                position: [
-                    min.x,
-                    max.y,
-                    max.z + offset
-                ],
-                tex: [tex.x as u16 + width * z, tex.y as u16],
-                tex_info: [
-                    0,
-                    0,
-                    width as i16,
-                    tex.height as i16,
-                ],
-                light_info: [0, 0],
-                light: 0,
-                light_type: z as u8,
-            });
-            verts.push(super::Vertex {
-                position: [
-                    max.x,
-                    min.y,
-                    max.z + offset
-                ],
-                tex: [tex.x as u16 + width * z, tex.y as u16],
-                tex_info: [
-                    0,
-                    0,
-                    width as i16,
-                    tex.height as i16,
-                ],
-                light_info: [0, 0],
-                light: 0,
-                light_type: z as u8,
-            });
+// An entity that places one of the level's submodels, such
+// as a door or a lift. The game moves these by changing the
+// origin and angles.
+#[derive(Debug, Clone)]
+pub struct BrushEntity {
+    // Index of the entity in the level's entity list
+    pub entity: usize,
+    pub model: usize,
+    pub origin: Vector3<f32>,
+    // Pitch, yaw and roll in degrees
+    pub angles: Vector3<f32>,
+    // Shows the alternate frames of animated textures
+    pub alternate_frames: bool,
+}
 
-            verts.push(super::Vertex {
-                position: [
-                    min.x,
-                    max.y,
-                    max.z + offset
-                ],
-                tex: [tex.x as u16 + width * z, tex.y as u16],
-                tex_info: [
-                    0,
-                    0,
-                    width as i16,
-                    tex.height as i16,
-                ],
-                light_info: [0, 0],
-                light: 0,
-                light_type: z as u8,
-            });
-            verts.push(super::Vertex {
-                position: [
-                    max.x,
-                    max.y,
-                    max.z + offset
-                ],
-                tex: [tex.x as u16 + width * z, tex.y as u16],
-                tex_info: [
-                    0,
-                    0,
-                    width as i16,
-                    tex.height as i16,
-                ],
-                light_info: [0, 0],
-                light: 0,
-                light_type: z as u8,
-            });
-            verts.push(super::Vertex {
-                position: [
-                    max.x,
-                    min.y,
-                    max.z + offset
-                ],
-                tex: [tex.x as u16 + width * z, tex.y as u16],
-                tex_info: [
-                    0,
-                    0,
-                    width as i16,
-                    tex.height as i16,
-                ],
-                light_info: [0, 0],
-                light: 0,
-                light_type: z as u8,
-            });
-        }
+impl BrushEntity {
+    // Links every submodel to the entities using it through
+    // their `*N` model key
+    pub fn from_entities(level: &bsp::BspFile) -> Vec<BrushEntity> {
+        level.entities.iter()
+            .enumerate()
+            .filter_map(|(idx, e)| {
+                let model = e.model_index()?;
+                if model == 0 || model >= level.models.len() {
+                    return None;
+                }
+                Some(BrushEntity {
+                    entity: idx,
+                    model: model,
+                    origin: e.origin().unwrap_or(Vector3::zero()),
+                    angles: Vector3::zero(),
+                    alternate_frames: false,
+                })
+            })
+            .collect()
+    }
 
-        verts
+    pub fn transform(&self) -> Matrix4<f32> {
+        entity_transform(self.origin, self.angles)
     }
 }
 
+// Places an entity at its origin turned by its pitch, yaw
+// and roll in degrees
+pub fn entity_transform(origin: Vector3<f32>, angles: Vector3<f32>) -> Matrix4<f32> {
+    Matrix4::from_translation(origin)
+        * Matrix4::from_angle_z(Deg(angles.y))
+        * Matrix4::from_angle_y(Deg(-angles.x))
+        * Matrix4::from_angle_x(Deg(-angles.z))
+}
+
 #[derive(PartialEq, Eq)]
 struct TSortable {
     idx: i32,
     width: u32,
     height: u32,
+    layers: u32,
 }
 
 impl PartialOrd for TSortable {
@@ -901,6 +1142,54 @@ impl PartialOrd for TSortable {
 }
 impl Ord for TSortable {
     fn cmp(&self, other: &Self) -> Ordering {
-        (self.width * self.height).cmp(&(other.width * other.height))
+        (self.width * self.height * self.layers).cmp(&(other.width * other.height * other.layers))
     }
-}
\ No newline at end of file
+}
+
+// Groups the animated textures into their primary and alternate
+// frame sequences. Also returns the sequence each texture is part
+// of and whether it is one of the alternate frames.
+fn texture_animations(textures: &[bsp::Texture]) -> (Vec<[Vec<i32>; 2]>, HashMap<i32, (usize, bool)>) {
+    let mut frames: HashMap<&str, [Vec<(usize, i32)>; 2]> = HashMap::new();
+    for tex in textures {
+        if tex.id == -1 {
+            continue;
+        }
+        if let Some((name, alternate, frame)) = tex.animation_frame() {
+            frames.entry(name).or_default()[alternate as usize].push((frame, tex.id));
+        }
+    }
+
+    let mut animations = vec![];
+    let mut lookup = HashMap::new();
+    for (name, mut sets) in frames {
+        let mut anim: [Vec<i32>; 2] = Default::default();
+        for (alternate, set) in sets.iter_mut().enumerate() {
+            if set.is_empty() {
+                continue;
+            }
+            set.sort();
+            // Every frame is packed into one strip so the sequence
+            // ends at a gap or a frame of a different size
+            let first = &textures[set[0].1 as usize];
+            for (idx, &(frame, id)) in set.iter().enumerate() {
+                let tex = &textures[id as usize];
+                if frame != idx || tex.width != first.width || tex.height != first.height {
+                    warn!("Animated texture {} has a missing or mismatched frame {}", name, idx);
+                    break;
+                }
+                anim[alternate].push(id);
+            }
+        }
+        if anim[0].is_empty() && anim[1].is_empty() {
+            continue;
+        }
+        for (alternate, set) in anim.iter().enumerate() {
+            for id in set {
+                lookup.insert(*id, (animations.len(), alternate != 0));
+            }
+        }
+        animations.push(anim);
+    }
+    (animations, lookup)
+}
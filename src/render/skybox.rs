@@ -0,0 +1,60 @@
+use hal::Limits;
+
+use crate::vfs::FileSystem;
+use crate::error;
+use crate::tga;
+use super::alloc;
+
+// The order the faces are placed in the image, matching the
+// sky box shader
+const FACE_SUFFIXES: [&str; 6] = ["rt", "bk", "lf", "ft", "up", "dn"];
+
+// The six faces of a sky box placed side by side in a single
+// RGBA image
+pub struct SkyBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+impl SkyBox {
+    // Loads gfx/env/<name><suffix>.tga, these are usually
+    // loose files as the pak files don't include any. Faces too
+    // large for the device or the allocator are an error so the
+    // level falls back to the scrolling sky.
+    pub fn load(files: &FileSystem, name: &str, limits: &Limits) -> error::Result<SkyBox> {
+        let mut faces = Vec::with_capacity(FACE_SUFFIXES.len());
+        for suffix in &FACE_SUFFIXES {
+            let path = format!("gfx/env/{}{}.tga", name, suffix);
+            let data = files.file(&path)?;
+            faces.push(tga::Image::parse(&data)?);
+        }
+
+        let size = faces[0].width;
+        if faces.iter().any(|v| v.width != size || v.height != size) {
+            bail!("The faces of sky box {} aren't all the same square size", name);
+        }
+        if size == 0 {
+            bail!("The faces of sky box {} are empty", name);
+        }
+        let width = size as u64 * FACE_SUFFIXES.len() as u64;
+        let pitch_mask = limits.optimal_buffer_copy_pitch_alignment.max(1) - 1;
+        let pitch = (width * 4 + pitch_mask) & !pitch_mask;
+        if width > limits.max_image_2d_size as u64 || pitch * size as u64 > alloc::REGION_SIZE {
+            bail!("Sky box {} is too large with {}x{} faces", name, size, size);
+        }
+
+        let row = size as usize * 4;
+        let mut data = vec![0; row * FACE_SUFFIXES.len() * size as usize];
+        for (idx, face) in faces.iter().enumerate() {
+            for y in 0 .. size as usize {
+                let offset = (y * FACE_SUFFIXES.len() + idx) * row;
+                data[offset .. offset + row].copy_from_slice(&face.data[y * row .. (y + 1) * row]);
+            }
+        }
+
+        Ok(SkyBox {
+            size: size,
+            data: data,
+        })
+    }
+}
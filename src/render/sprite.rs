@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use cgmath::prelude::*;
+use cgmath::{Vector3, Vector4, Matrix4};
+use log::*;
+
+use hal::{
+    Backend,
+    CommandPool,
+    pso,
+    command,
+    queue,
+};
+
+use crate::error;
+use crate::bsp;
+use crate::spr;
+use crate::vfs::FileSystem;
+use super::atlas;
+use super::alloc;
+use super::qmap::entity_transform;
+use super::{BufferBundle, ImageBundle, upload_vertex_buffer, upload_atlas};
+
+// Sprites the game spawns for its effects, loaded with
+// every level
+const EFFECT_SPRITES: &[&str] = &[
+    "progs/s_explod.spr",
+    "progs/s_bubble.spr",
+];
+
+// Entities drawn as a sprite where they are placed
+const ENTITY_SPRITES: &[(&str, &str)] = &[
+    ("light_globe", "progs/s_light.spr"),
+];
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(super) struct SpriteVertex {
+    // Position on the sprite's plane relative to the entity's origin,
+    // x to the right and y up
+    offset: [f32; 2],
+    tex: [f32; 2],
+}
+
+pub struct Sprites<B: Backend> {
+    sprites: Vec<Sprite>,
+    names: HashMap<String, usize>,
+    pub entities: Vec<SpriteEntity>,
+
+    // A quad for every picture of every sprite
+    buffer: BufferBundle<B>,
+    pub atlas: ImageBundle<B>,
+
+    time: f32,
+}
+
+struct Sprite {
+    spr: spr::SprFile,
+    // Vertex offset of the first picture of each frame
+    frame_starts: Vec<u32>,
+}
+
+// A sprite placed in the level, either by an entity or by the
+// game for an effect such as an explosion.
+#[derive(Debug, Clone)]
+pub struct SpriteEntity {
+    // Index of the entity in the level's entity list
+    pub entity: Option<usize>,
+    pub sprite: usize,
+    pub origin: Vector3<f32>,
+    // Pitch, yaw and roll in degrees, only used by oriented sprites
+    pub angles: Vector3<f32>,
+    pub frame: usize,
+}
+
+impl <B> Sprites<B>
+    where B: Backend,
+{
+    pub fn new(
+        files: &FileSystem,
+        level: &bsp::BspFile,
+        device: &B::Device,
+        queue: &mut queue::CommandQueue<B, hal::Graphics>,
+        command_pool: &mut CommandPool<B, hal::Graphics>,
+        allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>,
+    ) -> error::Result<Sprites<B>>
+    {
+        // Padded with transparent pixels so the edges of a sprite
+        // don't pick up its neighbours
+        let mut atlas = atlas::TextureAtlas::new_padded(
+            super::ATLAS_SIZE as i32,
+            super::ATLAS_SIZE as i32,
+            1
+        );
+        let mut atlas_data = vec![255u8; (super::ATLAS_SIZE * super::ATLAS_SIZE) as usize];
+
+        let mut sprites = vec![];
+        let mut names = HashMap::new();
+        let mut verts = vec![];
+
+        let entity_sprites = level.entities.iter()
+            .enumerate()
+            .filter_map(|(idx, e)| {
+                let classname = e.classname()?;
+                let &(_, name) = ENTITY_SPRITES.iter().find(|v| v.0 == classname)?;
+                Some((idx, name))
+            })
+            .collect::<Vec<_>>();
+
+        let wanted = EFFECT_SPRITES.iter()
+            .cloned()
+            .chain(entity_sprites.iter().map(|v| v.1));
+        for name in wanted {
+            if names.contains_key(name) {
+                continue;
+            }
+            match load_sprite(files, name, &mut atlas, &mut atlas_data, &mut verts) {
+                Ok(sprite) => {
+                    names.insert(name.to_owned(), sprites.len());
+                    sprites.push(sprite);
+                },
+                Err(err) => warn!("Failed to load sprite {}: {}", name, err),
+            }
+        }
+
+        let entities = entity_sprites.into_iter()
+            .filter_map(|(idx, name)| {
+                let e = &level.entities[idx];
+                Some(SpriteEntity {
+                    entity: Some(idx),
+                    sprite: *names.get(name)?,
+                    origin: e.origin().unwrap_or(Vector3::zero()),
+                    angles: Vector3::new(0.0, e.angle().unwrap_or(0.0), 0.0),
+                    frame: 0,
+                })
+            })
+            .collect();
+
+        let buffer = unsafe { upload_vertex_buffer(device, queue, command_pool, allocator, &verts) };
+        let atlas = unsafe { upload_atlas(device, queue, command_pool, allocator, &atlas_data) };
+
+        Ok(Sprites {
+            sprites,
+            names,
+            entities,
+            buffer,
+            atlas,
+            time: 0.0,
+        })
+    }
+
+    // Looks up a loaded sprite by its file name
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.names.get(name).cloned()
+    }
+
+    pub fn draw(
+        &mut self,
+        delta: f32,
+        matrix: Matrix4<f32>,
+        camera: &super::Camera,
+        layout: &B::PipelineLayout,
+        pipeline: &B::GraphicsPipeline,
+        encoder: &mut command::RenderPassInlineEncoder<B>,
+    ) {
+        self.time += delta / 60.0;
+        if self.entities.is_empty() {
+            return;
+        }
+
+        let forward = camera.forward();
+        let view_right = camera.right();
+        let view_up = view_right.cross(forward);
+
+        unsafe {
+            encoder.bind_graphics_pipeline(pipeline);
+            encoder.bind_vertex_buffers(0, Some((&*self.buffer.buffer, 0)));
+            for entity in &self.entities {
+                let sprite = match self.sprites.get(entity.sprite) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let frame = match sprite.spr.frames.get(entity.frame) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let start = sprite.frame_starts[entity.frame]
+                    + frame.index_at_time(self.time) as u32 * 6;
+
+                let (right, up) = sprite_axes(sprite.spr.kind, entity, camera.position(), forward, view_right, view_up);
+
+                let transform: [[f32; 4]; 4] = (matrix * Matrix4::from_translation(entity.origin)).into();
+                let axes: [[f32; 4]; 2] = [right.extend(0.0).into(), up.extend(0.0).into()];
+                encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 0, hal::memory::cast_slice(&[transform]));
+                encoder.push_graphics_constants(layout, pso::ShaderStageFlags::VERTEX, 4*4*4 + 4*4, hal::memory::cast_slice(&axes));
+                encoder.draw(start .. start + 6, 0..1);
+            }
+        }
+    }
+
+    pub unsafe fn destroy(self, device: &B::Device, allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>) {
+        self.buffer.destroy(device, allocator);
+        self.atlas.destroy(device, allocator);
+    }
+}
+
+// The directions of the sprite's x and y axes in the world,
+// following Quake's R_DrawSprite
+fn sprite_axes(
+    kind: spr::SpriteType,
+    entity: &SpriteEntity,
+    camera: Vector3<f32>,
+    forward: Vector3<f32>,
+    view_right: Vector3<f32>,
+    view_up: Vector3<f32>,
+) -> (Vector3<f32>, Vector3<f32>) {
+    use crate::spr::SpriteType::*;
+    // Upright sprites turn around the z axis to face along `dir`
+    let upright = |dir: Vector3<f32>| {
+        let right = Vector3::new(dir.y, -dir.x, 0.0);
+        if right.magnitude2() > 0.0 {
+            (right.normalize(), Vector3::unit_z())
+        } else {
+            (view_right, Vector3::unit_z())
+        }
+    };
+    match kind {
+        ParallelUpright => upright(forward),
+        FacingUpright => upright(entity.origin - camera),
+        Parallel => (view_right, view_up),
+        Oriented => {
+            let rotation = entity_transform(Vector3::zero(), entity.angles);
+            (
+                (rotation * Vector4::new(0.0, -1.0, 0.0, 0.0)).truncate(),
+                (rotation * Vector4::new(0.0, 0.0, 1.0, 0.0)).truncate(),
+            )
+        },
+        ParallelOriented => {
+            let (sr, cr) = entity.angles.z.to_radians().sin_cos();
+            (
+                view_right * cr + view_up * sr,
+                view_up * cr - view_right * sr,
+            )
+        },
+    }
+}
+
+// Loads a sprite from the pak file, packing its pictures into
+// the atlas and a quad for each into the vertex list
+fn load_sprite(
+    files: &FileSystem,
+    name: &str,
+    atlas: &mut atlas::TextureAtlas,
+    atlas_data: &mut [u8],
+    verts: &mut Vec<SpriteVertex>,
+) -> error::Result<Sprite> {
+    let data = files.file(name)?;
+    let spr = spr::SprFile::parse(&mut Cursor::new(data))?;
+
+    let mut frame_starts = Vec::with_capacity(spr.frames.len());
+    for frame in &spr.frames {
+        frame_starts.push(verts.len() as u32);
+        for pic in frame.pictures() {
+            let (width, height) = (pic.width as usize, pic.height as usize);
+            let rect = match atlas.find(width as i32, height as i32) {
+                Some(v) => v,
+                None => bail!("Out of space for sprites"),
+            };
+            for y in 0 .. height {
+                let idx = rect.x as usize + (rect.y as usize + y) * super::ATLAS_SIZE as usize;
+                atlas_data[idx .. idx + width].copy_from_slice(&pic.data[y * width .. (y + 1) * width]);
+            }
+
+            let left = pic.origin[0] as f32;
+            let top = pic.origin[1] as f32;
+            let corner = |x: usize, y: usize| SpriteVertex {
+                offset: [left + x as f32, top - y as f32],
+                tex: [(rect.x as usize + x) as f32, (rect.y as usize + y) as f32],
+            };
+            verts.extend_from_slice(&[
+                corner(0, 0), corner(width, 0), corner(width, height),
+                corner(0, 0), corner(width, height), corner(0, height),
+            ]);
+        }
+    }
+
+    Ok(Sprite {
+        spr,
+        frame_starts,
+    })
+}
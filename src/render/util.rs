@@ -2,11 +2,17 @@
 use hal::{
     Backend,
     Device,
+    CommandPool,
     buffer,
     memory,
+    image,
+    format,
+    pso,
+    command,
+    queue,
 };
 
-use std::mem::ManuallyDrop;
+use std::mem::{ManuallyDrop, size_of};
 
 use super::alloc;
 
@@ -182,4 +188,157 @@ impl <B> DepthImage<B>
         device.destroy_image(ManuallyDrop::into_inner(ptr::read(&self.image)));
         allocator.free(ManuallyDrop::into_inner(ptr::read(&self.memory)));
     }
-}
\ No newline at end of file
+}
+
+// Copies the vertices into a new device local vertex buffer
+pub unsafe fn upload_vertex_buffer<B: Backend, T: Copy>(
+    device: &B::Device,
+    queue: &mut queue::CommandQueue<B, hal::Graphics>,
+    command_pool: &mut CommandPool<B, hal::Graphics>,
+    allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>,
+    data: &[T],
+) -> BufferBundle<B> {
+    // Empty lists still get a buffer
+    let size = (size_of::<T>() * data.len().max(1)) as u64;
+    let staging_buffer = BufferBundle::new(
+        device,
+        allocator,
+        size,
+        buffer::Usage::TRANSFER_SRC,
+        memory::Properties::CPU_VISIBLE
+    );
+
+    {
+        let mut data_target = device.acquire_mapping_writer(staging_buffer.memory.memory(), staging_buffer.memory.range.clone()).unwrap();
+        data_target[..data.len()].copy_from_slice(data);
+        device.release_mapping_writer(data_target).unwrap();
+    }
+
+    let buffer = BufferBundle::new(
+        device,
+        allocator,
+        size,
+        buffer::Usage::VERTEX | buffer::Usage::TRANSFER_DST,
+        memory::Properties::DEVICE_LOCAL
+    );
+
+    // Copy from staging to real buffer
+    let mut cmd = command_pool.acquire_command_buffer::<command::OneShot>();
+    cmd.begin();
+    cmd.copy_buffer(&staging_buffer.buffer, &buffer.buffer, Some(command::BufferCopy {
+        src: 0,
+        dst: 0,
+        size: size,
+    }));
+    cmd.finish();
+
+    queue.submit_nosemaphores(Some(&cmd), None);
+    queue.wait_idle().unwrap();
+
+    command_pool.free(Some(cmd));
+    staging_buffer.destroy(device, allocator);
+
+    buffer
+}
+
+// Copies a palette indexed texture atlas into a new image
+pub unsafe fn upload_atlas<B: Backend>(
+    device: &B::Device,
+    queue: &mut queue::CommandQueue<B, hal::Graphics>,
+    command_pool: &mut CommandPool<B, hal::Graphics>,
+    allocator: &mut alloc::GPUAlloc<B, impl alloc::RangeAlloc>,
+    data: &[u8],
+) -> ImageBundle<B> {
+    let atlas = ImageBundle::new(
+        device, allocator, super::ATLAS_SIZE, super::ATLAS_SIZE, 1,
+        format::Format::R8Unorm,
+        hal::image::Filter::Nearest
+    );
+
+    let staging_buffer = BufferBundle::new(
+        device,
+        allocator,
+        (atlas.row_pitch * super::ATLAS_SIZE) as u64,
+        buffer::Usage::TRANSFER_SRC,
+        memory::Properties::CPU_VISIBLE
+    );
+
+    {
+        let mut data_target = device.acquire_mapping_writer(staging_buffer.memory.memory(), staging_buffer.memory.range.clone()).unwrap();
+        for y in 0 .. super::ATLAS_SIZE {
+            let idx = y * super::ATLAS_SIZE;
+            let data = &data[idx as usize .. (idx + super::ATLAS_SIZE) as usize];
+            let d_idx = y * atlas.row_pitch;
+            data_target[d_idx as usize..(d_idx + super::ATLAS_SIZE) as usize].copy_from_slice(&data);
+        }
+        device.release_mapping_writer(data_target).unwrap();
+    }
+
+    // Copy from staging to image
+    let mut cmd = command_pool.acquire_command_buffer::<command::OneShot>();
+    cmd.begin();
+    cmd.pipeline_barrier(
+        pso::PipelineStage::TOP_OF_PIPE .. pso::PipelineStage::TRANSFER,
+        memory::Dependencies::empty(),
+        &[
+            memory::Barrier::Image {
+                states: (image::Access::empty(), image::Layout::Undefined)
+                    .. (image::Access::TRANSFER_WRITE, image::Layout::TransferDstOptimal),
+                target: &*atlas.image,
+                families: None,
+                range: image::SubresourceRange {
+                    aspects: format::Aspects::COLOR,
+                    levels: 0..1,
+                    layers: 0..1,
+                },
+            },
+        ]
+    );
+    cmd.copy_buffer_to_image(
+        &staging_buffer.buffer,
+        &atlas.image,
+        image::Layout::TransferDstOptimal,
+        &[command::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_width: atlas.row_pitch / 1,
+            buffer_height: super::ATLAS_SIZE,
+            image_layers: image::SubresourceLayers {
+                aspects: format::Aspects::COLOR,
+                level: 0,
+                layers: 0..1,
+            },
+            image_offset: image::Offset { x: 0, y: 0, z: 0},
+            image_extent: image::Extent {
+                width: super::ATLAS_SIZE,
+                height: super::ATLAS_SIZE,
+                depth: 1,
+            },
+        }],
+    );
+    cmd.pipeline_barrier(
+        pso::PipelineStage::TRANSFER .. pso::PipelineStage::FRAGMENT_SHADER,
+        memory::Dependencies::empty(),
+        &[
+            memory::Barrier::Image {
+                states: (image::Access::TRANSFER_WRITE, image::Layout::TransferDstOptimal)
+                    .. (image::Access::SHADER_READ, image::Layout::ShaderReadOnlyOptimal),
+                target: &*atlas.image,
+                families: None,
+                range: image::SubresourceRange {
+                    aspects: format::Aspects::COLOR,
+                    levels: 0..1,
+                    layers: 0..1,
+                },
+            },
+        ]
+    );
+    cmd.finish();
+
+    queue.submit_nosemaphores(Some(&cmd), None);
+    queue.wait_idle().unwrap();
+
+    command_pool.free(Some(cmd));
+    staging_buffer.destroy(device, allocator);
+
+    atlas
+}
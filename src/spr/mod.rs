@@ -0,0 +1,201 @@
+use std::io::Read;
+
+use crate::error;
+use crate::parse::*;
+
+pub struct SprFile {
+    pub kind: SpriteType,
+    pub bounding_radius: f32,
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<Frame>,
+    pub beam_length: f32,
+    pub sync_type: i32,
+}
+
+// How the sprite is turned to face the camera
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpriteType {
+    // Faces the view direction but stays upright
+    ParallelUpright,
+    // Faces the camera's position but stays upright
+    FacingUpright,
+    // Faces the view direction
+    Parallel,
+    // Fixed by the entity's angles
+    Oriented,
+    // Faces the view direction, rolled by the entity's angles
+    ParallelOriented,
+}
+
+impl SprFile {
+    pub fn parse<R>(r: &mut R) -> error::Result<SprFile>
+        where R: Read,
+    {
+        let magic = read_string!(r, 4);
+        if &magic != b"IDSP" {
+            bail!("Invalid spr magic");
+        }
+        let version = r.read_long()?;
+        if version != 1 {
+            bail!("Unsupported spr version {}", version);
+        }
+
+        let kind = match r.read_long()? {
+            0 => SpriteType::ParallelUpright,
+            1 => SpriteType::FacingUpright,
+            2 => SpriteType::Parallel,
+            3 => SpriteType::Oriented,
+            4 => SpriteType::ParallelOriented,
+            v => bail!("Unknown sprite type {}", v),
+        };
+        let bounding_radius = r.read_float()?;
+        let width = read_count(r, "width")? as u32;
+        let height = read_count(r, "height")? as u32;
+        let num_frames = read_count(r, "frames")?;
+        if num_frames == 0 {
+            bail!("Invalid number of frames: 0");
+        }
+        let beam_length = r.read_float()?;
+        let sync_type = r.read_long()?;
+
+        let mut frames = vec![];
+        for _ in 0 .. num_frames {
+            frames.push(Frame::parse(r)?);
+        }
+
+        Ok(SprFile {
+            kind: kind,
+            bounding_radius: bounding_radius,
+            width: width,
+            height: height,
+            frames: frames,
+            beam_length: beam_length,
+            sync_type: sync_type,
+        })
+    }
+}
+
+pub struct Picture {
+    // Offset of the top left corner from the entity's origin,
+    // x to the right and y up
+    pub origin: [i32; 2],
+    pub width: u32,
+    pub height: u32,
+    // Indices into the palette, 255 is transparent
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    fn parse<R>(r: &mut R) -> error::Result<Picture>
+        where R: Read,
+    {
+        let origin = [r.read_long()?, r.read_long()?];
+        let width = read_count(r, "picture width")?;
+        let height = read_count(r, "picture height")?;
+        let size = match width.checked_mul(height) {
+            Some(v) => v,
+            None => bail!("Invalid picture size {}x{}", width, height),
+        };
+        let data = read_bytes(size, r)?;
+        Ok(Picture {
+            origin: origin,
+            width: width as u32,
+            height: height as u32,
+            data: data,
+        })
+    }
+}
+
+pub enum Frame {
+    Single(Picture),
+    // Pictures that animate on their own, each with the time
+    // it ends at
+    Group {
+        times: Vec<f32>,
+        pictures: Vec<Picture>,
+    },
+}
+
+impl Frame {
+    fn parse<R>(r: &mut R) -> error::Result<Frame>
+        where R: Read,
+    {
+        if r.read_long()? == 0 {
+            return Ok(Frame::Single(Picture::parse(r)?));
+        }
+        let count = read_group_count(r, "group pictures")?;
+        let mut times = vec![];
+        for _ in 0 .. count {
+            times.push(r.read_float()?);
+        }
+        let mut pictures = vec![];
+        for _ in 0 .. count {
+            pictures.push(Picture::parse(r)?);
+        }
+        Ok(Frame::Group {
+            times: times,
+            pictures: pictures,
+        })
+    }
+
+    // Index of the picture to show at the given time in seconds,
+    // single frames only have the one.
+    pub fn index_at_time(&self, time: f32) -> usize {
+        match self {
+            Frame::Single(_) => 0,
+            Frame::Group { times, .. } => {
+                let total = times.last().cloned().unwrap_or(0.0);
+                let time = if total > 0.0 { time % total } else { 0.0 };
+                times.iter()
+                    .position(|&v| time < v)
+                    .unwrap_or(0)
+            },
+        }
+    }
+
+    pub fn pictures(&self) -> &[Picture] {
+        match self {
+            Frame::Single(picture) => std::slice::from_ref(picture),
+            Frame::Group { pictures, .. } => pictures,
+        }
+    }
+}
+
+#[test]
+fn test_parse_spr() {
+    use std::io::Cursor;
+
+    let mut data = b"IDSP".to_vec();
+    data.write_longs(&[1, 3]);
+    data.write_float(8.0);
+    data.write_longs(&[2, 1, 2]);
+    data.write_float(0.0);
+    data.write_long(0);
+
+    let picture = |data: &mut Vec<u8>, pixels: &[u8]| {
+        data.write_longs(&[-1, 1, 2, 1]);
+        data.extend_from_slice(pixels);
+    };
+    data.write_long(0);
+    picture(&mut data, &[1, 2]);
+    data.write_longs(&[1, 2]);
+    data.write_floats(&[0.1, 0.3]);
+    picture(&mut data, &[3, 4]);
+    picture(&mut data, &[5, 255]);
+
+    let spr = SprFile::parse(&mut Cursor::new(data.clone())).unwrap();
+    assert_eq!(spr.kind, SpriteType::Oriented);
+    assert_eq!((spr.width, spr.height), (2, 1));
+    assert_eq!(spr.frames.len(), 2);
+    assert_eq!(spr.frames[0].pictures()[0].data, vec![1, 2]);
+    assert_eq!(spr.frames[0].pictures()[0].origin, [-1, 1]);
+    assert_eq!(spr.frames[1].index_at_time(0.05), 0);
+    assert_eq!(spr.frames[1].index_at_time(0.5), 1);
+    assert_eq!(spr.frames[1].pictures()[1].data, vec![5, 255]);
+
+    // No frames, an empty group, then a picture too large for the file
+    assert!(SprFile::parse(&mut Cursor::new(with_longs(&data, 24, &[0]))).is_err());
+    assert!(SprFile::parse(&mut Cursor::new(with_longs(&data, 62, &[0]))).is_err());
+    assert!(SprFile::parse(&mut Cursor::new(with_longs(&data, 48, &[0x7FFF_FFFF; 2]))).is_err());
+}
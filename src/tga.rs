@@ -0,0 +1,149 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::parse::*;
+use crate::error;
+
+// A true colour image decoded to RGBA with the first row at the top
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn parse(data: &[u8]) -> error::Result<Image> {
+        let mut r = Cursor::new(data);
+        let id_length = r.read_uchar()?;
+        let colour_map_type = r.read_uchar()?;
+        let image_type = r.read_uchar()?;
+        let _colour_map_start = r.read_ushort()?;
+        let colour_map_length = r.read_ushort()?;
+        let colour_map_depth = r.read_uchar()?;
+        let _x_origin = r.read_ushort()?;
+        let _y_origin = r.read_ushort()?;
+        let width = r.read_ushort()? as u32;
+        let height = r.read_ushort()? as u32;
+        let depth = r.read_uchar()?;
+        let descriptor = r.read_uchar()?;
+
+        // Skip the image id and any colour map, colour mapped
+        // images aren't supported but true colour images may
+        // still contain one.
+        let colour_map_size = if colour_map_type != 0 {
+            colour_map_length as i64 * ((colour_map_depth as i64 + 7) / 8)
+        } else { 0 };
+        r.seek(SeekFrom::Current(id_length as i64 + colour_map_size))?;
+
+        let (compressed, grey) = match image_type {
+            2 => (false, false),
+            3 => (false, true),
+            10 => (true, false),
+            11 => (true, true),
+            _ => bail!("Unsupported tga image type {}", image_type),
+        };
+        let pixel_size = match (grey, depth) {
+            (false, 24) => 3,
+            (false, 32) => 4,
+            (true, 8) => 1,
+            _ => bail!("Unsupported tga pixel depth {}", depth),
+        };
+
+        let count = (width * height) as usize;
+        // The size comes from the header so it is checked against
+        // the data before anything is allocated, compressed images
+        // grow as their packets are decoded instead
+        let available = (data.len() as u64).saturating_sub(r.position());
+        if !compressed && (count * pixel_size) as u64 > available {
+            bail!("{}x{} tga image is larger than its data", width, height);
+        }
+        let mut data = vec![];
+        let mut pixel = [0u8; 4];
+        while data.len() < count * 4 {
+            let remaining = count - data.len() / 4;
+            // Compressed images are made up of packets of either a
+            // single repeated pixel or a run of raw pixels
+            let (run, repeat) = if compressed {
+                let header = r.read_uchar()?;
+                (((header & 0x7F) as usize + 1).min(remaining), header & 0x80 != 0)
+            } else {
+                (remaining, false)
+            };
+            for i in 0 .. run {
+                if i == 0 || !repeat {
+                    r.read_exact(&mut pixel[..pixel_size])?;
+                }
+                match pixel_size {
+                    1 => data.extend_from_slice(&[pixel[0], pixel[0], pixel[0], 255]),
+                    3 => data.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 255]),
+                    _ => data.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]),
+                }
+            }
+        }
+
+        // Rows are stored bottom up unless the origin is at the top
+        if descriptor & 0x20 == 0 {
+            let row = width as usize * 4;
+            for y in 0 .. height as usize / 2 {
+                let (top, bottom) = data.split_at_mut((height as usize - 1 - y) * row);
+                top[y * row .. (y + 1) * row].swap_with_slice(&mut bottom[..row]);
+            }
+        }
+
+        Ok(Image {
+            width: width,
+            height: height,
+            data: data,
+        })
+    }
+
+    // Writes the image as an uncompressed 24 bit tga with the
+    // origin at the top
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut header = [0u8; 18];
+        header[2] = 2;
+        header[12..14].copy_from_slice(&(self.width as u16).to_le_bytes());
+        header[14..16].copy_from_slice(&(self.height as u16).to_le_bytes());
+        header[16] = 24;
+        header[17] = 0x20;
+        w.write_all(&header)?;
+
+        let mut data = Vec::with_capacity(self.data.len() / 4 * 3);
+        for pixel in self.data.chunks_exact(4) {
+            data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+        }
+        w.write_all(&data)
+    }
+}
+
+#[test]
+fn test_parse_tga() {
+    // A 2x2 bottom up image, a run of two red pixels then two
+    // raw pixels, green and blue
+    let data = [
+        0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 24, 0,
+        0x81, 0, 0, 255,
+        0x01, 0, 255, 0, 255, 0, 0,
+    ];
+    let image = Image::parse(&data).unwrap();
+    assert_eq!((image.width, image.height), (2, 2));
+    assert_eq!(image.data, vec![
+        0, 255, 0, 255, 0, 0, 255, 255,
+        255, 0, 0, 255, 255, 0, 0, 255,
+    ]);
+
+    // Uncompressed images are checked against their data, the
+    // second claiming to be 65535x65535
+    let raw = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 24, 0, 255, 0, 0];
+    assert_eq!(Image::parse(&raw).unwrap().data, vec![0, 0, 255, 255]);
+    assert!(Image::parse(&raw[.. raw.len() - 1]).is_err());
+    let mut large = raw;
+    large[12 .. 16].copy_from_slice(&[255, 255, 255, 255]);
+    assert!(Image::parse(&large).is_err());
+    assert!(Image::parse(&data[.. data.len() - 1]).is_err());
+
+    let mut written = vec![];
+    image.write(&mut written).unwrap();
+    let image2 = Image::parse(&written).unwrap();
+    assert_eq!((image2.width, image2.height), (2, 2));
+    assert_eq!(image2.data, image.data);
+}
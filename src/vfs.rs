@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use log::*;
+
+use crate::error;
+use crate::pak::{self, SharedData, SharedPackFile};
+
+// Quake stops looking for pak files at the first missing one
+const MAX_PAKS: usize = 100;
+
+enum Source {
+    Pak(SharedPackFile),
+    Dir(PathBuf),
+}
+
+// Files searched for through a list of pak files and directories.
+// Sources added later override the earlier ones like Quake's
+// search path. Pak files are held in memory so files in them are
+// shared instead of copied and the file system can be shared
+// between threads.
+#[derive(Default)]
+pub struct FileSystem {
+    sources: Vec<Source>,
+}
+
+impl FileSystem {
+    pub fn new() -> FileSystem {
+        FileSystem {
+            sources: vec![],
+        }
+    }
+
+    pub fn add_pak(&mut self, pak: SharedPackFile) {
+        self.sources.push(Source::Pak(pak));
+    }
+
+    // Loose files in the directory
+    pub fn add_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.sources.push(Source::Dir(path.into()));
+    }
+
+    // Adds a game directory such as id1 or a mod's directory, its
+    // loose files followed by pak0.pak, pak1.pak and so on which
+    // override them.
+    pub fn add_game_dir<P: AsRef<Path>>(&mut self, path: P) -> error::Result<()> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            bail!("Game directory {:?} doesn't exist", path);
+        }
+        self.add_dir(path);
+        for i in 0 .. MAX_PAKS {
+            let pak_path = match find_pak(path, i) {
+                Some(v) => v,
+                None => break,
+            };
+            info!("Adding {:?}", pak_path);
+            self.add_pak(SharedPackFile::load(&pak_path)?);
+        }
+        Ok(())
+    }
+
+    pub fn file(&self, name: &str) -> io::Result<SharedData> {
+        for source in self.sources.iter().rev() {
+            match source {
+                Source::Pak(pak) => if pak.contains(name) {
+                    return pak.file(name);
+                },
+                Source::Dir(dir) => if let Some(path) = dir_path(dir, name) {
+                    if path.is_file() {
+                        return fs::read(path).map(SharedData::from);
+                    }
+                },
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sources.iter().any(|source| match source {
+            Source::Pak(pak) => pak.contains(name),
+            Source::Dir(dir) => dir_path(dir, name).map_or(false, |v| v.is_file()),
+        })
+    }
+
+    // The name and size of every file, overridden files are
+    // only listed once with the size of the file that is used
+    pub fn files(&self) -> Vec<(String, u64)> {
+        self.files_with_prefix("")
+    }
+
+    pub fn files_with_prefix(&self, prefix: &str) -> Vec<(String, u64)> {
+        let mut files = BTreeMap::new();
+        for source in &self.sources {
+            match source {
+                Source::Pak(pak) => for (name, size) in pak.files_with_prefix(prefix) {
+                    files.insert(name.to_owned(), size);
+                },
+                Source::Dir(dir) => {
+                    let mut found = vec![];
+                    list_dir(dir, "", &mut found);
+                    for (name, size) in found {
+                        if name.starts_with(prefix) {
+                            files.insert(name, size);
+                        }
+                    }
+                },
+            }
+        }
+        files.into_iter().collect()
+    }
+
+    // Names of the files matching a pattern like `maps/*.bsp`
+    // in name order
+    pub fn glob(&self, pattern: &str) -> Vec<String> {
+        let prefix = &pattern[.. pattern.find(|c| c == '*' || c == '?').unwrap_or(pattern.len())];
+        self.files_with_prefix(prefix)
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| pak::glob_match(pattern, name))
+            .collect()
+    }
+}
+
+// Pak files are usually lower case but the shareware
+// release has PAK0.PAK
+fn find_pak(dir: &Path, idx: usize) -> Option<PathBuf> {
+    [format!("pak{}.pak", idx), format!("PAK{}.PAK", idx)].iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+// The path of a file in a directory, names that would leave the
+// directory are refused
+fn dir_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    let inside = name.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if inside {
+        Some(dir.join(name))
+    } else {
+        None
+    }
+}
+
+fn list_dir(dir: &Path, prefix: &str, out: &mut Vec<(String, u64)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|v| v.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(v) => format!("{}{}", prefix, v),
+            Err(_) => continue,
+        };
+        let meta = match entry.metadata() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        if meta.is_dir() {
+            list_dir(&entry.path(), &format!("{}/", name), out);
+        } else {
+            out.push((name, meta.len()));
+        }
+    }
+}
+
+#[test]
+fn test_file_system() {
+    let root = std::env::temp_dir().join(format!("rquake-vfs-{}", std::process::id()));
+    let base = root.join("id1");
+    let game = root.join("mod");
+    fs::create_dir_all(base.join("maps")).unwrap();
+    fs::create_dir_all(game.join("maps")).unwrap();
+    fs::write(base.join("maps/e1m1.bsp"), b"base").unwrap();
+    fs::write(base.join("maps/e1m2.bsp"), b"base").unwrap();
+    fs::write(game.join("maps/e1m1.bsp"), b"game!").unwrap();
+    fs::write(game.join("config.cfg"), b"").unwrap();
+    let mut writer = pak::PackWriter::new();
+    writer.add_file("maps/e1m2.bsp", b"pak".to_vec()).unwrap();
+    writer.add_file("maps/e1m3.bsp", b"pak".to_vec()).unwrap();
+    writer.write(&mut fs::File::create(base.join("pak0.pak")).unwrap()).unwrap();
+
+    let mut files = FileSystem::new();
+    files.add_game_dir(&base).unwrap();
+    files.add_game_dir(&game).unwrap();
+    assert!(files.add_game_dir(root.join("missing")).is_err());
+
+    assert_eq!(&files.file("maps/e1m1.bsp").unwrap()[..], b"game!");
+    assert_eq!(&files.file("maps/e1m2.bsp").unwrap()[..], b"pak");
+    assert!(files.file("maps/e1m4.bsp").is_err());
+    assert!(files.file("../id1/maps/e1m2.bsp").is_err());
+    assert!(files.contains("config.cfg"));
+
+    assert_eq!(files.glob("maps/*.bsp"), vec!["maps/e1m1.bsp", "maps/e1m2.bsp", "maps/e1m3.bsp"]);
+    assert_eq!(files.files_with_prefix("maps/"), vec![
+        ("maps/e1m1.bsp".to_owned(), 5),
+        ("maps/e1m2.bsp".to_owned(), 3),
+        ("maps/e1m3.bsp".to_owned(), 3),
+    ]);
+
+    // Files in paks are shared with the pak instead of copied
+    // and the file system can be used from other threads
+    let files = std::sync::Arc::new(files);
+    let thread = {
+        let files = files.clone();
+        std::thread::spawn(move || files.file("maps/e1m3.bsp").unwrap())
+    };
+    assert_eq!(thread.join().unwrap().as_ptr(), files.file("maps/e1m3.bsp").unwrap().as_ptr());
+
+    fs::remove_dir_all(&root).unwrap();
+}
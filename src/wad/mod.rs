@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use crate::error;
+use crate::parse::*;
+pub use crate::bsp::Picture;
+
+// The font is stored as a raw 128x128 image even though it is
+// marked as a miptex
+const CONCHARS: &str = "conchars";
+const CONCHARS_SIZE: u32 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LumpType {
+    Palette,
+    QTex,
+    QPic,
+    Sound,
+    MipTex,
+    Other(u8),
+}
+
+impl LumpType {
+    fn from_raw(v: u8) -> LumpType {
+        match v {
+            b'@' => LumpType::Palette,
+            b'A' => LumpType::QTex,
+            b'B' => LumpType::QPic,
+            b'C' => LumpType::Sound,
+            b'D' => LumpType::MipTex,
+            v => LumpType::Other(v),
+        }
+    }
+}
+
+pub struct Lump {
+    // Lower case as lookups ignore case
+    pub name: String,
+    pub kind: LumpType,
+    pub compressed: bool,
+    offset: usize,
+    size: usize,
+}
+
+// A WAD2 file such as gfx.wad, loaded into memory as its
+// lumps are small
+pub struct WadFile {
+    data: Vec<u8>,
+    lumps: Vec<Lump>,
+    names: HashMap<String, usize>,
+}
+
+impl WadFile {
+    pub fn parse(data: Vec<u8>) -> error::Result<WadFile> {
+        let mut r = Cursor::new(&data[..]);
+        let magic = read_string!(r, 4);
+        if &magic != b"WAD2" {
+            bail!("Invalid wad magic");
+        }
+        let count = r.read_long()?;
+        let offset = r.read_long()?;
+        if count < 0 || offset < 0 {
+            bail!("Invalid wad header");
+        }
+        // Each directory entry is 32 bytes
+        if count as u64 * 32 > (data.len() as u64).saturating_sub(offset as u64) {
+            bail!("Directory of {} lumps is outside of the wad", count);
+        }
+        r.seek(SeekFrom::Start(offset as u64))?;
+
+        let mut lumps = vec![];
+        let mut names = HashMap::new();
+        for _ in 0 .. count {
+            let offset = r.read_long()?;
+            let _disk_size = r.read_long()?;
+            let size = r.read_long()?;
+            let kind = r.read_uchar()?;
+            let compression = r.read_uchar()?;
+            let _pad = r.read_ushort()?;
+            let name = read_string!(r, 16);
+            let name = from_cstring(&name)?.to_ascii_lowercase();
+
+            if offset < 0 || size < 0 || offset as usize + size as usize > data.len() {
+                bail!("Lump {} is outside of the wad", name);
+            }
+            names.insert(name.clone(), lumps.len());
+            lumps.push(Lump {
+                name: name,
+                kind: LumpType::from_raw(kind),
+                compressed: compression != 0,
+                offset: offset as usize,
+                size: size as usize,
+            });
+        }
+
+        Ok(WadFile {
+            data: data,
+            lumps: lumps,
+            names: names,
+        })
+    }
+
+    pub fn lumps(&self) -> &[Lump] {
+        &self.lumps
+    }
+
+    pub fn lump(&self, name: &str) -> Option<&Lump> {
+        self.names.get(&name.to_ascii_lowercase())
+            .map(|v| &self.lumps[*v])
+    }
+
+    pub fn lump_data(&self, lump: &Lump) -> &[u8] {
+        &self.data[lump.offset .. lump.offset + lump.size]
+    }
+
+    // Decodes a picture lump, qpics and miptexs are supported
+    pub fn picture(&self, name: &str) -> error::Result<Picture> {
+        let lump = match self.lump(name) {
+            Some(v) => v,
+            None => bail!("No lump named {}", name),
+        };
+        if lump.compressed {
+            bail!("Lump {} is compressed", name);
+        }
+        let data = self.lump_data(lump);
+        if lump.name == CONCHARS {
+            let size = (CONCHARS_SIZE * CONCHARS_SIZE) as usize;
+            if data.len() < size {
+                bail!("Lump {} is too small", name);
+            }
+            return Ok(Picture {
+                width: CONCHARS_SIZE,
+                height: CONCHARS_SIZE,
+                data: data[..size].to_vec().into(),
+            });
+        }
+        match lump.kind {
+            LumpType::QPic => parse_qpic(data),
+            LumpType::MipTex => parse_miptex(data),
+            kind => bail!("Lump {} is a {:?} not a picture", name, kind),
+        }
+    }
+
+    pub fn palette(&self, name: &str) -> error::Result<Palette> {
+        match self.lump(name) {
+            Some(lump) if lump.kind == LumpType::Palette => Palette::parse(self.lump_data(lump)),
+            Some(_) => bail!("Lump {} is not a palette", name),
+            None => bail!("No lump named {}", name),
+        }
+    }
+}
+
+// A picture with its width and height in front of it, used by
+// the status bar pictures in gfx.wad and the gfx/*.lmp files
+pub fn parse_qpic(data: &[u8]) -> error::Result<Picture> {
+    let mut r = Cursor::new(data);
+    let width = r.read_ulong()?;
+    let height = r.read_ulong()?;
+    Ok(Picture {
+        width: width,
+        height: height,
+        data: picture_data(data, 8, width, height)?.into(),
+    })
+}
+
+// The full size image of a mip mapped texture
+pub fn parse_miptex(data: &[u8]) -> error::Result<Picture> {
+    let mut r = Cursor::new(data);
+    let _name = read_string!(r, 16);
+    let width = r.read_ulong()?;
+    let height = r.read_ulong()?;
+    let offset = r.read_ulong()?;
+    Ok(Picture {
+        width: width,
+        height: height,
+        data: picture_data(data, offset as usize, width, height)?.into(),
+    })
+}
+
+// The pixels of a picture, checked to be within the lump
+// before anything is allocated for them
+fn picture_data(data: &[u8], offset: usize, width: u32, height: u32) -> error::Result<Vec<u8>> {
+    let pixels = (width as usize).checked_mul(height as usize)
+        .and_then(|size| data.get(offset .. offset.checked_add(size)?));
+    match pixels {
+        Some(v) => Ok(v.to_vec()),
+        None => bail!("{}x{} picture at {} is outside of the {} byte lump", width, height, offset, data.len()),
+    }
+}
+
+pub struct Palette {
+    pub colours: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    // 256 RGB colours, from a palette lump or gfx/palette.lmp
+    pub fn parse(data: &[u8]) -> error::Result<Palette> {
+        if data.len() < 256 * 3 {
+            bail!("Palette is too small");
+        }
+        Ok(Palette {
+            colours: data[..256 * 3].chunks_exact(3)
+                .map(|v| [v[0], v[1], v[2]])
+                .collect(),
+        })
+    }
+
+    // Converts a picture to RGBA, colour 255 is transparent in
+    // 2D pictures
+    pub fn rgba(&self, picture: &Picture) -> Vec<u8> {
+        let mut out = Vec::with_capacity(picture.data.len() * 4);
+        for &idx in picture.data.iter() {
+            let [r, g, b] = self.colours[idx as usize];
+            out.extend_from_slice(&[r, g, b, if idx == 255 { 0 } else { 255 }]);
+        }
+        out
+    }
+}
+
+#[test]
+fn test_parse_wad() {
+    let mut data = b"WAD2".to_vec();
+    data.write_longs(&[3, 0]);
+
+    // A 2x1 qpic, a palette and a 1x1 miptex
+    let qpic = data.len();
+    data.write_longs(&[2, 1]);
+    data.extend_from_slice(&[4, 255]);
+    let palette = data.len();
+    data.extend((0 .. 256 * 3).map(|v| (v / 3) as u8));
+    let miptex = data.len();
+    data.extend_from_slice(b"tex\0\0\0\0\0\0\0\0\0\0\0\0\0");
+    data.write_longs(&[1, 1, 40, 41, 42, 43]);
+    data.push(7);
+
+    let table = data.len() as i32;
+    data[8..12].copy_from_slice(&table.to_le_bytes());
+    for &(offset, size, kind, name) in &[
+        (qpic, 10, b'B', &b"SBAR\0\0\0\0\0\0\0\0\0\0\0\0"),
+        (palette, 768, b'@', &b"PALETTE\0\0\0\0\0\0\0\0\0"),
+        (miptex, 41, b'D', &b"TEX\0\0\0\0\0\0\0\0\0\0\0\0\0"),
+    ] {
+        data.write_longs(&[offset as i32, size, size]);
+        data.extend_from_slice(&[kind, 0, 0, 0]);
+        data.extend_from_slice(*name);
+    }
+
+    let wad = WadFile::parse(data.clone()).unwrap();
+    assert_eq!(wad.lumps().len(), 3);
+    assert_eq!(wad.lump("Sbar").map(|v| v.kind), Some(LumpType::QPic));
+
+    let pic = wad.picture("sbar").unwrap();
+    assert_eq!((pic.width, pic.height), (2, 1));
+    let palette = wad.palette("palette").unwrap();
+    assert_eq!(palette.rgba(&pic), vec![4, 4, 4, 255, 255, 255, 255, 0]);
+
+    let tex = wad.picture("tex").unwrap();
+    assert_eq!(&tex.data[..], [7]);
+    assert!(wad.picture("palette").is_err());
+    assert!(wad.picture("missing").is_err());
+
+    // A truncated directory, then one with far too many lumps
+    assert!(WadFile::parse(data[.. data.len() - 10].to_vec()).is_err());
+    assert!(WadFile::parse(with_longs(&data, 4, &[0x7FFF_FFFF])).is_err());
+
+    // Sizes larger than the lump are errors rather than allocations
+    let mut qpic = vec![];
+    qpic.write_longs(&[0x7FFF_FFFF; 2]);
+    qpic.push(0);
+    assert!(parse_qpic(&qpic).is_err());
+    assert!(parse_qpic(&[2, 0, 0, 0, 1, 0, 0, 0, 1]).is_err());
+    assert_eq!(&parse_qpic(&[2, 0, 0, 0, 1, 0, 0, 0, 1, 2]).unwrap().data[..], [1, 2]);
+}